@@ -0,0 +1,190 @@
+#![no_std]
+// deny rather than forbid, so that #[entry] can export its symbol
+#![deny(unsafe_code)]
+
+use lunaris_user::{
+    calibration::{
+        calibration_server, Calibrate, CALIBRATION_SERVER_NAME, CALIBRATION_SERVER_PRIORITY,
+        CALIBRATION_SERVER_STACK_SIZE,
+    },
+    clock::{clock_server, Delay, DelayUntil, Time, CLOCK_SERVER_NAME, CLOCK_SERVER_PRIORITY},
+    entry,
+    io::{
+        io_server, Puts, CONSOLE_SERVER_NAME, IO_SERVER_PRIORITY, IO_SERVER_STACK_SIZE,
+        MARKLIN_SERVER_NAME,
+    },
+    marklin::{train_server, DumpSensors, TRAIN_SERVER_NAME, TRAIN_SERVER_PRIORITY},
+    name_server::WhoIs,
+    println,
+    reservation::{
+        reservation_server, RESERVATION_SERVER_NAME, RESERVATION_SERVER_PRIORITY,
+        RESERVATION_SERVER_STACK_SIZE,
+    },
+    syscall::{
+        Create, CreateEx, Exit, MyParentTid, MyTid, Receive, Reply, Send, TaskOptions, Write,
+        Yield, LINE_CONSOLE, LINE_MARKLIN,
+    },
+    tracking::{
+        tracking_server, TRACKING_SERVER_NAME, TRACKING_SERVER_PRIORITY, TRACKING_SERVER_STACK_SIZE,
+    },
+};
+
+/// The train timed once the servers are up, which has to be the only one on the track
+const CALIBRATION_TRAIN: u8 = 24;
+
+fn other() -> ! {
+    let tid = MyTid();
+    let parent_tid = MyParentTid().unwrap();
+
+    println!("my task id: {}", tid);
+    println!("my parent id: {}", parent_tid);
+
+    Yield();
+    println!("my task id: {}", tid);
+    println!("my parent id: {}", parent_tid);
+
+    Exit();
+}
+
+fn counter(count: usize) -> ! {
+    for i in 0..count {
+        println!("counter {}: {}", MyTid(), i);
+        Yield();
+    }
+
+    Exit();
+}
+
+/// Hand the argument we were started with back to our parent, which checks it
+fn echo_arg(arg: usize) -> ! {
+    Send(MyParentTid().unwrap(), &arg.to_le_bytes(), &mut []).unwrap();
+    Exit();
+}
+
+/// Every task that takes an argument depends on `CreateEx` handing it over intact
+fn check_arg() {
+    const ARG: usize = 0x1a2b_3c4d_5e6f_7081;
+    let task = CreateEx(&TaskOptions {
+        priority: 2,
+        func: echo_arg,
+        stack_size: 0x2000,
+        arg: ARG,
+        name: "echo_arg",
+    })
+    .unwrap();
+
+    let mut msg = [0; core::mem::size_of::<usize>()];
+    let (tid, len) = Receive(&mut msg);
+    assert_eq!(tid, task);
+    assert_eq!(len, Ok(msg.len()));
+    assert_eq!(usize::from_le_bytes(msg), ARG);
+    Reply(tid, &[]).unwrap();
+    println!("Task argument: ok");
+}
+
+#[entry]
+fn main() -> ! {
+    let task_1 = Create(0, other).unwrap();
+    println!("Created: {}", task_1);
+
+    let task_2 = Create(0, other).unwrap();
+    println!("Created: {}", task_2);
+
+    let task_3 = Create(2, other).unwrap();
+    println!("Created: {}", task_3);
+
+    let task_4 = Create(2, other).unwrap();
+    println!("Created: {}", task_4);
+
+    let counter = CreateEx(&TaskOptions {
+        priority: 2,
+        func: counter,
+        stack_size: 0x2000,
+        arg: 3,
+        name: "counter",
+    })
+    .unwrap();
+    println!("Created: {}", counter);
+
+    check_arg();
+
+    Create(CLOCK_SERVER_PRIORITY, clock_server).unwrap();
+    let clock = WhoIs(CLOCK_SERVER_NAME).unwrap();
+    println!("Clock server: {}", clock);
+
+    println!("Time: {}", Time(clock).unwrap());
+    println!("Woke from Delay(10) at: {}", Delay(clock, 10).unwrap());
+    let mut deadline = Time(clock).unwrap();
+    for _ in 0..3 {
+        deadline += 50;
+        println!(
+            "Woke from DelayUntil({}) at: {}",
+            deadline,
+            DelayUntil(clock, deadline).unwrap()
+        );
+    }
+
+    CreateEx(&TaskOptions {
+        priority: IO_SERVER_PRIORITY,
+        func: io_server,
+        stack_size: IO_SERVER_STACK_SIZE,
+        arg: LINE_CONSOLE as usize,
+        name: CONSOLE_SERVER_NAME,
+    })
+    .unwrap();
+    let console = WhoIs(CONSOLE_SERVER_NAME).unwrap();
+    Puts(console, LINE_CONSOLE, b"Console server up\r\n").unwrap();
+
+    // only the lab and sim builds have a Märklin line, writing nothing finds out which this is
+    if Write(LINE_MARKLIN, &[]).is_ok() {
+        CreateEx(&TaskOptions {
+            priority: IO_SERVER_PRIORITY,
+            func: io_server,
+            stack_size: IO_SERVER_STACK_SIZE,
+            arg: LINE_MARKLIN as usize,
+            name: MARKLIN_SERVER_NAME,
+        })
+        .unwrap();
+        Create(TRAIN_SERVER_PRIORITY, train_server).unwrap();
+        let train = WhoIs(TRAIN_SERVER_NAME).unwrap();
+        let report = DumpSensors(train).unwrap();
+        for sensor in report.triggered() {
+            println!("Sensor triggered: {}", sensor);
+        }
+        println!("Train server up");
+
+        CreateEx(&TaskOptions {
+            priority: CALIBRATION_SERVER_PRIORITY,
+            func: calibration_server,
+            stack_size: CALIBRATION_SERVER_STACK_SIZE,
+            // track A
+            arg: 0,
+            name: CALIBRATION_SERVER_NAME,
+        })
+        .unwrap();
+        CreateEx(&TaskOptions {
+            priority: TRACKING_SERVER_PRIORITY,
+            func: tracking_server,
+            stack_size: TRACKING_SERVER_STACK_SIZE,
+            arg: 0,
+            name: TRACKING_SERVER_NAME,
+        })
+        .unwrap();
+        CreateEx(&TaskOptions {
+            priority: RESERVATION_SERVER_PRIORITY,
+            func: reservation_server,
+            stack_size: RESERVATION_SERVER_STACK_SIZE,
+            arg: 0,
+            name: RESERVATION_SERVER_NAME,
+        })
+        .unwrap();
+
+        let calibration = WhoIs(CALIBRATION_SERVER_NAME).unwrap();
+        Calibrate(calibration, CALIBRATION_TRAIN).unwrap();
+        println!("Calibrating train {}", CALIBRATION_TRAIN);
+    }
+
+    println!("First User Task: exiting");
+
+    Exit();
+}
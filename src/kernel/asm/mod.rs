@@ -3,3 +3,4 @@ use core::arch::global_asm;
 global_asm!(include_str!("boot.S"));
 global_asm!(include_str!("exception.S"));
 global_asm!(include_str!("switch.S"));
+global_asm!(include_str!("uaccess.S"));
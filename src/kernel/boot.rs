@@ -1,10 +1,11 @@
 use crate::kernel::tasks::CPU_GLOBAL;
-use crate::user::main;
+use crate::kernel::{frame, heap, interrupt, mmu, term};
 use aarch64_cpu::{
     asm,
     registers::{Writeable, ELR_EL1, SPSR_EL1, SP_EL0, VBAR_EL1},
 };
 use core::cell::UnsafeCell;
+use lunaris_user::{name_server, syscall::TaskOptions};
 
 #[inline(always)]
 pub fn wait_forever() -> ! {
@@ -23,15 +24,16 @@ unsafe fn exception_setup() {
     asm::barrier::isb(asm::barrier::SY);
 }
 
-/// Sets up the ELR_EL1 and SP_EL0
+/// Sets up the ELR_EL1 and SP_EL0, with every exception unmasked once in EL0, the same as
+/// `__syscall_ret` leaves a resumed task
 #[inline(always)]
 pub fn el0_setup(func: u64, sp: u64) {
     SPSR_EL1.write(
-        SPSR_EL1::A::Masked
-            + SPSR_EL1::F::Masked
+        SPSR_EL1::A::Unmasked
+            + SPSR_EL1::F::Unmasked
             + SPSR_EL1::M::EL0t
-            + SPSR_EL1::I::Masked
-            + SPSR_EL1::D::Masked,
+            + SPSR_EL1::I::Unmasked
+            + SPSR_EL1::D::Unmasked,
     );
     ELR_EL1.set(func);
     SP_EL0.set(sp);
@@ -40,9 +42,40 @@ pub fn el0_setup(func: u64, sp: u64) {
     }
 }
 
+fn name_server(_: usize) -> ! {
+    name_server::name_server()
+}
+
+/// Run the first user task, which whichever crate has the `#[entry]` function exports
+fn user_entry() -> ! {
+    extern "Rust" {
+        fn __lunaris_user_entry() -> !;
+    }
+
+    unsafe { __lunaris_user_entry() }
+}
+
 #[no_mangle]
 unsafe extern "C" fn _kmain() -> ! {
-    CPU_GLOBAL.scheduler.create(1, None, main::main);
+    frame::init();
+    mmu::init();
+    heap::init();
+    term::init();
+    interrupt::init();
+    CPU_GLOBAL
+        .scheduler
+        .create_ex(
+            None,
+            &TaskOptions {
+                priority: name_server::NAME_SERVER_PRIORITY,
+                func: name_server,
+                stack_size: name_server::NAME_SERVER_STACK_SIZE,
+                arg: 0,
+                name: "name_server",
+            },
+        )
+        .unwrap();
+    CPU_GLOBAL.scheduler.create(1, None, user_entry).unwrap();
     CPU_GLOBAL.scheduler.run();
     wait_forever()
 }
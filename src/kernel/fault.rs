@@ -0,0 +1,86 @@
+use crate::{
+    kernel::{
+        sys_syscall::ExceptionFrame,
+        tasks::{StackKind, Task},
+    },
+    print, println,
+};
+use aarch64_cpu::registers::{Readable, ESR_EL1, FAR_EL1};
+
+/// Data/instruction fault status code for an alignment fault
+const FSC_ALIGNMENT: u64 = 0b10_0001;
+const FSC_MASK: u64 = 0b11_1111;
+
+/// Why a task was killed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    DataAbort,
+    InstructionAbort,
+    Alignment,
+    PcAlignment,
+    SpAlignment,
+    UndefinedInstruction,
+    Breakpoint,
+    SError,
+    /// An FIQ, which nothing is set up to raise
+    Fiq,
+    /// Any exception taken from AArch32
+    AArch32,
+    /// Ran off the end of its kernel or user stack
+    StackOverflow,
+    /// SVC with a syscall number the kernel doesn't know
+    BadSyscall(u64),
+    /// Any other exception class
+    Unhandled(u64),
+}
+
+impl Fault {
+    /// Classify the synchronous exception described by ESR_EL1.
+    pub fn from_esr() -> Self {
+        let fsc = ESR_EL1.read(ESR_EL1::ISS) & FSC_MASK;
+        match ESR_EL1.read_as_enum(ESR_EL1::EC) {
+            Some(ESR_EL1::EC::Value::DataAbortLowerEL) if fsc == FSC_ALIGNMENT => Fault::Alignment,
+            Some(ESR_EL1::EC::Value::DataAbortLowerEL) => Fault::DataAbort,
+            Some(ESR_EL1::EC::Value::InstrAbortLowerEL) => Fault::InstructionAbort,
+            Some(ESR_EL1::EC::Value::PCAlignmentFault) => Fault::PcAlignment,
+            Some(ESR_EL1::EC::Value::SPAlignmentFault) => Fault::SpAlignment,
+            Some(ESR_EL1::EC::Value::Unknown) => Fault::UndefinedInstruction,
+            Some(ESR_EL1::EC::Value::Brk64) => Fault::Breakpoint,
+            Some(ESR_EL1::EC::Value::SError) => Fault::SError,
+            _ => Fault::Unhandled(ESR_EL1.read(ESR_EL1::EC)),
+        }
+    }
+}
+
+/// Print what killed `task` and the registers it died with. The kernel carries on afterwards, so
+/// this goes through its log like anything else, never waiting on the UART.
+pub fn report(task: &Task, fault: Fault) {
+    let frame = unsafe { &*task.trap_frame.unwrap() };
+    println!("task {} killed: {:?}", task, fault);
+    println!(
+        "  ELR {:#018x}  FAR {:#018x}  ESR {:#010x}  SPSR {:#010x}",
+        frame.elr,
+        FAR_EL1.get(),
+        frame.esr,
+        frame.spsr
+    );
+
+    let registers = frame.registers();
+    for (row, chunk) in registers.chunks(4).enumerate() {
+        print_row(row * 4, chunk);
+    }
+}
+
+/// Print which stack `task` ran off the end of. Its registers are not worth showing, since the
+/// overflow is only noticed after the fact or while its frame was being saved.
+pub fn report_overflow(task: &Task, stack: StackKind) {
+    println!("task {} killed: overflowed its {:?} stack", task, stack);
+}
+
+fn print_row(first: usize, registers: &[u64]) {
+    print!(" ");
+    for (i, register) in registers.iter().enumerate() {
+        print!(" x{:<2} {:#018x}", first + i, register);
+    }
+    println!();
+}
@@ -0,0 +1,122 @@
+use crate::kernel::mailbox::Mailbox;
+use crate::kernel::setup::MMIO_BASE;
+use crate::kernel::tasks::STACK_BLOCKS;
+use crate::kernel::utils::Spinlock as Mutex;
+use core::{cell::UnsafeCell, fmt, ops::Range};
+
+pub const FRAME_SIZE: usize = 0x1000;
+/// The firmware only reports the ARM's share of the first 1GiB, so that is all the bitmap covers
+const MAX_FRAMES: usize = (1 << 30) / FRAME_SIZE;
+const WORD_BITS: usize = u64::BITS as usize;
+/// What we fall back to if the mailbox doesn't answer, the smallest ARM share of any board we run on
+const FALLBACK_RAM_SIZE: usize = 0x3B00_0000;
+
+/// A 4KiB frame of physical memory, handed back to the allocator when dropped
+pub struct Frame(usize);
+
+impl Frame {
+    pub fn addr(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Debug for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame({:#x})", self.0)
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        FRAMES.lock().free(self.0);
+    }
+}
+
+/// One bit per frame of RAM, set when the frame is in use or was never ours to give out
+pub struct FrameAllocator {
+    used: [u64; MAX_FRAMES / WORD_BITS],
+    num_frames: usize,
+    /// Where the last search stopped, every word before it was full at the time
+    next_word: usize,
+}
+
+impl FrameAllocator {
+    const fn empty() -> Self {
+        Self {
+            used: [u64::MAX; MAX_FRAMES / WORD_BITS],
+            num_frames: 0,
+            next_word: 0,
+        }
+    }
+
+    /// Give out the frames of `0..ram_end`, apart from those overlapping `reserved`
+    fn init(&mut self, ram_end: usize, reserved: &[Range<usize>]) {
+        self.num_frames = (ram_end / FRAME_SIZE).min(MAX_FRAMES);
+        for frame in 0..self.num_frames {
+            let addr = frame * FRAME_SIZE;
+            let overlaps = reserved
+                .iter()
+                .any(|range| range.start < addr + FRAME_SIZE && addr < range.end);
+            if !overlaps {
+                self.used[frame / WORD_BITS] &= !(1 << (frame % WORD_BITS));
+            }
+        }
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        let words = self.num_frames.div_ceil(WORD_BITS);
+        for i in 0..words {
+            let word = (self.next_word + i) % words;
+            let free = !self.used[word];
+            if free == 0 {
+                continue;
+            }
+
+            let frame = word * WORD_BITS + free.trailing_zeros() as usize;
+            if frame >= self.num_frames {
+                continue;
+            }
+            self.used[word] |= 1 << (frame % WORD_BITS);
+            self.next_word = word;
+            return Some(frame * FRAME_SIZE);
+        }
+        None
+    }
+
+    fn free(&mut self, addr: usize) {
+        let frame = addr / FRAME_SIZE;
+        let bit = 1 << (frame % WORD_BITS);
+        assert!(
+            self.used[frame / WORD_BITS] & bit != 0,
+            "double free of frame {addr:#x}"
+        );
+        self.used[frame / WORD_BITS] &= !bit;
+    }
+}
+
+static FRAMES: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
+
+/// Take a free frame, its contents are whatever the last owner left
+pub fn alloc() -> Option<Frame> {
+    FRAMES.lock().alloc().map(Frame)
+}
+
+/// Ask the firmware how much RAM there is and give out all of it that isn't the kernel image, the
+/// blocks task stacks live in or the peripherals.
+///
+/// Must be called before the MMU and caches are turned on, since the mailbox reads memory directly.
+pub unsafe fn init() {
+    extern "Rust" {
+        static __kernel_end_exclusive: UnsafeCell<()>;
+    }
+
+    let ram_end = match Mailbox::new().arm_memory() {
+        Some((base, size)) => base + size,
+        None => FALLBACK_RAM_SIZE,
+    };
+
+    // the boot stack sits below the image, and the heap and fault stack after .bss
+    let image = 0..__kernel_end_exclusive.get() as usize;
+    let stacks = STACK_BLOCKS.start as usize..STACK_BLOCKS.end as usize;
+    FRAMES.lock().init(ram_end.min(MMIO_BASE), &[image, stacks]);
+}
@@ -0,0 +1,152 @@
+use crate::kernel::utils::Spinlock as Mutex;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+};
+
+/// Every free region starts with one of these, so nothing smaller can be handed out or given back
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const MIN_ALIGN: usize = align_of::<FreeBlock>();
+
+struct FreeBlock {
+    size: usize,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// A first-fit free list kept sorted by address, so freed blocks merge back with their neighbours
+pub struct Heap {
+    head: Option<NonNull<FreeBlock>>,
+}
+
+// the free list is only reached through the lock
+unsafe impl Send for Heap {}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// What an allocation really takes up, so that whatever is left of a block can always be freed
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(MIN_ALIGN);
+    let size = align_up(layout.size().max(MIN_BLOCK_SIZE), MIN_ALIGN);
+    (size, align)
+}
+
+impl Heap {
+    pub const fn empty() -> Self {
+        Self { head: None }
+    }
+
+    /// Hand `start..end` to the heap. Must not overlap anything else the heap owns.
+    pub unsafe fn add_region(&mut self, start: usize, end: usize) {
+        let start = align_up(start, MIN_ALIGN);
+        let end = end & !(MIN_ALIGN - 1);
+        if end > start && end - start >= MIN_BLOCK_SIZE {
+            self.free(start, end - start);
+        }
+    }
+
+    /// Put `size` bytes at `addr` back on the list, merging with the blocks either side.
+    unsafe fn free(&mut self, addr: usize, size: usize) {
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut next = self.head;
+        while let Some(block) = next {
+            if block.as_ptr() as usize > addr {
+                break;
+            }
+            prev = next;
+            next = block.as_ref().next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if let Some(next) = next {
+            if addr + size == next.as_ptr() as usize {
+                (*block).size += next.as_ref().size;
+                (*block).next = next.as_ref().next;
+            }
+        }
+
+        match prev {
+            Some(prev) if prev.as_ptr() as usize + prev.as_ref().size == addr => {
+                (*prev.as_ptr()).size += (*block).size;
+                (*prev.as_ptr()).next = (*block).next;
+            }
+            Some(prev) => (*prev.as_ptr()).next = NonNull::new(block),
+            None => self.head = NonNull::new(block),
+        }
+    }
+
+    /// Carve `layout` out of the first block it fits in, or return null.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+
+        let mut link: *mut Option<NonNull<FreeBlock>> = &mut self.head;
+        while let Some(block) = *link {
+            let start = block.as_ptr() as usize;
+            let end = start + block.as_ref().size;
+
+            // padding in front of the allocation has to be big enough to stay on the list
+            let mut addr = align_up(start, align);
+            if addr != start && addr - start < MIN_BLOCK_SIZE {
+                addr = align_up(start + MIN_BLOCK_SIZE, align);
+            }
+
+            let tail = end.checked_sub(addr + size);
+            if let Some(tail) = tail.filter(|&tail| tail == 0 || tail >= MIN_BLOCK_SIZE) {
+                *link = block.as_ref().next;
+                if addr != start {
+                    self.free(start, addr - start);
+                }
+                if tail != 0 {
+                    self.free(addr + size, tail);
+                }
+                return addr as *mut u8;
+            }
+
+            link = &mut (*block.as_ptr()).next;
+        }
+
+        ptr::null_mut()
+    }
+
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.free(ptr as usize, size);
+    }
+}
+
+/// The kernel heap, which lives in the region `linker.ld` sets aside. It is kernel memory, so user
+/// tasks can't allocate from it.
+///
+/// Allocation failures come back as null. Kernel code that can run out should use the fallible
+/// APIs, such as `try_reserve`, and turn the failure into `SyscallError::OutOfMemory` for the
+/// caller.
+pub struct KernelHeap(Mutex<Heap>);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().deallocate(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap(Mutex::new(Heap::empty()));
+
+pub unsafe fn init() {
+    extern "Rust" {
+        static __heap_start: UnsafeCell<()>;
+        static __heap_end_exclusive: UnsafeCell<()>;
+    }
+
+    KERNEL_HEAP.0.lock().add_region(
+        __heap_start.get() as usize,
+        __heap_end_exclusive.get() as usize,
+    );
+}
@@ -0,0 +1,262 @@
+use crate::kernel::setup::{with_serial, MMIODeRefWrapper, SystemTimer, UARTLine, MMIO_BASE, UART};
+#[cfg(feature = "sim")]
+use crate::kernel::sim;
+use crate::kernel::tasks::CPU_GLOBAL;
+use crate::kernel::term::TERM_GLOBAL;
+use lunaris_user::syscall::EVENT_TIMER;
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_structs,
+    registers::{ReadOnly, ReadWrite, WriteOnly},
+};
+
+/// VideoCore interrupt for system timer compare channel 1
+const IRQ_TIMER: usize = 1;
+/// VideoCore interrupt shared by all the PL011 UARTs
+const IRQ_UART: usize = 57;
+
+#[cfg(feature = "default")]
+const LEGACY_IRQ_BASE: usize = MMIO_BASE + 0xB200;
+#[cfg(feature = "lab")]
+const GICD_BASE: usize = 0xFF84_1000;
+#[cfg(feature = "lab")]
+const GICC_BASE: usize = 0xFF84_2000;
+/// VideoCore interrupts start at this SPI on the GIC-400
+#[cfg(feature = "lab")]
+const GIC_VC_IRQ_OFFSET: usize = 96;
+#[cfg(feature = "lab")]
+const GIC_SPURIOUS_IRQ: u32 = 1023;
+
+// BCM2835 ARM peripherals, section 7.5 "Registers".
+#[cfg(feature = "default")]
+register_structs! {
+    #[allow(non_snake_case)]
+    pub LegacyRegisterBlock {
+        (0x00 => BASIC_PENDING: ReadOnly<u32>),
+        (0x04 => PENDING_1: ReadOnly<u32>),
+        (0x08 => PENDING_2: ReadOnly<u32>),
+        (0x0c => FIQ_CONTROL: ReadWrite<u32>),
+        (0x10 => ENABLE_1: WriteOnly<u32>),
+        (0x14 => ENABLE_2: WriteOnly<u32>),
+        (0x18 => ENABLE_BASIC: WriteOnly<u32>),
+        (0x1c => DISABLE_1: WriteOnly<u32>),
+        (0x20 => DISABLE_2: WriteOnly<u32>),
+        (0x24 => DISABLE_BASIC: WriteOnly<u32>),
+        (0x28 => @END),
+    }
+}
+
+// ARM Generic Interrupt Controller Architecture Specification v2, section 4.
+#[cfg(feature = "lab")]
+register_structs! {
+    #[allow(non_snake_case)]
+    pub DistributorRegisterBlock {
+        (0x000 => CTLR: ReadWrite<u32>),
+        (0x004 => _reserved1),
+        (0x100 => ISENABLER: [ReadWrite<u32>; 32]),
+        (0x180 => ICENABLER: [ReadWrite<u32>; 32]),
+        (0x200 => _reserved2),
+        (0x400 => IPRIORITYR: [ReadWrite<u8>; 1024]),
+        (0x800 => ITARGETSR: [ReadWrite<u8>; 1024]),
+        (0xc00 => @END),
+    }
+}
+
+#[cfg(feature = "lab")]
+register_structs! {
+    #[allow(non_snake_case)]
+    pub CPUInterfaceRegisterBlock {
+        (0x00 => CTLR: ReadWrite<u32>),
+        (0x04 => PMR: ReadWrite<u32>),
+        (0x08 => _reserved1),
+        (0x0c => IAR: ReadOnly<u32>),
+        (0x10 => EOIR: WriteOnly<u32>),
+        (0x14 => @END),
+    }
+}
+
+/// The interrupt controller routing VideoCore interrupts to the core.
+///
+/// Interrupt numbers are VideoCore IRQ numbers on both boards.
+#[cfg(feature = "default")]
+pub struct InterruptController {
+    registers: MMIODeRefWrapper<LegacyRegisterBlock>,
+}
+
+#[cfg(feature = "default")]
+impl InterruptController {
+    pub fn new() -> Self {
+        Self {
+            registers: MMIODeRefWrapper::new(LEGACY_IRQ_BASE),
+        }
+    }
+
+    pub fn init(&self) {
+        self.registers.DISABLE_1.set(u32::MAX);
+        self.registers.DISABLE_2.set(u32::MAX);
+        self.registers.DISABLE_BASIC.set(u32::MAX);
+    }
+
+    pub fn enable(&self, irq: usize) {
+        if irq < 32 {
+            self.registers.ENABLE_1.set(1 << irq);
+        } else {
+            self.registers.ENABLE_2.set(1 << (irq - 32));
+        }
+    }
+
+    /// The lowest numbered pending interrupt, which stays pending until its source is cleared
+    pub fn acknowledge(&self) -> Option<usize> {
+        let pending_1 = self.registers.PENDING_1.get();
+        if pending_1 != 0 {
+            return Some(pending_1.trailing_zeros() as usize);
+        }
+
+        let pending_2 = self.registers.PENDING_2.get();
+        if pending_2 != 0 {
+            return Some(32 + pending_2.trailing_zeros() as usize);
+        }
+
+        None
+    }
+
+    pub fn end_of_interrupt(&self, _irq: usize) {}
+}
+
+#[cfg(feature = "lab")]
+pub struct InterruptController {
+    distributor: MMIODeRefWrapper<DistributorRegisterBlock>,
+    cpu_interface: MMIODeRefWrapper<CPUInterfaceRegisterBlock>,
+}
+
+#[cfg(feature = "lab")]
+impl InterruptController {
+    pub fn new() -> Self {
+        Self {
+            distributor: MMIODeRefWrapper::new(GICD_BASE),
+            cpu_interface: MMIODeRefWrapper::new(GICC_BASE),
+        }
+    }
+
+    pub fn init(&self) {
+        self.distributor.CTLR.set(1);
+        self.cpu_interface.PMR.set(0xff);
+        self.cpu_interface.CTLR.set(1);
+    }
+
+    pub fn enable(&self, irq: usize) {
+        let irq = irq + GIC_VC_IRQ_OFFSET;
+        // route to core 0 at the highest priority
+        self.distributor.ITARGETSR[irq].set(1);
+        self.distributor.IPRIORITYR[irq].set(0);
+        self.distributor.ISENABLER[irq / 32].set(1 << (irq % 32));
+    }
+
+    /// Acknowledge the highest priority pending interrupt
+    pub fn acknowledge(&self) -> Option<usize> {
+        let irq = self.cpu_interface.IAR.get() & 0x3ff;
+        if irq == GIC_SPURIOUS_IRQ {
+            None
+        } else {
+            Some(irq as usize - GIC_VC_IRQ_OFFSET)
+        }
+    }
+
+    pub fn end_of_interrupt(&self, irq: usize) {
+        self.cpu_interface
+            .EOIR
+            .set((irq + GIC_VC_IRQ_OFFSET) as u32);
+    }
+}
+
+pub fn init() {
+    let controller = InterruptController::new();
+    controller.init();
+    // the console was brought up by `term::init`
+    #[cfg(all(feature = "lab", not(feature = "sim")))]
+    UART::train();
+    controller.enable(IRQ_TIMER);
+    controller.enable(IRQ_UART);
+    SystemTimer::new().start();
+}
+
+/// Arm the hardware behind `event` before a task waits on it.
+///
+/// Returns the event data right away if it is already available.
+pub fn arm(event: u64) -> Option<u64> {
+    let line = UARTLine::ALL
+        .iter()
+        .copied()
+        .find(|line| event == line.rx_event() || event == line.tx_event())?;
+    with_serial(line, |serial| {
+        if event == line.rx_event() {
+            if !serial.rxwaiting() {
+                return Some(serial.getc_no_wait() as u64);
+            }
+            serial.enable_rx_interrupt();
+            None
+        } else {
+            if !serial.txwaiting() {
+                return Some(0);
+            }
+            // only armed while full, so the FIFO is sure to drain past the trigger level
+            serial.enable_tx_interrupt();
+            None
+        }
+    })
+}
+
+/// Wake whoever waits on whichever direction of `line` is ready
+unsafe fn handle_line(line: UARTLine) {
+    with_serial(line, |serial| {
+        if serial.rx_interrupt_pending() {
+            // masked again until the next AwaitEvent
+            serial.disable_rx_interrupt();
+            let ch = serial.getc_no_wait();
+            CPU_GLOBAL
+                .scheduler
+                .deliver_event(line.rx_event(), ch as u64);
+        }
+
+        if serial.tx_interrupt_pending() {
+            serial.disable_tx_interrupt();
+            if line == UARTLine::Console {
+                // the kernel's own output goes first, and re-arms the interrupt if it still
+                // doesn't fit
+                TERM_GLOBAL.lock().flush();
+            }
+            if serial.txwaiting() {
+                serial.enable_tx_interrupt();
+            } else {
+                CPU_GLOBAL.scheduler.deliver_event(line.tx_event(), 0);
+            }
+        }
+    })
+}
+
+/// Service the pending interrupt, waking the tasks waiting on the matching event.
+///
+/// Anything else still pending fires again as soon as interrupts are unmasked.
+pub unsafe fn handle() {
+    let controller = InterruptController::new();
+    if let Some(irq) = controller.acknowledge() {
+        if irq == IRQ_TIMER {
+            // returning to the scheduler afterwards is what preempts the interrupted task
+            SystemTimer::new().next_tick();
+            CPU_GLOBAL.scheduler.deliver_event(EVENT_TIMER, 0);
+
+            // the simulated box has no interrupt of its own, so it runs off the tick
+            #[cfg(feature = "sim")]
+            {
+                sim::tick();
+                handle_line(UARTLine::Marklin);
+            }
+        } else if irq == IRQ_UART {
+            // both directions of every line share one interrupt, so check each of them
+            for &line in UARTLine::ALL {
+                handle_line(line);
+            }
+        }
+        controller.end_of_interrupt(irq);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::kernel::setup::{MMIODeRefWrapper, MMIO_BASE};
+use core::mem::size_of;
+use tock_registers::{
+    interfaces::{Readable, Writeable},
+    register_structs,
+    registers::{ReadOnly, WriteOnly},
+};
+
+const MAILBOX_BASE: usize = MMIO_BASE + 0xB880;
+
+/// The channel the VideoCore answers property tag requests on
+const CHANNEL_PROPERTY: u32 = 8;
+const STATUS_FULL: u32 = 1 << 31;
+const STATUS_EMPTY: u32 = 1 << 30;
+
+const REQUEST: u32 = 0;
+const RESPONSE_SUCCESS: u32 = 1 << 31;
+const TAG_ARM_MEMORY: u32 = 0x0001_0005;
+const TAG_END: u32 = 0;
+
+// Mailbox 0 is read by the ARM, mailbox 1 is written by it.
+//
+// Taken from the "Mailboxes" page of the raspberrypi/firmware wiki.
+register_structs! {
+    #[allow(non_snake_case)]
+    pub MailboxRegisterBlock {
+        (0x00 => READ: ReadOnly<u32>),
+        (0x04 => _reserved1),
+        (0x18 => STATUS: ReadOnly<u32>),
+        (0x1c => _reserved2),
+        (0x20 => WRITE: WriteOnly<u32>),
+        (0x24 => _reserved3),
+        (0x38 => WRITE_STATUS: ReadOnly<u32>),
+        (0x3c => @END),
+    }
+}
+
+/// Property messages hold the address of the buffer in the upper 28 bits, so it must be 16 byte
+/// aligned
+#[repr(C, align(16))]
+struct Message([u32; 8]);
+
+pub struct Mailbox {
+    registers: MMIODeRefWrapper<MailboxRegisterBlock>,
+}
+
+impl Mailbox {
+    pub fn new() -> Self {
+        Self {
+            registers: MMIODeRefWrapper::new(MAILBOX_BASE),
+        }
+    }
+
+    /// Hand `message` to the VideoCore and wait for its answer. The buffer is read and written
+    /// behind the caches, so this may only be called before they are turned on.
+    unsafe fn call(&self, message: &mut Message) -> bool {
+        let addr = message as *mut Message as u32;
+
+        while self.registers.WRITE_STATUS.get() & STATUS_FULL != 0 {}
+        self.registers.WRITE.set(addr | CHANNEL_PROPERTY);
+
+        loop {
+            while self.registers.STATUS.get() & STATUS_EMPTY != 0 {}
+            if self.registers.READ.get() == addr | CHANNEL_PROPERTY {
+                break;
+            }
+        }
+
+        core::ptr::read_volatile(&message.0[1]) == RESPONSE_SUCCESS
+    }
+
+    /// The base and size of the RAM the ARM owns, which ends where the VideoCore's share begins.
+    ///
+    /// Must be called before the MMU and caches are turned on.
+    pub unsafe fn arm_memory(&self) -> Option<(usize, usize)> {
+        let mut message = Message([
+            size_of::<Message>() as u32,
+            REQUEST,
+            TAG_ARM_MEMORY,
+            8,
+            REQUEST,
+            0,
+            0,
+            TAG_END,
+        ]);
+
+        if !self.call(&mut message) {
+            return None;
+        }
+        let base = core::ptr::read_volatile(&message.0[5]);
+        let size = core::ptr::read_volatile(&message.0[6]);
+        Some((base as usize, size as usize))
+    }
+}
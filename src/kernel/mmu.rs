@@ -0,0 +1,493 @@
+use crate::kernel::{
+    frame::{self, Frame},
+    setup::MMIO_BASE,
+    tasks::{kernel_stack, user_stack_region, MAX_STACK_PAGES, TASK_SIZE},
+};
+use aarch64_cpu::{
+    asm::barrier,
+    registers::{ReadWriteable, Readable, Writeable, MAIR_EL1, SCTLR_EL1, TCR_EL1, TTBR0_EL1},
+};
+use core::{arch::asm, cell::UnsafeCell, ops::Range};
+use heapless::Vec;
+use tock_registers::register_bitfields;
+
+/// 4KiB translation granule
+const PAGE_SIZE: usize = 1 << 12;
+/// Each level 2 entry maps a 2MiB block
+const BLOCK_SIZE: usize = 1 << 21;
+/// Each level 1 entry maps 1GiB
+const L1_SIZE: usize = 1 << 30;
+const ENTRIES: usize = 512;
+/// The low 4GiB of the physical address space is identity mapped, which covers RAM and both the
+/// QEMU and the Pi 4 peripherals
+const ADDRESS_BITS: u64 = 32;
+const NUM_L2_TABLES: usize = (1 << ADDRESS_BITS) / L1_SIZE;
+
+/// Indices into MAIR_EL1
+const ATTR_DEVICE: u64 = 0;
+const ATTR_NORMAL: u64 = 1;
+
+// Descriptor formats for the VMSAv8-64 stage 1 translation with a 4KiB granule.
+//
+// Descriptions taken from "ARM Architecture Reference Manual" D5.3.
+register_bitfields! {
+    u64,
+
+    /// Level 1 descriptor pointing at a level 2 table, or level 2 pointing at level 3
+    TABLE_DESCRIPTOR [
+        /// Physical address of the next level table, bits [47:12]
+        NEXT_LEVEL_TABLE_ADDR OFFSET(12) NUMBITS(36) [],
+
+        TYPE OFFSET(1) NUMBITS(1) [
+            Block = 0,
+            Table = 1
+        ],
+
+        VALID OFFSET(0) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ]
+    ],
+
+    /// Level 2 block or level 3 page descriptor
+    PAGE_DESCRIPTOR [
+        /// Unprivileged execute-never
+        UXN OFFSET(54) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        /// Privileged execute-never
+        PXN OFFSET(53) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        /// Physical address of the block or page, bits [47:12]
+        OUTPUT_ADDR OFFSET(12) NUMBITS(36) [],
+
+        /// Not global, the TLB entry only matches the current ASID
+        NG OFFSET(11) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        /// Access flag, faults on first access when clear
+        AF OFFSET(10) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ],
+
+        SH OFFSET(8) NUMBITS(2) [
+            OuterShareable = 0b10,
+            InnerShareable = 0b11
+        ],
+
+        AP OFFSET(6) NUMBITS(2) [
+            RW_EL1 = 0b00,
+            RW_EL1_EL0 = 0b01,
+            RO_EL1 = 0b10,
+            RO_EL1_EL0 = 0b11
+        ],
+
+        /// Index into MAIR_EL1
+        ATTR_INDX OFFSET(2) NUMBITS(3) [],
+
+        /// Level 2 entries are blocks, level 3 entries must be pages
+        TYPE OFFSET(1) NUMBITS(1) [
+            Block = 0,
+            Page = 1
+        ],
+
+        VALID OFFSET(0) NUMBITS(1) [
+            False = 0,
+            True = 1
+        ]
+    ]
+}
+
+#[repr(C, align(4096))]
+#[derive(Clone, Copy)]
+struct Table([u64; ENTRIES]);
+
+const EMPTY: Table = Table([0; ENTRIES]);
+
+/// The kernel's identity map of the low 4GiB, which every address space shares. The first 2MiB
+/// holds the kernel image, so it is split into pages to let EL0 see the code. The blocks the task
+/// stacks are mapped into have tables of their own, filled in with frames as tasks are created,
+/// which leaves the guard pages between them unmapped.
+#[repr(C)]
+struct KernelTables {
+    l1: Table,
+    l2: [Table; NUM_L2_TABLES],
+    code: Table,
+    kernel_stacks: Table,
+    user_stacks: Table,
+}
+
+/// A task's view of memory: the kernel map, plus its own stack pages opened up to EL0. Its tables
+/// and stacks are frames of their own, so they are given back when the task's descriptor is
+/// dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    l1: Frame,
+    l2: Frame,
+    stack: Frame,
+    /// The frame behind each page of the task's kernel and user stacks, by address
+    stack_pages: Vec<(usize, Frame), MAX_STACK_PAGES>,
+    asid: u64,
+}
+
+impl AddressSpace {
+    /// The value to load into TTBR0_EL1 when the task runs
+    pub fn ttbr0(&self) -> u64 {
+        ttbr0(unsafe { &*(self.l1.addr() as *const Table) }, self.asid)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // the frames may be reused as soon as they are freed, so the walker must stop looking at
+        // them and nothing may still map the stacks
+        unsafe {
+            if TTBR0_EL1.get() == self.ttbr0() {
+                switch_to_kernel();
+            }
+            for (addr, _) in &self.stack_pages {
+                *stack_entry(*addr) = 0;
+            }
+            barrier::dsb(barrier::ISHST);
+            for (addr, _) in &self.stack_pages {
+                asm!("tlbi vaae1, {}", in(reg) *addr as u64 >> 12);
+            }
+            asm!("tlbi aside1, {}", in(reg) self.asid << 48);
+            barrier::dsb(barrier::ISH);
+            barrier::isb(barrier::SY);
+        }
+    }
+}
+
+/// The table stored in `frame`, which is identity mapped for the kernel
+unsafe fn table(frame: &mut Frame) -> &mut Table {
+    &mut *(frame.addr() as *mut Table)
+}
+
+struct TablesCell<T>(UnsafeCell<T>);
+
+// the kernel tables are written once at boot
+unsafe impl<T> Sync for TablesCell<T> {}
+
+static KERNEL_TABLES: TablesCell<KernelTables> = TablesCell(UnsafeCell::new(KernelTables {
+    l1: EMPTY,
+    l2: [EMPTY; NUM_L2_TABLES],
+    code: EMPTY,
+    kernel_stacks: EMPTY,
+    user_stacks: EMPTY,
+}));
+
+fn table_descriptor(table: &Table) -> u64 {
+    (TABLE_DESCRIPTOR::NEXT_LEVEL_TABLE_ADDR.val(table as *const Table as u64 >> 12)
+        + TABLE_DESCRIPTOR::TYPE::Table
+        + TABLE_DESCRIPTOR::VALID::True)
+        .value
+}
+
+/// Everything from `MMIO_BASE` up is a peripheral, everything below it is RAM. Neither is
+/// reachable from EL0.
+fn block_descriptor(addr: usize) -> u64 {
+    let attributes = if addr >= MMIO_BASE {
+        PAGE_DESCRIPTOR::ATTR_INDX.val(ATTR_DEVICE) + PAGE_DESCRIPTOR::SH::OuterShareable
+    } else {
+        PAGE_DESCRIPTOR::ATTR_INDX.val(ATTR_NORMAL) + PAGE_DESCRIPTOR::SH::InnerShareable
+    };
+
+    (attributes
+        + PAGE_DESCRIPTOR::OUTPUT_ADDR.val(addr as u64 >> 12)
+        + PAGE_DESCRIPTOR::AF::True
+        + PAGE_DESCRIPTOR::AP::RW_EL1
+        + PAGE_DESCRIPTOR::UXN::True
+        + PAGE_DESCRIPTOR::PXN::True
+        + PAGE_DESCRIPTOR::TYPE::Block
+        + PAGE_DESCRIPTOR::VALID::True)
+        .value
+}
+
+/// Who may touch a page of RAM
+#[derive(Clone, Copy)]
+enum Access {
+    Kernel,
+    /// Read-only and executable at both ELs, since user tasks run out of the kernel image
+    Code,
+    UserData,
+}
+
+fn page_descriptor(addr: usize, access: Access, global: bool) -> u64 {
+    let permissions = match access {
+        Access::Kernel => {
+            PAGE_DESCRIPTOR::AP::RW_EL1 + PAGE_DESCRIPTOR::UXN::True + PAGE_DESCRIPTOR::PXN::True
+        }
+        Access::Code => {
+            PAGE_DESCRIPTOR::AP::RO_EL1_EL0
+                + PAGE_DESCRIPTOR::UXN::False
+                + PAGE_DESCRIPTOR::PXN::False
+        }
+        Access::UserData => {
+            PAGE_DESCRIPTOR::AP::RW_EL1_EL0
+                + PAGE_DESCRIPTOR::UXN::True
+                + PAGE_DESCRIPTOR::PXN::True
+        }
+    };
+    let scope = if global {
+        PAGE_DESCRIPTOR::NG::False
+    } else {
+        PAGE_DESCRIPTOR::NG::True
+    };
+
+    (permissions
+        + scope
+        + PAGE_DESCRIPTOR::OUTPUT_ADDR.val(addr as u64 >> 12)
+        + PAGE_DESCRIPTOR::ATTR_INDX.val(ATTR_NORMAL)
+        + PAGE_DESCRIPTOR::SH::InnerShareable
+        + PAGE_DESCRIPTOR::AF::True
+        + PAGE_DESCRIPTOR::TYPE::Page
+        + PAGE_DESCRIPTOR::VALID::True)
+        .value
+}
+
+/// The 2MiB block that every one of `stacks` lives in
+fn stack_block(stacks: impl Fn(usize) -> Range<u64>) -> usize {
+    let block = stacks(0).start as usize / BLOCK_SIZE;
+    for index in 0..TASK_SIZE {
+        let stack = stacks(index);
+        assert!(stack.start as usize / BLOCK_SIZE == block);
+        assert!((stack.end - 1) as usize / BLOCK_SIZE == block);
+    }
+    assert!(block != 0 && block < ENTRIES);
+    block
+}
+
+/// The address of every page in `range`
+fn pages(range: &Range<u64>) -> impl Iterator<Item = usize> {
+    (range.start as usize..range.end as usize).step_by(PAGE_SIZE)
+}
+
+/// Where a page of a block is in the block's table
+fn page_index(addr: usize) -> usize {
+    addr % BLOCK_SIZE / PAGE_SIZE
+}
+
+/// The kernel map's entry for the stack page at `addr`
+unsafe fn stack_entry(addr: usize) -> &'static mut u64 {
+    let tables = &mut *KERNEL_TABLES.0.get();
+    let table = if addr / BLOCK_SIZE == kernel_stack(0).start as usize / BLOCK_SIZE {
+        &mut tables.kernel_stacks
+    } else {
+        &mut tables.user_stacks
+    };
+    &mut table.0[page_index(addr)]
+}
+
+unsafe fn populate(tables: &mut KernelTables) {
+    extern "Rust" {
+        static __code_start: UnsafeCell<()>;
+        static __code_end_exclusive: UnsafeCell<()>;
+    }
+
+    let code = __code_start.get() as usize..__code_end_exclusive.get() as usize;
+
+    for (i, entry) in tables.code.0.iter_mut().enumerate() {
+        let addr = i * PAGE_SIZE;
+        let access = if code.contains(&addr) {
+            Access::Code
+        } else {
+            Access::Kernel
+        };
+        *entry = page_descriptor(addr, access, true);
+    }
+
+    // the stack tables start out empty, `map_task` fills them in
+    let kernel_stacks = stack_block(kernel_stack);
+    let user_stacks = stack_block(user_stack_region);
+
+    for (i, l2) in tables.l2.iter_mut().enumerate() {
+        for (j, entry) in l2.0.iter_mut().enumerate() {
+            *entry = block_descriptor(i * L1_SIZE + j * BLOCK_SIZE);
+        }
+        tables.l1.0[i] = table_descriptor(l2);
+    }
+
+    tables.l2[0].0[0] = table_descriptor(&tables.code);
+    tables.l2[0].0[kernel_stacks] = table_descriptor(&tables.kernel_stacks);
+    tables.l2[0].0[user_stacks] = table_descriptor(&tables.user_stacks);
+}
+
+fn ttbr0(l1: &Table, asid: u64) -> u64 {
+    (TTBR0_EL1::ASID.val(asid) + TTBR0_EL1::BADDR.val(l1 as *const Table as u64 >> 1)).value
+}
+
+/// Build the address space for task slot `index`, backing its user `stack` and `kernel_stack`
+/// with frames of their own. The kernel map gets both stacks, so that from its own address space
+/// the kernel can reach every task's, while the task's tables only open up its user stack. The
+/// rest of the slot's stack region is left unmapped as a guard. Returns `None` if there are not
+/// enough frames.
+pub unsafe fn map_task(
+    index: usize,
+    stack: Range<u64>,
+    kernel_stack: Range<u64>,
+) -> Option<AddressSpace> {
+    let mut space = AddressSpace {
+        l1: frame::alloc()?,
+        l2: frame::alloc()?,
+        stack: frame::alloc()?,
+        stack_pages: Vec::new(),
+        // ASID 0 is the kernel's
+        asid: index as u64 + 1,
+    };
+    let block = stack.start as usize / BLOCK_SIZE;
+
+    let stack_table = table(&mut space.stack);
+    *stack_table = EMPTY;
+    for addr in pages(&stack) {
+        let frame = frame::alloc()?;
+        // whatever its last owner left in it is none of this task's business
+        core::ptr::write_bytes(frame.addr() as *mut u8, 0, PAGE_SIZE);
+        stack_table.0[page_index(addr)] = page_descriptor(frame.addr(), Access::UserData, false);
+        // not global, or the kernel's entry could stand in for the task's own
+        *stack_entry(addr) = page_descriptor(frame.addr(), Access::Kernel, false);
+        let _ = space.stack_pages.push((addr, frame));
+    }
+    for addr in pages(&kernel_stack) {
+        let frame = frame::alloc()?;
+        *stack_entry(addr) = page_descriptor(frame.addr(), Access::Kernel, true);
+        let _ = space.stack_pages.push((addr, frame));
+    }
+
+    let kernel = &*KERNEL_TABLES.0.get();
+    let l2 = table(&mut space.l2);
+    *l2 = kernel.l2[0];
+    l2.0[block] = table_descriptor(stack_table);
+    let l1 = table(&mut space.l1);
+    *l1 = kernel.l1;
+    l1.0[0] = table_descriptor(l2);
+
+    // the slot's last owner may have left entries behind
+    barrier::dsb(barrier::ISHST);
+    asm!("tlbi aside1, {}", in(reg) space.asid << 48);
+    barrier::dsb(barrier::ISH);
+
+    Some(space)
+}
+
+/// Switch to the address space whose `ttbr0` is given.
+pub unsafe fn switch_to(ttbr0: u64) {
+    TTBR0_EL1.set(ttbr0);
+    barrier::isb(barrier::SY);
+}
+
+/// Switch to the kernel's own address space, the only one that maps every task's stacks.
+pub unsafe fn switch_to_kernel() {
+    if cfg!(feature = "mmu") {
+        switch_to(ttbr0(&(*KERNEL_TABLES.0.get()).l1, 0));
+    }
+}
+
+/// Build the identity map and turn on the MMU if the `mmu` feature is on, with the caches picked by
+/// the `icache` and `dcache` features
+pub unsafe fn init() {
+    if cfg!(feature = "mmu") {
+        set_up_translation();
+    }
+
+    let translation = if cfg!(feature = "mmu") {
+        SCTLR_EL1::M::Enable
+    } else {
+        SCTLR_EL1::M::Disable
+    };
+    let instruction_cache = if cfg!(feature = "icache") {
+        SCTLR_EL1::I::Cacheable
+    } else {
+        SCTLR_EL1::I::NonCacheable
+    };
+    let data_cache = if cfg!(feature = "dcache") {
+        SCTLR_EL1::C::Cacheable
+    } else {
+        SCTLR_EL1::C::NonCacheable
+    };
+
+    if cfg!(feature = "dcache") {
+        invalidate_dcache();
+    }
+    SCTLR_EL1.modify(translation + SCTLR_EL1::A::Disable + instruction_cache + data_cache);
+    barrier::isb(barrier::SY);
+}
+
+/// Throw away whatever the data and unified caches hold, set by set and way, up to the point of
+/// coherency. Their contents are unknown out of reset, and turning them on without this could
+/// hand back stale lines or write them over RAM.
+unsafe fn invalidate_dcache() {
+    let clidr: u64;
+    asm!("mrs {}, clidr_el1", out(reg) clidr);
+    let coherency_level = (clidr >> 24) & 0b111;
+
+    for level in 0..coherency_level {
+        // 0b010 and up have a data or unified cache at this level
+        if (clidr >> (level * 3)) & 0b111 < 0b010 {
+            continue;
+        }
+
+        let ccsidr: u64;
+        asm!("msr csselr_el1, {}", in(reg) level << 1);
+        barrier::isb(barrier::SY);
+        asm!("mrs {}, ccsidr_el1", out(reg) ccsidr);
+
+        let line_shift = (ccsidr & 0b111) + 4;
+        let ways = ((ccsidr >> 3) & 0x3ff) + 1;
+        let sets = ((ccsidr >> 13) & 0x7fff) + 1;
+        // the way goes in the top bits, as many as it takes to count them
+        let way_shift = (ways as u32 - 1).leading_zeros();
+
+        for way in 0..ways {
+            for set in 0..sets {
+                let way_bits = if ways > 1 { way << way_shift } else { 0 };
+                let operand = way_bits | (set << line_shift) | (level << 1);
+                asm!("dc isw, {}", in(reg) operand);
+            }
+        }
+    }
+
+    barrier::dsb(barrier::SY);
+    barrier::isb(barrier::SY);
+}
+
+unsafe fn set_up_translation() {
+    let tables = &mut *KERNEL_TABLES.0.get();
+    populate(tables);
+
+    MAIR_EL1.write(
+        MAIR_EL1::Attr0_Device::nonGathering_nonReordering_EarlyWriteAck
+            + MAIR_EL1::Attr1_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
+            + MAIR_EL1::Attr1_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc,
+    );
+
+    TTBR0_EL1.set(ttbr0(&tables.l1, 0));
+
+    TCR_EL1.write(
+        TCR_EL1::TBI0::Used
+            + TCR_EL1::IPS::Bits_32
+            + TCR_EL1::TG0::KiB_4
+            + TCR_EL1::SH0::Inner
+            + TCR_EL1::ORGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::IRGN0::WriteBack_ReadAlloc_WriteAlloc_Cacheable
+            + TCR_EL1::EPD0::EnableTTBR0Walks
+            + TCR_EL1::A1::TTBR0
+            + TCR_EL1::AS::ASID8Bits
+            + TCR_EL1::T0SZ.val(64 - ADDRESS_BITS)
+            + TCR_EL1::EPD1::DisableTTBR1Walks,
+    );
+
+    // the tables must be in memory before the walker looks at them
+    barrier::dsb(barrier::ISHST);
+    asm!("tlbi vmalle1");
+    barrier::dsb(barrier::ISH);
+    barrier::isb(barrier::SY);
+}
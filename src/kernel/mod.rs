@@ -1,9 +1,17 @@
 mod asm;
 mod boot;
 mod console;
+mod fault;
+mod frame;
+mod heap;
+mod interrupt;
+mod mailbox;
+mod mmu;
 mod setup;
+#[cfg(feature = "sim")]
+mod sim;
 mod sys_syscall;
-pub mod syscall;
 mod tasks;
 pub mod term;
+mod uaccess;
 mod utils;
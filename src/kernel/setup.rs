@@ -1,20 +1,36 @@
 use core::{marker::PhantomData, ops::Deref};
 
-use aarch64_cpu::asm;
+#[cfg(feature = "sim")]
+use crate::kernel::sim;
+
+use lunaris_user::syscall::{
+    EVENT_CONSOLE_RX, EVENT_CONSOLE_TX, EVENT_MARKLIN_RX, EVENT_MARKLIN_TX, LINE_CONSOLE,
+    LINE_MARKLIN,
+};
 use tock_registers::{
-    interfaces::{Readable, Writeable},
+    interfaces::{ReadWriteable, Readable, Writeable},
     register_bitfields, register_structs,
     registers::{ReadOnly, ReadWrite, WriteOnly},
 };
 
 #[cfg(feature = "lab")]
-const MMIO_BASE: usize = 0xFE00_0000;
+pub(super) const MMIO_BASE: usize = 0xFE00_0000;
 #[cfg(feature = "default")]
-const MMIO_BASE: usize = 0x3F00_0000;
+pub(super) const MMIO_BASE: usize = 0x3F00_0000;
 
 const UART0_BASE: usize = MMIO_BASE + 0x201000;
 const UART3_BASE: usize = MMIO_BASE + 0x201600;
 const CLK_BASE: usize = MMIO_BASE + 0x3000;
+#[cfg(feature = "lab")]
+const GPIO_BASE: usize = MMIO_BASE + 0x200000;
+/// GPIO 4 and 5 carry UART3's TXD and RXD in alternate function 4
+#[cfg(feature = "lab")]
+const UART3_PINS: [usize; 2] = [4, 5];
+#[cfg(feature = "lab")]
+const GPIO_FUNCTION_ALT4: u32 = 0b011;
+
+/// The system timer counts at 1MHz
+pub const TICK_US: u32 = 10_000;
 
 // PL011 UART registers.
 //
@@ -145,6 +161,31 @@ register_bitfields! {
         ]
     ],
 
+    /// Interrupt Mask Set/Clear Register.
+    IMSC [
+        /// Receive timeout interrupt mask. A read returns the current mask for the UARTRTINTR
+        /// interrupt.
+        RTIM OFFSET(6) NUMBITS(1) [],
+
+        /// Transmit interrupt mask. A read returns the current mask for the UARTTXINTR interrupt.
+        TXIM OFFSET(5) NUMBITS(1) [],
+
+        /// Receive interrupt mask. A read returns the current mask for the UARTRXINTR interrupt.
+        RXIM OFFSET(4) NUMBITS(1) []
+    ],
+
+    /// Masked Interrupt Status Register.
+    MIS [
+        /// Receive timeout masked interrupt status.
+        RTMIS OFFSET(6) NUMBITS(1) [],
+
+        /// Transmit masked interrupt status.
+        TXMIS OFFSET(5) NUMBITS(1) [],
+
+        /// Receive masked interrupt status.
+        RXMIS OFFSET(4) NUMBITS(1) []
+    ],
+
     /// Interrupt Clear Register.
     ICR [
         /// Meta field for all pending interrupts.
@@ -152,6 +193,20 @@ register_bitfields! {
     ]
 }
 
+// BCM2835 system timer registers.
+//
+// Descriptions taken from "BCM2835 ARM Peripherals", chapter 12.
+register_bitfields! {
+    u32,
+
+    /// System Timer Control/Status.
+    CS [
+        /// System Timer Match 1. Set when the free running counter matches C1, cleared by writing
+        /// a 1 to it. Channels 0 and 2 belong to the GPU.
+        M1 OFFSET(1) NUMBITS(1) []
+    ]
+}
+
 register_structs! {
     #[allow(non_snake_case)]
     pub RegisterBlock {
@@ -164,11 +219,28 @@ register_structs! {
         (0x2c => LCR_H: WriteOnly<u32, LCR_H::Register>),
         (0x30 => CR: WriteOnly<u32, CR::Register>),
         (0x34 => _reserved3),
+        (0x38 => IMSC: ReadWrite<u32, IMSC::Register>),
+        (0x3c => _reserved4),
+        (0x40 => MIS: ReadOnly<u32, MIS::Register>),
         (0x44 => ICR: WriteOnly<u32, ICR::Register>),
         (0x48 => @END),
     }
 }
 
+// BCM2711 GPIO registers.
+//
+// Taken from "BCM2711 ARM Peripherals", section 5.2 "Register View".
+#[cfg(feature = "lab")]
+register_structs! {
+    #[allow(non_snake_case)]
+    pub GPIORegisterBlock {
+        (0x00 => GPFSEL: [ReadWrite<u32>; 6]),
+        (0x18 => _reserved1),
+        (0xe4 => GPIO_PUP_PDN_CNTRL: [ReadWrite<u32>; 4]),
+        (0xf4 => @END),
+    }
+}
+
 pub struct MMIODeRefWrapper<T> {
     start_addr: usize,
     phantom: PhantomData<T>,
@@ -191,15 +263,58 @@ impl<T> Deref for MMIODeRefWrapper<T> {
     }
 }
 
+register_structs! {
+    #[allow(non_snake_case)]
+    pub TimerRegisterBlock {
+        (0x00 => CS: ReadWrite<u32, CS::Register>),
+        (0x04 => CLO: ReadOnly<u32>),
+        (0x08 => CHI: ReadOnly<u32>),
+        (0x0c => C0: ReadWrite<u32>),
+        (0x10 => C1: ReadWrite<u32>),
+        (0x14 => C2: ReadWrite<u32>),
+        (0x18 => C3: ReadWrite<u32>),
+        (0x1c => @END),
+    }
+}
+
 type Registers = MMIODeRefWrapper<RegisterBlock>;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum UARTLine {
     Console,
     Marklin,
 }
 
 impl UARTLine {
+    #[cfg(any(feature = "lab", feature = "sim"))]
+    pub const ALL: &'static [UARTLine] = &[UARTLine::Console, UARTLine::Marklin];
+    /// The raspi3b has no UART3, and QEMU reads its registers back as endless input
+    #[cfg(not(any(feature = "lab", feature = "sim")))]
+    pub const ALL: &'static [UARTLine] = &[UARTLine::Console];
+
+    /// The line a syscall names by number, see `LINE_CONSOLE`
+    pub fn from_line(line: u64) -> Option<Self> {
+        match line {
+            LINE_CONSOLE => Some(UARTLine::Console),
+            LINE_MARKLIN if Self::ALL.contains(&UARTLine::Marklin) => Some(UARTLine::Marklin),
+            _ => None,
+        }
+    }
+
+    pub fn rx_event(&self) -> u64 {
+        match self {
+            UARTLine::Console => EVENT_CONSOLE_RX,
+            UARTLine::Marklin => EVENT_MARKLIN_RX,
+        }
+    }
+
+    pub fn tx_event(&self) -> u64 {
+        match self {
+            UARTLine::Console => EVENT_CONSOLE_TX,
+            UARTLine::Marklin => EVENT_MARKLIN_TX,
+        }
+    }
+
     pub fn addr(&self) -> usize {
         match self {
             UARTLine::Console => UART0_BASE,
@@ -214,7 +329,8 @@ pub struct UART {
 }
 
 impl UART {
-    fn new(line: UARTLine) -> Self {
+    /// A handle on an already initialised line
+    pub fn new(line: UARTLine) -> Self {
         Self {
             registers: Registers::new(line.addr()),
             line,
@@ -228,6 +344,9 @@ impl UART {
     }
 
     pub fn train() -> Self {
+        #[cfg(feature = "lab")]
+        Gpio::new().select_uart3();
+
         let mut uart = Self::new(UARTLine::Marklin);
         uart.init();
         uart
@@ -236,14 +355,16 @@ impl UART {
     fn init(&mut self) {
         let (baud_ival, baud_fval): (u32, u32);
 
+        // the divisor is UARTCLK / (16 * baud), with UARTCLK at 48MHz and the fraction in 64ths
         match self.line {
             UARTLine::Console => {
                 baud_ival = 26;
                 baud_fval = 2;
             }
+            // 2400 baud comes out at exactly 1250
             UARTLine::Marklin => {
                 baud_ival = 1250;
-                baud_fval = 1;
+                baud_fval = 0;
             }
         }
 
@@ -259,7 +380,8 @@ impl UART {
                 + LCR_H::PEN::ParityDisable
                 + match self.line {
                     UARTLine::Console => LCR_H::STP2::TwoStopBits,
-                    UARTLine::Marklin => LCR_H::STP2::CLEAR,
+                    // the Märklin box wants 8N2
+                    UARTLine::Marklin => LCR_H::STP2::TwoStopBits,
                 },
         );
 
@@ -268,40 +390,170 @@ impl UART {
             .write(CR::UARTEN::Enabled + CR::TXE::Enabled + CR::RXE::Enabled);
     }
 
-    pub fn getc_no_wait(&self) -> char {
+    pub fn putc_nowait(&mut self, ch: u8) {
+        self.registers.DR.set(ch as u32)
+    }
+}
+
+/// What the kernel does with a line, so something other than a PL011 can stand in for one
+pub trait Serial {
+    fn getc_no_wait(&mut self) -> char;
+
+    /// Whether the receive FIFO is empty
+    fn rxwaiting(&self) -> bool;
+
+    fn enable_rx_interrupt(&mut self);
+
+    fn disable_rx_interrupt(&mut self);
+
+    /// Whether an unmasked receive or receive timeout interrupt is pending
+    fn rx_interrupt_pending(&self) -> bool;
+
+    /// Fires once the transmit FIFO drains below its trigger level, so it should only be enabled
+    /// while the FIFO is full
+    fn enable_tx_interrupt(&mut self);
+
+    fn disable_tx_interrupt(&mut self);
+
+    fn tx_interrupt_pending(&self) -> bool;
+
+    /// Whether the transmit FIFO is full
+    fn txwaiting(&self) -> bool;
+
+    /// Put as much of `slice` in the transmit FIFO as fits right now, returning how much that was
+    fn put_slice_nowait(&mut self, slice: &[u8]) -> usize;
+}
+
+impl Serial for UART {
+    fn getc_no_wait(&mut self) -> char {
         self.registers.DR.get() as u8 as char
     }
 
-    pub fn rxwaiting(&self) -> bool {
+    fn rxwaiting(&self) -> bool {
         self.registers.FR.is_set(FR::RXFE)
     }
 
-    pub fn getc(&self) -> char {
-        while self.rxwaiting() {
-            asm::nop()
-        }
-        self.getc_no_wait()
+    fn enable_rx_interrupt(&mut self) {
+        self.registers
+            .IMSC
+            .modify(IMSC::RXIM::SET + IMSC::RTIM::SET);
+    }
+
+    fn disable_rx_interrupt(&mut self) {
+        self.registers
+            .IMSC
+            .modify(IMSC::RXIM::CLEAR + IMSC::RTIM::CLEAR);
+    }
+
+    fn rx_interrupt_pending(&self) -> bool {
+        self.registers.MIS.is_set(MIS::RXMIS) || self.registers.MIS.is_set(MIS::RTMIS)
     }
 
-    pub fn txwaiting(&self) -> bool {
+    fn enable_tx_interrupt(&mut self) {
+        self.registers.IMSC.modify(IMSC::TXIM::SET);
+    }
+
+    fn disable_tx_interrupt(&mut self) {
+        self.registers.IMSC.modify(IMSC::TXIM::CLEAR);
+    }
+
+    fn tx_interrupt_pending(&self) -> bool {
+        self.registers.MIS.is_set(MIS::TXMIS)
+    }
+
+    fn txwaiting(&self) -> bool {
         self.registers.FR.is_set(FR::TXFF)
     }
 
-    pub fn putc_nowait(&mut self, ch: u8) {
-        self.registers.DR.set(ch as u32)
+    fn put_slice_nowait(&mut self, slice: &[u8]) -> usize {
+        let mut written = 0;
+        for &ch in slice {
+            if self.txwaiting() {
+                break;
+            }
+            self.putc_nowait(ch);
+            written += 1;
+        }
+        written
     }
+}
 
-    pub fn putc(&mut self, ch: u8) {
-        while self.txwaiting() {}
-        self.putc_nowait(ch)
+/// Run `f` on whatever carries `line`
+pub fn with_serial<R>(line: UARTLine, f: impl FnOnce(&mut dyn Serial) -> R) -> R {
+    #[cfg(feature = "sim")]
+    if line == UARTLine::Marklin {
+        return sim::with_line(f);
     }
+    f(&mut UART::new(line))
+}
 
-    pub fn put_slice(&mut self, slice: &[u8]) {
-        slice.iter().for_each(|ch| self.putc(*ch));
+#[cfg(feature = "lab")]
+pub struct Gpio {
+    registers: MMIODeRefWrapper<GPIORegisterBlock>,
+}
+
+#[cfg(feature = "lab")]
+impl Gpio {
+    pub fn new() -> Self {
+        Self {
+            registers: MMIODeRefWrapper::new(GPIO_BASE),
+        }
+    }
+
+    /// Hand `pin` to alternate `function`, with neither pull resistor
+    fn select(&self, pin: usize, function: u32) {
+        let fsel = &self.registers.GPFSEL[pin / 10];
+        let shift = (pin % 10) * 3;
+        fsel.set(fsel.get() & !(0b111 << shift) | function << shift);
+
+        let pull = &self.registers.GPIO_PUP_PDN_CNTRL[pin / 16];
+        pull.set(pull.get() & !(0b11 << ((pin % 16) * 2)));
+    }
+
+    /// Route UART3 to the header pins the Märklin box is wired to
+    pub fn select_uart3(&self) {
+        for pin in UART3_PINS {
+            self.select(pin, GPIO_FUNCTION_ALT4);
+        }
+    }
+}
+
+/// The free running 1MHz system timer, using compare channel 1 for a periodic tick
+pub struct SystemTimer {
+    registers: MMIODeRefWrapper<TimerRegisterBlock>,
+}
+
+impl SystemTimer {
+    pub fn new() -> Self {
+        Self {
+            registers: MMIODeRefWrapper::new(CLK_BASE),
+        }
+    }
+
+    /// Microseconds since boot, only the low 32 bits
+    pub fn now(&self) -> u32 {
+        self.registers.CLO.get()
     }
 
-    pub fn println(&mut self, str: &[u8]) {
-        str.iter().for_each(|ch| self.putc(*ch));
-        self.putc(b'\n');
+    /// Fire the first tick one period from now
+    pub fn start(&self) {
+        self.registers.C1.set(self.now().wrapping_add(TICK_US));
+        self.registers.CS.write(CS::M1::SET);
+    }
+
+    pub fn tick_pending(&self) -> bool {
+        self.registers.CS.is_set(CS::M1)
+    }
+
+    /// Clear the match and schedule the next tick relative to the last one, so ticks don't drift
+    pub fn next_tick(&self) {
+        self.registers.CS.write(CS::M1::SET);
+
+        let mut next = self.registers.C1.get().wrapping_add(TICK_US);
+        // we missed a whole period, so catch up rather than wait for the counter to wrap
+        if next.wrapping_sub(self.now()) > TICK_US {
+            next = self.now().wrapping_add(TICK_US);
+        }
+        self.registers.C1.set(next);
     }
 }
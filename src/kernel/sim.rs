@@ -0,0 +1,265 @@
+use crate::kernel::setup::{Serial, TICK_US};
+use crate::kernel::utils::Spinlock as Mutex;
+use heapless::Deque;
+use lunaris_user::marklin::{
+    DUMP_BANK, DUMP_BANKS, GO, LIGHTS, SENSORS_PER_BANK, SENSOR_REPORT_SIZE, SOLENOID_OFF,
+    SPEED_REVERSE, STOP, SWITCH_CURVED, SWITCH_STRAIGHT,
+};
+use lunaris_user::track::{NodeId, NodeKind, Track, DIR_CURVED, DIR_STRAIGHT, TRACK_A};
+
+const SIM_TRACK: &Track = &TRACK_A;
+/// The trains on the simulated track, and the sensor each starts on
+const SIM_TRAINS: [(u8, &str); 4] = [(1, "A1"), (24, "B1"), (58, "C1"), (77, "D1")];
+/// mm/s at each speed, roughly what the lab's trains manage
+const SPEED_MM_PER_S: [u32; 15] = [
+    0, 40, 80, 120, 160, 200, 240, 280, 320, 360, 400, 440, 480, 520, 560,
+];
+/// How much a train speeds up or slows down each tick, in mm/s
+const ACCEL_PER_TICK: u32 = 3;
+const TICK_MS: u32 = TICK_US / 1000;
+/// 2400 baud with 8 data and 2 stop bits is about 218 bytes a second
+const BYTES_PER_TICK: usize = 2;
+/// Room for a dump of every bank, and then some
+const OUTGOING_SIZE: usize = 64;
+/// The PL011's receive FIFO
+const FIFO_SIZE: usize = 16;
+
+struct Train {
+    number: u8,
+    /// The edge the front of the train is on
+    from: NodeId,
+    to: NodeId,
+    length_um: u32,
+    /// How far along it the front is
+    offset_um: u32,
+    velocity: u32,
+    speed: u8,
+}
+
+/// A Märklin box and track in the kernel, standing in for UART3. Trains move at their commanded
+/// speeds each tick, trip the sensors they pass and are answered for in dumps as the box would.
+pub struct Simulator {
+    trains: [Train; SIM_TRAINS.len()],
+    /// Indexed by switch number
+    curved: [bool; 256],
+    /// Sensors tripped since they were last dumped, laid out as the box sends them
+    triggered: [u8; SENSOR_REPORT_SIZE],
+    reset_mode: bool,
+    powered: bool,
+    /// The first byte of a two byte command
+    pending: Option<u8>,
+    /// What the box has yet to send, let out at the line's rate
+    outgoing: Deque<u8, OUTGOING_SIZE>,
+    /// What has arrived, as if in the receive FIFO
+    fifo: Deque<u8, FIFO_SIZE>,
+    rx_armed: bool,
+}
+
+static SIMULATOR: Mutex<Option<Simulator>> = Mutex::new(None);
+
+/// The edge out of `node` with the switches as they are, or `None` at a dead end
+fn next_edge(node: NodeId, curved: &[bool; 256]) -> Option<(NodeId, u32)> {
+    let node = &SIM_TRACK.nodes[node];
+    let dir = match node.kind {
+        NodeKind::Branch(switch) if curved[switch as usize] => DIR_CURVED,
+        _ => DIR_STRAIGHT,
+    };
+    node.edges[dir].map(|edge| (edge.dest, edge.dist))
+}
+
+impl Simulator {
+    fn new() -> Self {
+        let curved = [false; 256];
+        let trains = SIM_TRAINS.map(|(number, start)| {
+            let from = SIM_TRACK.find(start).unwrap();
+            let (to, dist) = next_edge(from, &curved).unwrap();
+            Train {
+                number,
+                from,
+                to,
+                length_um: dist * 1000,
+                offset_um: 0,
+                velocity: 0,
+                speed: 0,
+            }
+        });
+
+        Self {
+            trains,
+            curved,
+            triggered: [0; SENSOR_REPORT_SIZE],
+            reset_mode: false,
+            powered: true,
+            pending: None,
+            outgoing: Deque::new(),
+            fifo: Deque::new(),
+            rx_armed: false,
+        }
+    }
+
+    fn train(&mut self, number: u8) -> Option<&mut Train> {
+        self.trains.iter_mut().find(|train| train.number == number)
+    }
+
+    fn trigger(&mut self, node: NodeId) {
+        if let NodeKind::Sensor(sensor) = SIM_TRACK.nodes[node].kind {
+            let index = sensor.index();
+            self.triggered[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Queue bank `bank`, counting from 0, the way the box sends it
+    fn dump(&mut self, bank: usize) {
+        let bytes = SENSORS_PER_BANK / 8;
+        for i in bank * bytes..(bank + 1) * bytes {
+            let byte = self.triggered.get(i).copied().unwrap_or(0);
+            let _ = self.outgoing.push_back(byte);
+            if self.reset_mode && i < SENSOR_REPORT_SIZE {
+                self.triggered[i] = 0;
+            }
+        }
+    }
+
+    fn command(&mut self, byte: u8) {
+        if let Some(first) = self.pending.take() {
+            match first {
+                SWITCH_STRAIGHT => self.curved[byte as usize] = false,
+                SWITCH_CURVED => self.curved[byte as usize] = true,
+                _ => {
+                    if let Some(train) = self.train(byte) {
+                        match first & !LIGHTS {
+                            // trains flip at once, and have to build speed up again
+                            SPEED_REVERSE => {
+                                let (from, to) = (train.from, train.to);
+                                train.from = SIM_TRACK.nodes[to].reverse;
+                                train.to = SIM_TRACK.nodes[from].reverse;
+                                train.offset_um = train.length_um - train.offset_um;
+                                train.velocity = 0;
+                            }
+                            speed => train.speed = speed,
+                        }
+                    }
+                }
+            }
+            return;
+        }
+
+        match byte {
+            SWITCH_STRAIGHT | SWITCH_CURVED => self.pending = Some(byte),
+            _ if byte < SOLENOID_OFF => self.pending = Some(byte),
+            GO => self.powered = true,
+            STOP => self.powered = false,
+            DUMP_BANKS => self.reset_mode = false,
+            DUMP_BANK => self.reset_mode = true,
+            _ if byte > DUMP_BANKS && byte < DUMP_BANK => {
+                for bank in 0..(byte - DUMP_BANKS) as usize {
+                    self.dump(bank);
+                }
+            }
+            _ if byte > DUMP_BANK && byte < DUMP_BANK + 0x20 => {
+                self.dump((byte - DUMP_BANK - 1) as usize);
+            }
+            _ => {}
+        }
+    }
+
+    /// Move every train on by one tick, and let out the next bytes of any answer
+    fn tick(&mut self) {
+        for _ in 0..BYTES_PER_TICK {
+            if let Some(byte) = self.outgoing.pop_front() {
+                let _ = self.fifo.push_back(byte);
+            }
+        }
+
+        for i in 0..self.trains.len() {
+            let train = &mut self.trains[i];
+            if !self.powered {
+                // cutting power stops everything where it is
+                train.velocity = 0;
+                continue;
+            }
+            let target = SPEED_MM_PER_S[train.speed as usize];
+            train.velocity = if train.velocity < target {
+                (train.velocity + ACCEL_PER_TICK).min(target)
+            } else {
+                train.velocity.saturating_sub(ACCEL_PER_TICK).max(target)
+            };
+            // mm/s for a tick of ms is µm
+            train.offset_um += train.velocity * TICK_MS;
+
+            while self.trains[i].offset_um >= self.trains[i].length_um {
+                let train = &self.trains[i];
+                let reached = train.to;
+                match next_edge(reached, &self.curved) {
+                    Some((to, dist)) => {
+                        let train = &mut self.trains[i];
+                        train.offset_um -= train.length_um;
+                        train.from = reached;
+                        train.to = to;
+                        train.length_um = dist * 1000;
+                        self.trigger(reached);
+                    }
+                    None => {
+                        // ran into a dead end
+                        let train = &mut self.trains[i];
+                        train.offset_um = train.length_um;
+                        train.velocity = 0;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Serial for Simulator {
+    fn getc_no_wait(&mut self) -> char {
+        self.fifo.pop_front().unwrap_or(0) as char
+    }
+
+    fn rxwaiting(&self) -> bool {
+        self.fifo.is_empty()
+    }
+
+    fn enable_rx_interrupt(&mut self) {
+        self.rx_armed = true;
+    }
+
+    fn disable_rx_interrupt(&mut self) {
+        self.rx_armed = false;
+    }
+
+    fn rx_interrupt_pending(&self) -> bool {
+        self.rx_armed && !self.fifo.is_empty()
+    }
+
+    /// The box takes commands as fast as they come, so there is never anything to wait for
+    fn enable_tx_interrupt(&mut self) {}
+
+    fn disable_tx_interrupt(&mut self) {}
+
+    fn tx_interrupt_pending(&self) -> bool {
+        false
+    }
+
+    fn txwaiting(&self) -> bool {
+        false
+    }
+
+    fn put_slice_nowait(&mut self, slice: &[u8]) -> usize {
+        for &byte in slice {
+            self.command(byte);
+        }
+        slice.len()
+    }
+}
+
+/// Run `f` on the simulated Märklin line
+pub fn with_line<R>(f: impl FnOnce(&mut dyn Serial) -> R) -> R {
+    f(SIMULATOR.lock().get_or_insert_with(Simulator::new))
+}
+
+/// Advance the simulation by one timer tick
+pub fn tick() {
+    SIMULATOR.lock().get_or_insert_with(Simulator::new).tick();
+}
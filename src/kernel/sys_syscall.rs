@@ -1,12 +1,30 @@
 use crate::kernel::{
-    syscall::{
-        EXCEPTION_CODE_CREATE, EXCEPTION_CODE_EXIT, EXCEPTION_CODE_MY_PARENT_TID,
-        EXCEPTION_CODE_MY_TID, EXCEPTION_CODE_YIELD,
-    },
+    fault::{self, Fault},
+    interrupt, mmu,
+    setup::{with_serial, UARTLine},
     tasks::{Context, Task, TaskRunState, CPU_GLOBAL},
+    term::TERM_GLOBAL,
+    uaccess::{self, Access},
+    utils::{force_unlock, SpinlockGuard},
 };
 use aarch64_cpu as cpu;
-use cpu::registers::{Readable, ESR_EL1};
+use core::mem::size_of;
+use cpu::registers::{Readable, ELR_EL1, ESR_EL1, FAR_EL1};
+use lunaris_user::syscall::{
+    SyscallError, TaskOptions, Tid, EVENT_COUNT, EXCEPTION_CODE_AWAIT_EVENT, EXCEPTION_CODE_CREATE,
+    EXCEPTION_CODE_CREATE_EX, EXCEPTION_CODE_EXIT, EXCEPTION_CODE_MY_PARENT_TID,
+    EXCEPTION_CODE_MY_TID, EXCEPTION_CODE_PRINT, EXCEPTION_CODE_RECEIVE, EXCEPTION_CODE_REPLY,
+    EXCEPTION_CODE_SEND, EXCEPTION_CODE_WRITE, EXCEPTION_CODE_YIELD, TASK_NAME_LEN,
+};
+
+/// FP/SIMD state, saved right above the `ExceptionFrame` of tasks that use it
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct FpFrame {
+    pub q: [u128; 32],
+    pub fpcr: u64,
+    pub fpsr: u64,
+}
 
 #[repr(C)]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -49,82 +67,362 @@ pub struct ExceptionFrame {
     pub _elr_dup: u64,
 }
 
-unsafe fn kcreate(task: &mut Task) -> i8 {
+impl ExceptionFrame {
+    /// x0 to x30
+    pub fn registers(&self) -> &[u64; 31] {
+        // repr(C) lays x0..x30 out back to back at the start of the frame
+        unsafe { &*(self as *const Self).cast::<[u64; 31]>() }
+    }
+
+    /// Hand a syscall's result back in x0, as the value or the negated error
+    pub fn set_return(&mut self, ret: Result<u64, SyscallError>) {
+        self.x0 = match ret {
+            Ok(value) => value,
+            Err(err) => err.code() as u64,
+        };
+    }
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+
+/// How much of a `Print` or `Write` is copied in at a time
+const PRINT_CHUNK: usize = 64;
+
+/// The leading fields of `TaskOptions`, as plain numbers until they have been checked
+#[repr(C)]
+#[derive(Default)]
+struct RawTaskOptions {
+    priority: u64,
+    func: u64,
+    stack_size: u64,
+    arg: u64,
+}
+
+/// x0: priority, x1: function
+unsafe fn kcreate(task: &mut Task) -> SyscallResult {
     let trap_frame = &*task.trap_frame.unwrap();
-    CPU_GLOBAL.scheduler.create(
+    uaccess::check_entry(trap_frame.x1)?;
+    let tid = CPU_GLOBAL.scheduler.create(
         trap_frame.x0 as usize,
         Some(task.id),
         core::mem::transmute(trap_frame.x1),
-    )
+    )?;
+    Ok(tid as u64)
 }
 
-unsafe fn kmy_tid(task: &mut Task) -> i8 {
-    task.id as i8
+/// x0: options, x1/x2: name
+unsafe fn kcreate_ex(task: &mut Task) -> SyscallResult {
+    let trap_frame = &*task.trap_frame.unwrap();
+
+    let mut raw = RawTaskOptions::default();
+    uaccess::copy_from_user(
+        task,
+        core::slice::from_raw_parts_mut(
+            &mut raw as *mut RawTaskOptions as *mut u8,
+            size_of::<RawTaskOptions>(),
+        ),
+        trap_frame.x0,
+    )?;
+    uaccess::check_entry(raw.func)?;
+
+    // names are cut short anyway, so only copy what will be kept
+    let mut name = [0u8; TASK_NAME_LEN];
+    let name_len = (trap_frame.x2 as usize).min(TASK_NAME_LEN);
+    uaccess::copy_from_user(task, &mut name[..name_len], trap_frame.x1)?;
+    let name = match core::str::from_utf8(&name[..name_len]) {
+        Ok(name) => name,
+        Err(err) => core::str::from_utf8_unchecked(&name[..err.valid_up_to()]),
+    };
+
+    let options = TaskOptions {
+        priority: raw.priority as usize,
+        func: core::mem::transmute::<u64, fn(usize) -> !>(raw.func),
+        stack_size: raw.stack_size as usize,
+        arg: raw.arg as usize,
+        name,
+    };
+    let tid = CPU_GLOBAL.scheduler.create_ex(Some(task.id), &options)?;
+    Ok(tid as u64)
 }
 
-unsafe fn kmy_parent_tid(task: &mut Task) -> i8 {
-    if let Some(parent) = task.parent {
-        parent as i8
+unsafe fn kmy_tid(task: &mut Task) -> SyscallResult {
+    Ok(task.id as u64)
+}
+
+unsafe fn kmy_parent_tid(task: &mut Task) -> SyscallResult {
+    task.parent
+        .map(|parent| parent as u64)
+        .ok_or(SyscallError::NoSuchTask)
+}
+
+unsafe fn kyield(task: &mut Task) -> SyscallResult {
+    Ok(0)
+}
+
+/// Copy as much of `src` into `dst` as fits, returning the length or `Truncated`. Both buffers
+/// must have been checked against their owners when they were handed to the kernel.
+unsafe fn copy_message(src: u64, src_len: u64, dst: u64, dst_len: u64) -> SyscallResult {
+    let len = src_len.min(dst_len) as usize;
+    uaccess::copy(dst, src, len)?;
+    if src_len > dst_len {
+        Err(SyscallError::Truncated)
     } else {
-        -1
+        Ok(len as u64)
     }
 }
 
-unsafe fn kyield(task: &mut Task) -> i8 {
-    0
+/// Hand the sender's message to a receiver blocked in `Receive`, setting the receiver's return
+/// values: the message length in x0 and the sender in x1.
+unsafe fn deliver(sender: &Task, receiver: &Task) -> SyscallResult {
+    let sender_frame = &*sender.trap_frame.unwrap();
+    let receiver_frame = &mut *receiver.trap_frame.unwrap();
+    let ret = copy_message(
+        sender_frame.x1,
+        sender_frame.x2,
+        receiver_frame.x0,
+        receiver_frame.x1,
+    );
+    receiver_frame.set_return(ret);
+    receiver_frame.x1 = sender.id as u64;
+    ret
 }
 
-unsafe fn kexit(task: &mut Task) -> i8 {
+/// x0: receiver tid, x1/x2: message, x3/x4: reply buffer
+unsafe fn ksend(task: &mut Task) -> SyscallResult {
+    let trap_frame = &*task.trap_frame.unwrap();
+    uaccess::check(task, trap_frame.x1, trap_frame.x2, Access::Read)?;
+    uaccess::check(task, trap_frame.x3, trap_frame.x4, Access::Write)?;
+
+    let receiver = trap_frame.x0 as Tid;
+    if receiver == task.id {
+        return Err(SyscallError::InvalidTid);
+    }
+    CPU_GLOBAL.scheduler.validate(receiver)?;
+
+    task.partner = Some(receiver);
+    let delivered = CPU_GLOBAL.scheduler.with_task(receiver, |receiver_task| {
+        if receiver_task.run_state == TaskRunState::ReceiveBlocked {
+            deliver(task, receiver_task);
+            true
+        } else {
+            false
+        }
+    });
+
+    if delivered == Some(true) {
+        CPU_GLOBAL.scheduler.unblock(receiver);
+        task.run_state = TaskRunState::ReplyBlocked;
+    } else if let Err(err) = CPU_GLOBAL.scheduler.enqueue_sender(receiver, task.id) {
+        task.partner = None;
+        return Err(err);
+    } else {
+        task.run_state = TaskRunState::SendBlocked;
+    }
+
+    // the return value is filled in by Reply
+    Ok(0)
+}
+
+/// x0/x1: message buffer
+unsafe fn kreceive(task: &mut Task) -> SyscallResult {
+    let trap_frame = &*task.trap_frame.unwrap();
+    uaccess::check(task, trap_frame.x0, trap_frame.x1, Access::Write)?;
+
+    if let Some(sender) = CPU_GLOBAL.scheduler.dequeue_sender(task.id) {
+        CPU_GLOBAL
+            .scheduler
+            .with_task(sender, |sender_task| {
+                sender_task.run_state = TaskRunState::ReplyBlocked;
+                deliver(sender_task, task)
+            })
+            .unwrap()
+    } else {
+        task.run_state = TaskRunState::ReceiveBlocked;
+        // the return values are filled in by Send
+        Ok(0)
+    }
+}
+
+/// x0: tid of the reply blocked sender, x1/x2: reply
+unsafe fn kreply(task: &mut Task) -> SyscallResult {
+    let trap_frame = &*task.trap_frame.unwrap();
+    let sender = trap_frame.x0 as Tid;
+    uaccess::check(task, trap_frame.x1, trap_frame.x2, Access::Read)?;
+    CPU_GLOBAL.scheduler.validate(sender)?;
+
+    let copied = CPU_GLOBAL.scheduler.with_task(sender, |sender_task| {
+        if sender_task.run_state != TaskRunState::ReplyBlocked
+            || sender_task.partner != Some(task.id)
+        {
+            return None;
+        }
+
+        let sender_frame = &mut *sender_task.trap_frame.unwrap();
+        let ret = copy_message(
+            trap_frame.x1,
+            trap_frame.x2,
+            sender_frame.x3,
+            sender_frame.x4,
+        );
+        sender_frame.set_return(ret);
+        Some(ret)
+    });
+
+    let Some(ret) = copied.flatten() else {
+        return Err(SyscallError::NotReplyBlocked);
+    };
+    CPU_GLOBAL.scheduler.unblock(sender);
+    ret.map(|_| 0)
+}
+
+/// x0: event to wait for
+unsafe fn kawait_event(task: &mut Task) -> SyscallResult {
+    let event = (&*task.trap_frame.unwrap()).x0;
+    if event as usize >= EVENT_COUNT {
+        return Err(SyscallError::InvalidEvent);
+    }
+
+    if let Some(data) = interrupt::arm(event) {
+        return Ok(data);
+    }
+
+    CPU_GLOBAL.scheduler.wait_event(event as usize, task.id)?;
+
+    task.run_state = TaskRunState::EventBlocked;
+    // the return value is filled in when the interrupt arrives
+    Ok(0)
+}
+
+/// x0/x1: message
+///
+/// Only takes what the kernel log has room for, returning how much that was.
+unsafe fn kprint(task: &mut Task) -> SyscallResult {
+    let trap_frame = &*task.trap_frame.unwrap();
+    uaccess::check(task, trap_frame.x0, trap_frame.x1, Access::Read)?;
+
+    let mut buffer = [0u8; PRINT_CHUNK];
+    let mut addr = trap_frame.x0;
+    let mut left = trap_frame.x1 as usize;
+    let mut printed = 0;
+    while left > 0 {
+        let chunk = &mut buffer[..left.min(PRINT_CHUNK)];
+        uaccess::copy_from_user(task, chunk, addr)?;
+        let pushed = TERM_GLOBAL.lock().put_slice_flush(chunk);
+        printed += pushed;
+        if pushed < chunk.len() {
+            break;
+        }
+        addr += chunk.len() as u64;
+        left -= chunk.len();
+    }
+    Ok(printed as u64)
+}
+
+/// x0: line, x1/x2: bytes
+///
+/// Only takes what the FIFO has room for, the caller waits on the line's TX event for more.
+unsafe fn kwrite(task: &mut Task) -> SyscallResult {
+    let trap_frame = &*task.trap_frame.unwrap();
+    let line = UARTLine::from_line(trap_frame.x0).ok_or(SyscallError::InvalidLine)?;
+    uaccess::check(task, trap_frame.x1, trap_frame.x2, Access::Read)?;
+
+    with_serial(line, |serial| {
+        let mut buffer = [0u8; PRINT_CHUNK];
+        let mut addr = trap_frame.x1;
+        let mut left = trap_frame.x2 as usize;
+        let mut written = 0;
+        while left > 0 && !serial.txwaiting() {
+            let chunk = &mut buffer[..left.min(PRINT_CHUNK)];
+            uaccess::copy_from_user(task, chunk, addr)?;
+            let pushed = serial.put_slice_nowait(chunk);
+            written += pushed;
+            addr += pushed as u64;
+            left -= pushed;
+        }
+        Ok(written as u64)
+    })
+}
+
+/// Mark the task exited and fail anyone still talking to it, since they will never get an answer
+unsafe fn release(task: &mut Task) {
+    task.run_state = TaskRunState::Exited;
+
+    for partner in CPU_GLOBAL.scheduler.take_partners(task.id) {
+        CPU_GLOBAL.scheduler.with_task(partner, |partner_task| {
+            (*partner_task.trap_frame.unwrap()).set_return(Err(SyscallError::NoSuchTask));
+        });
+        CPU_GLOBAL.scheduler.unblock(partner);
+    }
+}
+
+unsafe fn kexit(task: &mut Task) -> SyscallResult {
     extern "C" {
         fn __switch_to_scheduler(old_context: *mut Context, new_context: *mut Context) -> !;
     }
 
-    task.run_state = TaskRunState::Exited;
+    release(task);
 
     let mut cpu_context = CPU_GLOBAL.context.lock();
     let cpu_context_ptr = &raw mut *cpu_context as *mut Context;
     core::mem::drop(cpu_context);
 
     // this is locked in syscall
-    CPU_GLOBAL.scheduler.active_task.force_unlock();
+    CPU_GLOBAL.scheduler.force_unlock_active();
     __switch_to_scheduler(
         task.context.as_mut().unwrap() as *mut Context,
         cpu_context_ptr,
     );
-    0
+    Ok(0)
 }
 
 #[no_mangle]
 pub extern "C" fn get_kernel_sp() -> u64 {
-    let active_task = CPU_GLOBAL.scheduler.active_task.lock();
+    // still on the task's stack, which the kernel's address space maps too
+    unsafe {
+        mmu::switch_to_kernel();
+    }
+    let active_task = CPU_GLOBAL.scheduler.active();
     let ret = active_task.as_ref().unwrap().kernel_sp;
     core::mem::drop(active_task);
     ret
 }
 
-/// Look up which syscall to excute and excute it
-#[no_mangle]
-pub unsafe extern "C" fn syscall(exception_frame: *mut ExceptionFrame) -> ! {
+/// The task used FP/SIMD for the first time.
+///
+/// The kernel itself is built with FP/SIMD, so the registers can't stay live across kernel entries
+/// and every trap from a task that uses them saves them. Tasks start with them trapped so that
+/// integer-only tasks never pay for it. Make room for a zeroed `FpFrame` under the task's frame,
+/// then let it retry the instruction with FP/SIMD enabled.
+unsafe fn kfp_first_use(task: &mut Task) {
+    let frame = task.trap_frame.unwrap();
+    let moved = frame
+        .cast::<u8>()
+        .sub(core::mem::size_of::<FpFrame>())
+        .cast::<ExceptionFrame>();
+    core::ptr::copy(frame, moved, 1);
+    moved.add(1).cast::<FpFrame>().write(FpFrame::default());
+
+    task.trap_frame = Some(moved);
+    task.uses_fp = true;
+}
+
+/// Kill the task instead of the kernel, leaving everyone else running
+pub unsafe fn kill(task: &mut Task, fault: Fault) {
+    task.fault = Some(fault);
+    release(task);
+}
+
+unsafe fn kfault(task: &mut Task, fault: Fault) {
+    fault::report(task, fault);
+    kill(task, fault);
+}
+
+/// Save the active task's kernel context and go back to the scheduler loop
+unsafe fn return_to_scheduler(mut task: SpinlockGuard<Option<Task>>) -> ! {
     extern "C" {
         fn __switch_to_scheduler(old_context: *mut Context, new_context: *mut Context) -> !;
     }
 
-    let mut task = CPU_GLOBAL.scheduler.active_task.lock();
-    let exception_num = ESR_EL1.read(ESR_EL1::ISS);
-    let task_ref = task.as_mut().unwrap();
-    task_ref.trap_frame = Some(exception_frame);
-    let ret = match exception_num {
-        EXCEPTION_CODE_MY_TID => kmy_tid(task_ref),
-        EXCEPTION_CODE_CREATE => kcreate(task_ref),
-        EXCEPTION_CODE_MY_PARENT_TID => kmy_parent_tid(task_ref),
-        EXCEPTION_CODE_EXIT => kexit(task_ref),
-        EXCEPTION_CODE_YIELD => kyield(task_ref),
-        _ => todo!(),
-    };
-
-    let frame = &mut *exception_frame;
-    frame.x0 = ret as u64;
-
     let task_context = task.as_mut().unwrap().context.as_mut().unwrap() as *mut Context;
     let mut cpu_context = CPU_GLOBAL.context.lock();
     let cpu_context_ptr = &mut *cpu_context as *mut Context;
@@ -135,4 +433,117 @@ pub unsafe extern "C" fn syscall(exception_frame: *mut ExceptionFrame) -> ! {
     __switch_to_scheduler(task_context, cpu_context_ptr);
 }
 
+/// Look up which syscall to excute and excute it
+unsafe fn ksvc(task: &mut Task) {
+    let exception_num = ESR_EL1.read(ESR_EL1::ISS);
+    let ret = match exception_num {
+        EXCEPTION_CODE_MY_TID => kmy_tid(task),
+        EXCEPTION_CODE_CREATE => kcreate(task),
+        EXCEPTION_CODE_MY_PARENT_TID => kmy_parent_tid(task),
+        EXCEPTION_CODE_EXIT => kexit(task),
+        EXCEPTION_CODE_YIELD => kyield(task),
+        EXCEPTION_CODE_SEND => ksend(task),
+        EXCEPTION_CODE_RECEIVE => kreceive(task),
+        EXCEPTION_CODE_REPLY => kreply(task),
+        EXCEPTION_CODE_AWAIT_EVENT => kawait_event(task),
+        EXCEPTION_CODE_PRINT => kprint(task),
+        EXCEPTION_CODE_CREATE_EX => kcreate_ex(task),
+        EXCEPTION_CODE_WRITE => kwrite(task),
+        _ => return kfault(task, Fault::BadSyscall(exception_num)),
+    };
+
+    // blocked tasks get their return value from whoever unblocks them
+    if !task.is_blocked() {
+        (*task.trap_frame.unwrap()).set_return(ret);
+    }
+}
+
+/// Handle a synchronous exception from EL0: a syscall, the first FP/SIMD instruction, or a fault
+#[no_mangle]
+pub unsafe extern "C" fn syscall(exception_frame: *mut ExceptionFrame) -> ! {
+    let mut task = CPU_GLOBAL.scheduler.active();
+    let task_ref = task.as_mut().unwrap();
+    task_ref.trap_frame = Some(exception_frame);
+
+    match ESR_EL1.read_as_enum(ESR_EL1::EC) {
+        Some(ESR_EL1::EC::Value::SVC64) => ksvc(task_ref),
+        Some(ESR_EL1::EC::Value::TrappedFP) => kfp_first_use(task_ref),
+        _ => kfault(task_ref, Fault::from_esr()),
+    }
+
+    return_to_scheduler(task);
+}
+
+/// Kill the active task over an exception that can't come from a well behaved one
+unsafe fn unexpected(exception_frame: *mut ExceptionFrame, fault: Fault) -> ! {
+    let mut task = CPU_GLOBAL.scheduler.active();
+    let task_ref = task.as_mut().unwrap();
+    task_ref.trap_frame = Some(exception_frame);
+
+    kfault(task_ref, fault);
+    return_to_scheduler(task);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fiq_fault(exception_frame: *mut ExceptionFrame) -> ! {
+    unexpected(exception_frame, Fault::Fiq)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn serror_fault(exception_frame: *mut ExceptionFrame) -> ! {
+    unexpected(exception_frame, Fault::SError)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aarch32_fault(exception_frame: *mut ExceptionFrame) -> ! {
+    unexpected(exception_frame, Fault::AArch32)
+}
+
+/// Handle a fault taken in the kernel itself, on the fault stack. Running into a guard page while
+/// working on the active task's behalf only costs that task, anything else is a kernel bug.
+#[no_mangle]
+pub unsafe extern "C" fn kernel_fault() -> ! {
+    let far = FAR_EL1.get();
+    let fault = Fault::from_esr();
+    let elr = ELR_EL1.get();
+    let esr = ESR_EL1.get();
+    let kernel_bug = || -> ! {
+        panic!(
+            "kernel fault: {:?} at {:#x}, FAR {:#x}, ESR {:#x}",
+            fault, elr, far, esr
+        )
+    };
+
+    // a fault while saving a frame is taken in the task's address space
+    mmu::switch_to_kernel();
+    // the fault may have hit with any of these held, and the code holding them is never resumed
+    CPU_GLOBAL.scheduler.force_unlock_all();
+    force_unlock(&CPU_GLOBAL.context);
+    force_unlock(&TERM_GLOBAL);
+    if CPU_GLOBAL.scheduler.active_index().is_none() {
+        kernel_bug();
+    }
+
+    let mut task = CPU_GLOBAL.scheduler.active();
+    let task_ref = task.as_mut().unwrap();
+    let Some(stack) = task_ref.guarded_stack(far) else {
+        kernel_bug();
+    };
+
+    fault::report_overflow(task_ref, stack);
+    kill(task_ref, Fault::StackOverflow);
+    return_to_scheduler(task);
+}
+
+/// Service an interrupt taken from EL0, then let the scheduler pick who runs next
+#[no_mangle]
+pub unsafe extern "C" fn irq(exception_frame: *mut ExceptionFrame) -> ! {
+    let mut task = CPU_GLOBAL.scheduler.active();
+    task.as_mut().unwrap().trap_frame = Some(exception_frame);
+
+    interrupt::handle();
+
+    return_to_scheduler(task);
+}
+
 // todo: kernel stack needs to be restored
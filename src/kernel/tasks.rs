@@ -1,16 +1,65 @@
 use crate::kernel::boot::el0_setup;
-use crate::kernel::sys_syscall::ExceptionFrame;
-use crate::kernel::utils::Spinlock as Mutex;
+use crate::kernel::fault::{self, Fault};
+use crate::kernel::interrupt;
+use crate::kernel::mmu::{self, AddressSpace};
+use crate::kernel::sys_syscall::{self, ExceptionFrame};
+use crate::kernel::utils::{force_unlock, Spinlock as Mutex, SpinlockGuard};
 use aarch64_cpu as cpu;
+use alloc::collections::VecDeque;
+use core::{fmt, ops::Range};
+use cpu::registers::{Writeable, CPACR_EL1};
 use derive_more::Constructor;
-use heapless::{binary_heap::Max, BinaryHeap};
+use heapless::{String, Vec};
+use lunaris_user::syscall::{SyscallError, TaskOptions, Tid, EVENT_COUNT, TASK_NAME_LEN};
 
-const TASK_SIZE: usize = 50;
-const OUT_OF_DESCRIPTORS: i8 = -2;
-const PER_TASK_KERNEL_STACK_SIZE: u64 = 0x400;
-const USER_STACK_SIZE: u64 = 0x800;
-const KERNEL_STACK_START: u64 = 0x20000;
-const USER_STACK_START: u64 = 0x50000;
+pub const TASK_SIZE: usize = 50;
+/// Priorities run from 0 to 63, higher runs first
+const NUM_PRIORITIES: usize = u64::BITS as usize;
+/// Marks the end of the slot lists threaded through `TaskTable` and `ReadyQueue`
+const END: usize = TASK_SIZE;
+/// The low bits of a TID are the descriptor slot, the rest are the slot's generation
+const TID_INDEX_BITS: u32 = 8;
+const TID_INDEX_MASK: Tid = (1 << TID_INDEX_BITS) - 1;
+const TID_GENERATION_MASK: u32 = Tid::MAX as u32 >> TID_INDEX_BITS;
+/// Stacks are whole pages so each task can be given only its own
+const PAGE_SIZE: u64 = 0x1000;
+const PER_TASK_KERNEL_STACK_SIZE: u64 = 0x1000;
+/// The stack size `Create` gives, `CreateEx` can ask for up to `MAX_USER_STACK_SIZE`
+const USER_STACK_SIZE: u64 = 0x1000;
+const MAX_USER_STACK_SIZE: u64 = 0x8000;
+/// Every stack has a page below it that is never mapped, so running off the end faults
+const GUARD_SIZE: u64 = 0x1000;
+/// Kernel stacks fill the 2MiB block below `KERNEL_STACK_START`, and user stacks the block below
+/// `USER_STACK_START`, both clear of the kernel image
+const KERNEL_STACK_START: u64 = 0xE0_0000;
+const USER_STACK_START: u64 = 0x100_0000;
+/// Both stack blocks, which the frame allocator leaves alone. With the MMU on they only say where
+/// stacks are mapped, each page backed by a frame of its own, and the RAM they cover can't be
+/// given out since the kernel could no longer reach it. Without the MMU they are the stacks.
+pub const STACK_BLOCKS: Range<u64> = KERNEL_STACK_START - 0x20_0000..USER_STACK_START;
+/// The most frames a task's stacks take
+pub const MAX_STACK_PAGES: usize =
+    ((PER_TASK_KERNEL_STACK_SIZE + MAX_USER_STACK_SIZE) / PAGE_SIZE) as usize;
+/// Written to the lowest word of every stack when there are no guard pages to catch an overflow
+const STACK_CANARY: u64 = 0x6C75_6E61_7269_7321;
+
+/// The kernel stack of task slot `index`
+pub fn kernel_stack(index: usize) -> Range<u64> {
+    let top = KERNEL_STACK_START - (index as u64 + 1) * (PER_TASK_KERNEL_STACK_SIZE + GUARD_SIZE);
+    top - PER_TASK_KERNEL_STACK_SIZE..top
+}
+
+/// The room task slot `index` has for its user stack, which grows down from the top
+pub fn user_stack_region(index: usize) -> Range<u64> {
+    let top = USER_STACK_START - (index as u64 + 1) * (MAX_USER_STACK_SIZE + GUARD_SIZE);
+    top - MAX_USER_STACK_SIZE..top
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind {
+    Kernel,
+    User,
+}
 
 #[derive(Eq, PartialEq, PartialOrd, Ord, Debug)]
 pub enum TaskRunState {
@@ -61,37 +110,217 @@ impl Context {
     }
 }
 
-#[derive(Eq, Constructor, Debug)]
+#[derive(Constructor, Debug)]
 pub struct Task {
-    pub id: u8,
+    pub id: Tid,
     pub priority: usize,
-    cnt: usize,
-    pub parent: Option<u8>,
+    pub parent: Option<Tid>,
     pub run_state: TaskRunState,
+    /// Set when the task was killed for faulting rather than calling Exit
+    pub fault: Option<Fault>,
+    /// The task this one is sending to or waiting on a reply from
+    pub partner: Option<Tid>,
     pub trap_frame: Option<*mut ExceptionFrame>,
+    /// Whether FP/SIMD is enabled for the task, which also means its frames carry an `FpFrame`
+    pub uses_fp: bool,
     pub context: Option<Context>,
     pub kernel_sp: u64,
     pub starting_sp: u64,
-    pub fn_ptr: fn() -> !,
+    pub stack_size: u64,
+    /// The task's own tables, `None` when the MMU is off
+    pub address_space: Option<AddressSpace>,
+    pub fn_ptr: fn(usize) -> !,
+    /// Handed to `fn_ptr` in x0
+    pub arg: usize,
+    pub name: String<TASK_NAME_LEN>,
 }
 
-impl Ord for Task {
-    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
-        self.priority
-            .cmp(&other.priority)
-            .then_with(|| self.cnt.cmp(&other.cnt))
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "{}", self.id)
+        } else {
+            write!(f, "{} ({})", self.id, self.name)
+        }
     }
 }
 
-impl PartialOrd for Task {
-    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
-        Some(self.cmp(other))
+impl Task {
+    pub fn kernel_stack(&self) -> Range<u64> {
+        self.kernel_sp - PER_TASK_KERNEL_STACK_SIZE..self.kernel_sp
+    }
+
+    pub fn user_stack(&self) -> Range<u64> {
+        self.starting_sp - self.stack_size..self.starting_sp
+    }
+
+    /// Find the stack whose guard holds `addr`. Below a user stack, that is everything down past the
+    /// bottom of the slot's region.
+    pub fn guarded_stack(&self, addr: u64) -> Option<StackKind> {
+        let kernel_stack = self.kernel_stack();
+        let user_stack = self.user_stack();
+        if (kernel_stack.start - GUARD_SIZE..kernel_stack.start).contains(&addr) {
+            Some(StackKind::Kernel)
+        } else if (self.starting_sp - MAX_USER_STACK_SIZE - GUARD_SIZE..user_stack.start)
+            .contains(&addr)
+        {
+            Some(StackKind::User)
+        } else {
+            None
+        }
+    }
+
+    /// Find a stack whose canary has been overwritten.
+    unsafe fn overflowed_stack(&self) -> Option<StackKind> {
+        if *(self.kernel_stack().start as *const u64) != STACK_CANARY {
+            Some(StackKind::Kernel)
+        } else if *(self.user_stack().start as *const u64) != STACK_CANARY {
+            Some(StackKind::User)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        matches!(
+            self.run_state,
+            TaskRunState::SendBlocked
+                | TaskRunState::ReceiveBlocked
+                | TaskRunState::ReplyBlocked
+                | TaskRunState::EventBlocked
+        )
     }
 }
 
-impl PartialEq for Task {
-    fn eq(&self, other: &Self) -> bool {
-        self.priority == other.priority
+/// Hands out descriptor slots, and with them TIDs and stacks.
+///
+/// A slot's generation is bumped whenever it is freed, so the TID of an exited task never
+/// names whoever gets the slot next.
+struct TaskTable {
+    generations: [u32; TASK_SIZE],
+    live: [bool; TASK_SIZE],
+    /// Free slots form a FIFO linked through `next_free`, so a freed slot is reused as late as
+    /// possible.
+    next_free: [usize; TASK_SIZE],
+    free_head: usize,
+    free_tail: usize,
+    num_live: u64,
+}
+
+impl TaskTable {
+    const fn new() -> Self {
+        let mut next_free = [0; TASK_SIZE];
+        let mut i = 0;
+        while i < TASK_SIZE {
+            next_free[i] = i + 1;
+            i += 1;
+        }
+
+        Self {
+            generations: [0; TASK_SIZE],
+            live: [false; TASK_SIZE],
+            next_free,
+            free_head: 0,
+            free_tail: END - 1,
+            num_live: 0,
+        }
+    }
+
+    fn tid(&self, index: usize) -> Tid {
+        ((self.generations[index] << TID_INDEX_BITS) | index as u32) as Tid
+    }
+
+    /// Take the oldest free slot, returning its index and the new task's TID
+    fn alloc(&mut self) -> Option<(usize, Tid)> {
+        if self.free_head == END {
+            return None;
+        }
+
+        let index = self.free_head;
+        self.free_head = self.next_free[index];
+        if self.free_head == END {
+            self.free_tail = END;
+        }
+        self.live[index] = true;
+        self.num_live += 1;
+        Some((index, self.tid(index)))
+    }
+
+    fn free(&mut self, tid: Tid) {
+        let Ok(index) = self.lookup(tid) else {
+            return;
+        };
+
+        self.live[index] = false;
+        self.generations[index] = (self.generations[index] + 1) & TID_GENERATION_MASK;
+        self.num_live -= 1;
+
+        self.next_free[index] = END;
+        if self.free_tail == END {
+            self.free_head = index;
+        } else {
+            self.next_free[self.free_tail] = index;
+        }
+        self.free_tail = index;
+    }
+
+    /// The slot of a live task, `InvalidTid` if `tid` could never name a task, or
+    /// `NoSuchTask` if the task has exited.
+    fn lookup(&self, tid: Tid) -> Result<usize, SyscallError> {
+        let index = (tid & TID_INDEX_MASK) as usize;
+        if tid < 0 || index >= TASK_SIZE {
+            Err(SyscallError::InvalidTid)
+        } else if !self.live[index] || self.tid(index) != tid {
+            Err(SyscallError::NoSuchTask)
+        } else {
+            Ok(index)
+        }
+    }
+}
+
+/// One FIFO of task slots per priority, linked through `next`, plus a bitmap of the non-empty
+/// priorities so the highest ready one is a single `clz` away.
+struct ReadyQueue {
+    bitmap: u64,
+    heads: [usize; NUM_PRIORITIES],
+    tails: [usize; NUM_PRIORITIES],
+    next: [usize; TASK_SIZE],
+}
+
+impl ReadyQueue {
+    const fn new() -> Self {
+        Self {
+            bitmap: 0,
+            heads: [END; NUM_PRIORITIES],
+            tails: [END; NUM_PRIORITIES],
+            next: [END; TASK_SIZE],
+        }
+    }
+
+    fn push(&mut self, index: usize, priority: usize) {
+        self.next[index] = END;
+        if self.heads[priority] == END {
+            self.heads[priority] = index;
+        } else {
+            self.next[self.tails[priority]] = index;
+        }
+        self.tails[priority] = index;
+        self.bitmap |= 1 << priority;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.bitmap == 0 {
+            return None;
+        }
+
+        let priority = (u64::BITS - 1 - self.bitmap.leading_zeros()) as usize;
+        let index = self.heads[priority];
+        self.heads[priority] = self.next[index];
+        if self.heads[priority] == END {
+            self.tails[priority] = END;
+            self.bitmap &= !(1 << priority);
+        }
+        Some(index)
     }
 }
 
@@ -115,112 +344,332 @@ unsafe impl Sync for CPU {}
 pub static CPU_GLOBAL: CPU = CPU::init();
 
 pub struct Scheduler {
-    pub active_task: Mutex<Option<Task>>,
-    ready_queue: Mutex<BinaryHeap<Task, Max, TASK_SIZE>>,
-    cnt: usize,
-    num_tasks: Mutex<u64>,
+    /// Slot of the running task
+    pub active_task: Mutex<Option<usize>>,
+    /// Every task lives in its slot from Create until it exits
+    descriptors: [Mutex<Option<Task>>; TASK_SIZE],
+    ready_queue: Mutex<ReadyQueue>,
+    /// Tasks waiting for each receiver to call Receive, in arrival order, by the receiver's slot
+    send_queues: Mutex<[VecDeque<Tid>; TASK_SIZE]>,
+    /// Tasks blocked in AwaitEvent, per event
+    event_waiters: Mutex<[VecDeque<Tid>; EVENT_COUNT]>,
+    tasks: Mutex<TaskTable>,
 }
 
 impl Scheduler {
     pub const fn new() -> Self {
         Scheduler {
             active_task: Mutex::new(None),
-            ready_queue: Mutex::new(BinaryHeap::new()),
-            cnt: usize::max_value(),
-            num_tasks: Mutex::new(0),
+            descriptors: [const { Mutex::new(None) }; TASK_SIZE],
+            ready_queue: Mutex::new(ReadyQueue::new()),
+            send_queues: Mutex::new([const { VecDeque::new() }; TASK_SIZE]),
+            event_waiters: Mutex::new([const { VecDeque::new() }; EVENT_COUNT]),
+            tasks: Mutex::new(TaskTable::new()),
         }
     }
 
     pub fn task_num(&self) -> u64 {
-        *self.num_tasks.lock()
+        self.tasks.lock().num_live
     }
 
-    pub fn create(&self, priority: usize, parent: Option<u8>, fn_ptr: fn() -> !) -> i8 {
-        let mut num = self.num_tasks.lock();
-        *num += 1;
-        let task = Task {
-            id: *num as u8,
-            priority,
-            cnt: self.cnt - 1,
+    pub fn create(
+        &self,
+        priority: usize,
+        parent: Option<Tid>,
+        fn_ptr: fn() -> !,
+    ) -> Result<Tid, SyscallError> {
+        // a task that takes no argument just never looks at x0
+        let func = unsafe { core::mem::transmute::<fn() -> !, fn(usize) -> !>(fn_ptr) };
+        self.create_ex(
+            parent,
+            &TaskOptions {
+                priority,
+                func,
+                stack_size: USER_STACK_SIZE as usize,
+                arg: 0,
+                name: "",
+            },
+        )
+    }
+
+    pub fn create_ex(
+        &self,
+        parent: Option<Tid>,
+        options: &TaskOptions,
+    ) -> Result<Tid, SyscallError> {
+        if options.priority >= NUM_PRIORITIES {
+            return Err(SyscallError::InvalidPriority);
+        }
+
+        let stack_size = (options.stack_size as u64).next_multiple_of(PAGE_SIZE);
+        if stack_size == 0 || stack_size > MAX_USER_STACK_SIZE {
+            return Err(SyscallError::InvalidStackSize);
+        }
+
+        let Some((index, id)) = self.tasks.lock().alloc() else {
+            return Err(SyscallError::OutOfDescriptors);
+        };
+
+        // where the stacks go belongs to the slot, and with the MMU on what backs them belongs to
+        // the address space, so both are handed back when the task exits
+        let kernel_stack = kernel_stack(index);
+        let top = user_stack_region(index).end;
+        let user_stack = top - stack_size..top;
+        if !cfg!(feature = "mmu") {
+            unsafe {
+                (kernel_stack.start as *mut u64).write(STACK_CANARY);
+                (user_stack.start as *mut u64).write(STACK_CANARY);
+            }
+        }
+
+        let address_space = if cfg!(feature = "mmu") {
+            let Some(space) =
+                (unsafe { mmu::map_task(index, user_stack.clone(), kernel_stack.clone()) })
+            else {
+                self.tasks.lock().free(id);
+                return Err(SyscallError::OutOfMemory);
+            };
+            Some(space)
+        } else {
+            None
+        };
+
+        let mut name = String::new();
+        for ch in options.name.chars() {
+            if name.push(ch).is_err() {
+                break;
+            }
+        }
+
+        *self.descriptors[index].lock() = Some(Task {
+            id,
+            priority: options.priority,
             parent,
             run_state: TaskRunState::Ready,
+            fault: None,
+            partner: None,
             trap_frame: None,
+            uses_fp: false,
             context: None,
-            kernel_sp: KERNEL_STACK_START - *num * PER_TASK_KERNEL_STACK_SIZE,
-            starting_sp: USER_STACK_START - *num * USER_STACK_SIZE,
-            fn_ptr,
+            kernel_sp: kernel_stack.end,
+            starting_sp: user_stack.end,
+            stack_size,
+            address_space,
+            fn_ptr: options.func,
+            arg: options.arg,
+            name,
+        });
+        self.ready_queue.lock().push(index, options.priority);
+        Ok(id)
+    }
+
+    /// Lock the running task's descriptor.
+    pub fn active(&self) -> SpinlockGuard<'_, Option<Task>> {
+        let index = self.active_task.lock().unwrap();
+        self.descriptors[index].lock()
+    }
+
+    /// The slot of the running task, if any.
+    pub fn active_index(&self) -> Option<usize> {
+        *self.active_task.lock()
+    }
+
+    /// Release the running task's descriptor when its guard will never be dropped.
+    pub unsafe fn force_unlock_active(&self) {
+        let index = self.active_task.lock().unwrap();
+        self.descriptors[index].force_unlock();
+    }
+
+    /// Release every lock the scheduler has, for when the kernel faulted while holding some and
+    /// the code holding them will never run again. There is one core and interrupts are masked
+    /// in the kernel, so nobody else can be holding them.
+    pub unsafe fn force_unlock_all(&self) {
+        force_unlock(&self.active_task);
+        for descriptor in &self.descriptors {
+            force_unlock(descriptor);
+        }
+        force_unlock(&self.ready_queue);
+        force_unlock(&self.send_queues);
+        force_unlock(&self.event_waiters);
+        force_unlock(&self.tasks);
+    }
+
+    /// Check that `id` names a live task, returning `InvalidTid` or `NoSuchTask` otherwise.
+    pub fn validate(&self, id: Tid) -> Result<(), SyscallError> {
+        self.tasks.lock().lookup(id).map(|_| ())
+    }
+
+    /// Run `f` on a live task. Must not be the active task, whose descriptor is already locked.
+    pub fn with_task<R>(&self, id: Tid, f: impl FnOnce(&mut Task) -> R) -> Option<R> {
+        let index = self.tasks.lock().lookup(id).ok()?;
+        self.descriptors[index].lock().as_mut().map(f)
+    }
+
+    /// Move a blocked task back onto the ready queue.
+    pub fn unblock(&self, id: Tid) {
+        let Ok(index) = self.tasks.lock().lookup(id) else {
+            return;
         };
 
-        if self.push(task).is_ok() {
-            *num as i8
-        } else {
-            OUT_OF_DESCRIPTORS
+        let mut descriptor = self.descriptors[index].lock();
+        let task = descriptor.as_mut().unwrap();
+        task.run_state = TaskRunState::Ready;
+        task.partner = None;
+        let priority = task.priority;
+        core::mem::drop(descriptor);
+
+        self.ready_queue.lock().push(index, priority);
+    }
+
+    /// Queue `sender` behind any other tasks already sending to `receiver`.
+    pub fn enqueue_sender(&self, receiver: Tid, sender: Tid) -> Result<(), SyscallError> {
+        let index = self.tasks.lock().lookup(receiver)?;
+        let mut send_queues = self.send_queues.lock();
+        let queue = &mut send_queues[index];
+        queue
+            .try_reserve(1)
+            .map_err(|_| SyscallError::OutOfMemory)?;
+        queue.push_back(sender);
+        Ok(())
+    }
+
+    /// Take the longest waiting sender to `receiver`.
+    pub fn dequeue_sender(&self, receiver: Tid) -> Option<Tid> {
+        let index = self.tasks.lock().lookup(receiver).ok()?;
+        self.send_queues.lock()[index].pop_front()
+    }
+
+    /// Forget `id`'s send queue and collect every blocked task sending to or awaiting a reply from it.
+    ///
+    /// `id` must be the active task.
+    pub fn take_partners(&self, id: Tid) -> Vec<Tid, TASK_SIZE> {
+        if let Ok(index) = self.tasks.lock().lookup(id) {
+            // replaced rather than cleared, so its memory goes back to the heap
+            self.send_queues.lock()[index] = VecDeque::new();
         }
+
+        let active = self.active_task.lock().unwrap();
+        self.descriptors
+            .iter()
+            .enumerate()
+            // the active task's descriptor is already locked
+            .filter(|(index, _)| *index != active)
+            .filter_map(|(_, descriptor)| {
+                descriptor
+                    .lock()
+                    .as_ref()
+                    .filter(|task| task.partner == Some(id))
+                    .map(|task| task.id)
+            })
+            .collect()
+    }
+
+    pub fn wait_event(&self, event: usize, id: Tid) -> Result<(), SyscallError> {
+        let mut event_waiters = self.event_waiters.lock();
+        event_waiters[event]
+            .try_reserve(1)
+            .map_err(|_| SyscallError::OutOfMemory)?;
+        event_waiters[event].push_back(id);
+        Ok(())
     }
 
-    pub fn push(&self, mut task: Task) -> Result<(), Task> {
-        task.cnt -= 1;
-        self.ready_queue.lock().push(task)
+    /// Wake the longest waiting task blocked on `event`, handing it `data`.
+    pub unsafe fn deliver_event(&self, event: u64, data: u64) {
+        let waiter = self.event_waiters.lock()[event as usize].pop_front();
+        if let Some(id) = waiter {
+            self.with_task(id, |task| {
+                (*task.trap_frame.unwrap()).set_return(Ok(data));
+            });
+            self.unblock(id);
+        }
     }
 
-    /// Check the priority of the current running task and the task to be scheduled.
-    pub fn schedule(&self) -> Option<Task> {
+    /// Pick the slot of the longest waiting task at the highest ready priority.
+    pub fn schedule(&self) -> Option<usize> {
         self.ready_queue.lock().pop()
     }
 
-    pub unsafe fn activate(&self, mut task: Task) {
+    pub unsafe fn activate(&self, index: usize) {
         extern "C" {
             fn __syscall_ret() -> !;
-            fn __switch_to_task(old_context: *mut Context, new_context: *mut Context);
+            fn __switch_to_task(old_context: *mut Context, new_context: *mut Context, arg: usize);
         }
 
-        if task.run_state == TaskRunState::Exited {
-            return;
+        let mut descriptor = self.descriptors[index].lock();
+        let task = descriptor.as_mut().unwrap();
+        task.run_state = TaskRunState::Active;
+        *self.active_task.lock() = Some(index);
+
+        // without guard pages an overflow is only noticed once the task is next picked to run
+        if !cfg!(feature = "mmu") {
+            if let Some(stack) = task.overflowed_stack() {
+                fault::report_overflow(task, stack);
+                sys_syscall::kill(task, Fault::StackOverflow);
+                core::mem::drop(descriptor);
+                self.reschedule();
+                return;
+            }
+        }
+        CPACR_EL1.write(if task.uses_fp {
+            CPACR_EL1::FPEN::TrapNothing
+        } else {
+            CPACR_EL1::FPEN::TrapEl0
+        });
+        if let Some(space) = &task.address_space {
+            mmu::switch_to(space.ttbr0());
         }
 
         // if there is trap fram then resume execution
-        if task.trap_frame.is_some() {
-            let frame_ptr = task.trap_frame.unwrap();
+        if let Some(frame_ptr) = task.trap_frame {
             let frame = &*frame_ptr;
             el0_setup(frame.elr, frame_ptr as u64);
-            let mut active_task = self.active_task.lock();
-            *active_task = Some(task);
-            core::mem::drop(active_task);
+            core::mem::drop(descriptor);
             __syscall_ret();
         }
 
         // setup sp when returning
-        let task_starting_sp = task.starting_sp;
-        el0_setup(task.fn_ptr as u64, task_starting_sp);
-        if task.context.is_none() {
-            let context = Context::new();
-            task.context = Some(context);
-        }
-        let mut active_task = self.active_task.lock();
-        *active_task = Some(task);
-        let active_task_context =
-            active_task.as_mut().unwrap().context.as_mut().unwrap() as *mut Context;
+        el0_setup(task.fn_ptr as u64, task.starting_sp);
+        let arg = task.arg;
+        let active_task_context = task.context.insert(Context::new()) as *mut Context;
         let mut cpu_context = CPU_GLOBAL.context.lock();
         let cpu_context_ptr = &mut *cpu_context as *mut Context;
-        core::mem::drop(active_task);
+        core::mem::drop(descriptor);
         core::mem::drop(cpu_context);
-        __switch_to_task(cpu_context_ptr, active_task_context);
+        __switch_to_task(cpu_context_ptr, active_task_context, arg);
         self.reschedule();
     }
 
     pub fn reschedule(&self) {
-        let task = self.active_task.lock().take();
-        if let Some(task) = task {
-            self.push(task).unwrap();
+        let Some(index) = self.active_task.lock().take() else {
+            return;
+        };
+
+        let mut descriptor = self.descriptors[index].lock();
+        let task = descriptor.as_mut().unwrap();
+        match task.run_state {
+            TaskRunState::Exited => {
+                self.tasks.lock().free(task.id);
+                *descriptor = None;
+            }
+            TaskRunState::Active | TaskRunState::Ready => {
+                task.run_state = TaskRunState::Ready;
+                let priority = task.priority;
+                core::mem::drop(descriptor);
+                self.ready_queue.lock().push(index, priority);
+            }
+            // blocked tasks stay in their slot until someone unblocks them
+            _ => {}
         }
     }
 
     pub unsafe fn run(&self) {
         loop {
-            if let Some(task) = self.schedule() {
-                self.activate(task);
+            if let Some(index) = self.schedule() {
+                self.activate(index);
             } else {
-                cpu::asm::wfe(); // wait for event
+                // interrupts stay masked in the kernel, so service them by hand
+                cpu::asm::wfi();
+                interrupt::handle();
             }
         }
     }
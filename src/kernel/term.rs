@@ -1,7 +1,9 @@
-use core::{cell::RefCell, fmt, ops::Deref, panic::PanicInfo};
+use core::fmt;
 
-use crate::kernel::setup::UART;
-use heapless::String;
+use crate::kernel::setup::{Serial, UARTLine, UART};
+use crate::kernel::utils::Spinlock;
+use aarch64_cpu::registers::{Readable, ESR_EL1};
+use lunaris_user::syscall::EXCEPTION_CODE_PRINT;
 use numtoa::NumToA;
 use once_cell::unsync::Lazy;
 use ringbuf::{
@@ -10,28 +12,12 @@ use ringbuf::{
 };
 
 const BUFFER_SIZE: usize = 2048;
-const BUFFER_FLUSH_SIZE: usize = 256;
-const WINDOW_WIDTH: usize = 140;
-const WINDOW_HEIGHT: usize = 90;
-
-struct Cursor {
-    pub x: usize,
-    pub y: usize,
-    pub on: bool,
-}
-
-impl Cursor {
-    pub fn new(x: usize, y: usize) -> Self {
-        Self { x, y, on: false }
-    }
-}
+/// The immediate of an SVC, which is all ESR_EL1.ISS holds for one
+const SVC_IMMEDIATE_MASK: u64 = 0xffff;
 
 pub struct Term {
     uart: UART,
     buffer: ringbuf::StaticRb<u8, BUFFER_SIZE>,
-    width: usize,
-    height: usize,
-    cursor: Cursor,
 }
 
 impl Term {
@@ -39,9 +25,6 @@ impl Term {
         let mut term = Self {
             uart: UART::console(),
             buffer: StaticRb::default(),
-            width: WINDOW_WIDTH,
-            height: WINDOW_HEIGHT,
-            cursor: Cursor::new(0, 0),
         };
         term.set_lf();
         term
@@ -51,33 +34,25 @@ impl Term {
         self.put_command(b"[20h");
     }
 
+    /// Wait for the whole buffer to go out. This spins on the FIFO, so it is only for a kernel that
+    /// is going down and has nothing left to schedule.
     pub fn flush_all(&mut self) {
-        self.buffer.pop_iter().for_each(|c| self.uart.putc(c));
+        self.uart.disable_tx_interrupt();
+        while let Some(ch) = self.buffer.try_pop() {
+            while self.uart.txwaiting() {}
+            self.uart.putc_nowait(ch);
+        }
     }
 
-    fn flush(&mut self, len: usize) {
-        self.buffer
-            .pop_iter()
-            .take(len)
-            .for_each(|c| self.uart.putc(c));
-
-        // flush as much as possible
-        while !self.uart.rxwaiting() {
+    /// Move as much of the buffer into the FIFO as fits right now. Whatever is left goes out from
+    /// the transmit interrupt, see `interrupt::handle`.
+    pub fn flush(&mut self) {
+        while !self.buffer.is_empty() && !self.uart.txwaiting() {
             self.uart.putc_nowait(self.buffer.try_pop().unwrap());
         }
-    }
-
-    fn move_cursor(&mut self, x: usize, y: usize) {
-        self.put_escape();
-        let buf: String<10> = (x as u64).try_into().unwrap();
-    }
-
-    fn cursor_pos(&mut self) -> Cursor {
-        todo!()
-    }
-
-    fn draw_at(&mut self, x: usize, y: usize) {
-        todo!()
+        if !self.buffer.is_empty() {
+            self.uart.enable_tx_interrupt();
+        }
     }
 
     #[inline(always)]
@@ -90,32 +65,22 @@ impl Term {
         self.put_slice(command);
     }
 
-    #[inline(always)]
-    fn put_unchecked(&mut self, ch: u8) {
-        self.buffer.try_push(ch).unwrap();
-    }
-
     fn put_ch(&mut self, ch: u8) {
-        if self.buffer.is_full() {
-            self.flush(BUFFER_FLUSH_SIZE);
-            self.put_unchecked(ch);
-        } else if self.buffer.vacant_len() == 1 {
-            self.put_unchecked(ch);
-            self.flush(BUFFER_FLUSH_SIZE);
-        } else {
-            self.put_unchecked(ch);
-        }
-    }
-
-    pub fn put_slice(&mut self, str: &[u8]) {
-        if self.buffer.vacant_len() == str.len() {
-            self.buffer.push_slice(str);
-            self.flush(BUFFER_FLUSH_SIZE);
-        } else {
-            while self.buffer.vacant_len() < str.len() {
-                self.flush(BUFFER_FLUSH_SIZE);
+        self.put_slice(&[ch]);
+    }
+
+    /// Queue as much of `str` for the console as the buffer and FIFO have room for, returning how
+    /// much that was. Nothing here waits on the UART, so the rest is the caller's to retry or drop.
+    pub fn put_slice(&mut self, str: &[u8]) -> usize {
+        let mut pushed = 0;
+        loop {
+            pushed += self.buffer.push_slice(&str[pushed..]);
+            let queued = self.buffer.occupied_len();
+            self.flush();
+            // stop once everything is in, or the FIFO took nothing to make room for more
+            if pushed == str.len() || self.buffer.occupied_len() == queued {
+                return pushed;
             }
-            self.buffer.push_slice(str);
         }
     }
 
@@ -139,34 +104,49 @@ impl Term {
 
     pub fn put_u_dec_flush(&mut self, u: usize) {
         self.put_u_dec(u);
-        self.flush_all();
+        self.flush();
     }
 
     pub fn put_int_flush(&mut self, i: i8) {
         self.put_int(i);
-        self.flush_all();
+        self.flush();
     }
 
-    pub fn put_slice_flush(&mut self, str: &[u8]) {
-        self.put_slice(str);
-        self.flush_all();
+    pub fn put_slice_flush(&mut self, str: &[u8]) -> usize {
+        let pushed = self.put_slice(str);
+        self.flush();
+        pushed
     }
 }
 
-pub struct NullLock<T>(RefCell<T>);
-
-impl<T> Deref for NullLock<T> {
-    type Target = RefCell<T>;
+/// A lock rather than a `RefCell`, so that the fault path can take it back from whoever held it
+/// when the kernel faulted
+pub static TERM_GLOBAL: Spinlock<Lazy<Term>> = Spinlock::new(Lazy::new(Term::init));
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
+/// Set up the console UART now rather than on the first print, since the console server drives
+/// the same line
+pub fn init() {
+    Lazy::force(&TERM_GLOBAL.lock());
 }
 
-unsafe impl<T> Sync for NullLock<T> {}
-unsafe impl<T> Send for NullLock<T> {}
+/// Writes straight into the console FIFO, spinning while it is full. It takes no lock and keeps
+/// no state, so it still works however the kernel went down.
+pub struct RawConsole;
 
-pub static TERM_GLOBAL: NullLock<Lazy<Term>> = NullLock(RefCell::new(Lazy::new(Term::init)));
+impl fmt::Write for RawConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut uart = UART::new(UARTLine::Console);
+        for &ch in s.as_bytes() {
+            while uart.txwaiting() {}
+            uart.putc_nowait(ch);
+            if ch == b'\n' {
+                while uart.txwaiting() {}
+                uart.putc_nowait(b'\r');
+            }
+        }
+        Ok(())
+    }
+}
 
 impl fmt::Write for Term {
     fn write_str(&mut self, s: &str) -> fmt::Result {
@@ -179,7 +159,7 @@ impl fmt::Write for Term {
 macro_rules! print {
     ($($arg:tt)*) => {{
             use core::fmt::Write;
-            let _ = write!($crate::kernel::term::TERM_GLOBAL.borrow_mut(), $($arg)*);
+            let _ = write!($crate::kernel::term::TERM_GLOBAL.lock(), $($arg)*);
     }};
 }
 
@@ -188,19 +168,39 @@ macro_rules! print {
 macro_rules! println {
     ($($arg:tt)*) => {{
             use core::fmt::Write;
-            let _ = writeln!($crate::kernel::term::TERM_GLOBAL.borrow_mut(), $($arg)*);
-            let _ = write!($crate::kernel::term::TERM_GLOBAL.borrow_mut(), "\r");
+            let _ = writeln!($crate::kernel::term::TERM_GLOBAL.lock(), $($arg)*);
+            let _ = write!($crate::kernel::term::TERM_GLOBAL.lock(), "\r");
+    }};
+}
+
+/// Like `println!`, but over `RawConsole` for when the kernel can't be trusted to get a regular
+/// print out
+#[macro_export]
+macro_rules! raw_println {
+    ($($arg:tt)*) => {{
+            use core::fmt::Write;
+            let _ = writeln!($crate::kernel::term::RawConsole, $($arg)*);
     }};
 }
 
-#[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    println!("panicked");
-    println!(
-        "file = {}, line = {}",
-        info.location().unwrap().file(),
-        info.location().unwrap().line()
-    );
+/// A panic in the kernel.
+///
+/// The kernel is linked into one image with lunaris-user, whose panic handler is the only one there
+/// is. From EL1 its `Print` syscall lands in `__kernel_fault_handler`, which hands the message over
+/// here with the registers it was passed in. Nothing is resumed, so it goes straight to the UART.
+#[no_mangle]
+pub unsafe extern "C" fn kernel_panic(msg: *const u8, len: usize) -> ! {
+    // what was queued before goes first, unless the panic hit while it was being queued
+    if let Some(mut term) = TERM_GLOBAL.try_lock() {
+        term.flush_all();
+    }
+    if ESR_EL1.read(ESR_EL1::ISS) & SVC_IMMEDIATE_MASK == EXCEPTION_CODE_PRINT {
+        let msg = core::slice::from_raw_parts(msg, len);
+        let msg = core::str::from_utf8(msg).unwrap_or("panicked");
+        raw_println!("kernel {}", msg.trim_end());
+    } else {
+        raw_println!("kernel panicked");
+    }
 
     loop {
         aarch64_cpu::asm::wfe();
@@ -0,0 +1,96 @@
+use crate::kernel::{
+    sys_syscall::{ExceptionFrame, FpFrame},
+    tasks::Task,
+};
+use core::{cell::UnsafeCell, ops::Range};
+use lunaris_user::syscall::SyscallError;
+
+/// What a syscall is going to do with a user buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// The kernel image's code and read-only data, which user tasks run out of
+fn code() -> Range<u64> {
+    extern "Rust" {
+        static __code_start: UnsafeCell<()>;
+        static __code_end_exclusive: UnsafeCell<()>;
+    }
+
+    unsafe { __code_start.get() as u64..__code_end_exclusive.get() as u64 }
+}
+
+fn within(region: &Range<u64>, start: u64, end: u64) -> bool {
+    region.start <= start && end <= region.end
+}
+
+/// The part of `task`'s stack above where it trapped. Below that sits its saved frame, which the
+/// kernel resumes it from, so no syscall may copy into or out of it.
+fn live_stack(task: &Task) -> Range<u64> {
+    let stack = task.user_stack();
+    let Some(frame) = task.trap_frame else {
+        return stack;
+    };
+
+    // see SAVE_FRAME_AND_CALL: the frame, the FP/SIMD registers if saved, then a scratch slot
+    let mut top = frame as u64 + core::mem::size_of::<ExceptionFrame>() as u64 + 16;
+    if task.uses_fp {
+        top += core::mem::size_of::<FpFrame>() as u64;
+    }
+    top..stack.end
+}
+
+/// Check that the `len` bytes at `addr` are memory `task` may `access`: the live part of its own
+/// stack, or for reads, the code and constants it runs from. An empty buffer is fine wherever it
+/// points.
+pub fn check(task: &Task, addr: u64, len: u64, access: Access) -> Result<(), SyscallError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = addr.checked_add(len).ok_or(SyscallError::BadAddress)?;
+    let allowed = within(&live_stack(task), addr, end)
+        || (access == Access::Read && within(&code(), addr, end));
+    if allowed {
+        Ok(())
+    } else {
+        Err(SyscallError::BadAddress)
+    }
+}
+
+/// Check that `addr` is somewhere a task could start running
+pub fn check_entry(addr: u64) -> Result<(), SyscallError> {
+    if addr.is_multiple_of(4) && code().contains(&addr) {
+        Ok(())
+    } else {
+        Err(SyscallError::BadAddress)
+    }
+}
+
+/// Copy `len` bytes between buffers that have already been checked. A fault partway through comes
+/// back as `BadAddress` rather than taking the kernel down.
+pub unsafe fn copy(dst: u64, src: u64, len: usize) -> Result<(), SyscallError> {
+    extern "C" {
+        fn __copy_user(dst: u64, src: u64, len: usize) -> usize;
+    }
+
+    if __copy_user(dst, src, len) == 0 {
+        Ok(())
+    } else {
+        Err(SyscallError::BadAddress)
+    }
+}
+
+/// Fill `dst` from `task`'s memory at `src`.
+pub unsafe fn copy_from_user(task: &Task, dst: &mut [u8], src: u64) -> Result<(), SyscallError> {
+    check(task, src, dst.len() as u64, Access::Read)?;
+    copy(dst.as_mut_ptr() as u64, src, dst.len())
+}
+
+/// Write `src` into `task`'s memory at `dst`.
+pub unsafe fn copy_to_user(task: &Task, dst: u64, src: &[u8]) -> Result<(), SyscallError> {
+    check(task, dst, src.len() as u64, Access::Write)?;
+    copy(dst, src.as_ptr() as u64, src.len())
+}
@@ -1,3 +1,4 @@
 pub mod util;
+pub use util::force_unlock;
 pub use util::Spinlock;
 pub use util::SpinlockGuard;
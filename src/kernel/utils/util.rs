@@ -31,3 +31,10 @@ unsafe impl RawMutex for RawSpinlock {
 // 3. Export the wrappers. This are the types that your users will actually use.
 pub type Spinlock<T> = lock_api::Mutex<RawSpinlock, T>;
 pub type SpinlockGuard<'a, T> = lock_api::MutexGuard<'a, RawSpinlock, T>;
+
+/// Release `lock` if it is held, for when whoever holds it will never get to.
+pub unsafe fn force_unlock<T>(lock: &Spinlock<T>) {
+    if lock.is_locked() {
+        lock.force_unlock();
+    }
+}
@@ -2,5 +2,8 @@
 #![no_std]
 #![allow(unused)]
 
+extern crate alloc;
+// nothing names the first user task, so link it in by hand
+extern crate lunaris_init;
+
 mod kernel;
-mod user;
@@ -1,3 +0,0 @@
-#![forbid(unsafe_code)]
-
-pub mod main;
@@ -0,0 +1,69 @@
+//! Turns the track descriptions in `tracks/` into the node tables `track` includes. See
+//! `build/track_file.rs` for how they are written.
+
+#[path = "build/track_file.rs"]
+mod track_file;
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use track_file::{parse_track, Graph, Node, SENSORS_PER_BANK};
+
+const TRACKS: [&str; 2] = ["A", "B"];
+
+fn emit_track(out: &mut String, name: &str, graph: &Graph) {
+    writeln!(out, "pub static TRACK_{name}: Track = Track {{").unwrap();
+    writeln!(out, "    name: {name:?},").unwrap();
+    writeln!(out, "    nodes: &[").unwrap();
+    for (node, edges) in graph.nodes.iter().zip(&graph.edges) {
+        let kind = match *node {
+            Node::Sensor(index) => format!(
+                "NodeKind::Sensor(Sensor {{ bank: {}, number: {} }})",
+                index / SENSORS_PER_BANK as usize,
+                index % SENSORS_PER_BANK as usize + 1
+            ),
+            Node::Branch(n) => format!("NodeKind::Branch({n})"),
+            Node::Merge(n) => format!("NodeKind::Merge({n})"),
+            Node::Enter(_) => "NodeKind::Enter".to_string(),
+            Node::Exit(_) => "NodeKind::Exit".to_string(),
+        };
+        let edges: Vec<String> = edges
+            .iter()
+            .map(|edge| match edge {
+                Some(edge) => format!(
+                    "Some(Edge {{ dest: {}, dist: {} }})",
+                    graph.index(edge.dest),
+                    edge.dist
+                ),
+                None => "None".to_string(),
+            })
+            .collect();
+        writeln!(
+            out,
+            "        Node {{ name: {:?}, kind: {kind}, reverse: {}, edges: [{}] }},",
+            node.name(),
+            graph.index(node.reverse()),
+            edges.join(", ")
+        )
+        .unwrap();
+    }
+    writeln!(out, "    ],").unwrap();
+    writeln!(out, "}};").unwrap();
+}
+
+fn main() {
+    let mut out = String::new();
+    let mut max_nodes = 0;
+    for name in TRACKS {
+        let path = format!("tracks/{}.txt", name.to_lowercase());
+        println!("cargo:rerun-if-changed={path}");
+        let graph = parse_track(&path, &fs::read_to_string(&path).unwrap());
+        max_nodes = max_nodes.max(graph.nodes.len());
+        emit_track(&mut out, name, &graph);
+    }
+    writeln!(out, "pub const MAX_NODES: usize = {max_nodes};").unwrap();
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("track_data.rs");
+    fs::write(dest, out).unwrap();
+}
@@ -0,0 +1,358 @@
+//! Reads the track descriptions in `tracks/`.
+//!
+//! Each line of a description is one piece of track, as `<from> <to> <mm>` in one direction of
+//! travel. The other direction is filled in here, so every piece is written once:
+//!
+//! - sensors are named by bank and number, `A1` and `A2` being the two directions over the same
+//!   sensor
+//! - `BR<n>:S` and `BR<n>:C` leave switch n straight or curved, `BR<n>` arrives at its points
+//! - `MR<n>:S` and `MR<n>:C` arrive at switch n from the straight or curved side, `MR<n>` leaves
+//!   through its points
+//! - `EN<n>` leaves dead end n, `EX<n>` arrives at it
+//!
+//! Anything after a `#` is a comment.
+
+use std::collections::BTreeSet;
+
+const SENSOR_BANKS: u8 = 5;
+pub const SENSORS_PER_BANK: u8 = 16;
+const SENSOR_COUNT: usize = (SENSOR_BANKS * SENSORS_PER_BANK) as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Node {
+    Sensor(usize),
+    Branch(u32),
+    Merge(u32),
+    Enter(u32),
+    Exit(u32),
+}
+
+impl Node {
+    pub fn reverse(self) -> Node {
+        match self {
+            Node::Sensor(index) => Node::Sensor(index ^ 1),
+            Node::Branch(n) => Node::Merge(n),
+            Node::Merge(n) => Node::Branch(n),
+            Node::Enter(n) => Node::Exit(n),
+            Node::Exit(n) => Node::Enter(n),
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Node::Sensor(index) => format!(
+                "{}{}",
+                (b'A' + (index / SENSORS_PER_BANK as usize) as u8) as char,
+                index % SENSORS_PER_BANK as usize + 1
+            ),
+            Node::Branch(n) => format!("BR{n}"),
+            Node::Merge(n) => format!("MR{n}"),
+            Node::Enter(n) => format!("EN{n}"),
+            Node::Exit(n) => format!("EX{n}"),
+        }
+    }
+}
+
+/// One end of a piece of track: the node and, for switches, which side of it
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Port {
+    node: Node,
+    curved: Option<bool>,
+}
+
+impl Port {
+    /// The same end seen travelling the other way
+    fn reverse(self) -> Port {
+        Port {
+            node: self.node.reverse(),
+            curved: self.curved,
+        }
+    }
+}
+
+fn parse_port(word: &str) -> Option<Port> {
+    let (name, curved) = match word.split_once(':') {
+        Some((name, "S")) => (name, Some(false)),
+        Some((name, "C")) => (name, Some(true)),
+        Some(_) => return None,
+        None => (word, None),
+    };
+
+    let number = |prefix: &str| name.strip_prefix(prefix)?.parse::<u32>().ok();
+    let node = if let Some(n) = number("BR") {
+        Node::Branch(n)
+    } else if let Some(n) = number("MR") {
+        Node::Merge(n)
+    } else if let Some(n) = number("EN") {
+        Node::Enter(n)
+    } else if let Some(n) = number("EX") {
+        Node::Exit(n)
+    } else {
+        let bank = name.as_bytes().first()?.checked_sub(b'A')?;
+        let number: u8 = name.get(1..)?.parse().ok()?;
+        if bank >= SENSOR_BANKS || !(1..=SENSORS_PER_BANK).contains(&number) {
+            return None;
+        }
+        Node::Sensor((bank * SENSORS_PER_BANK + number - 1) as usize)
+    };
+    Some(Port { node, curved })
+}
+
+/// Which edge of its node a piece leaving `port` is, or `None` if nothing can leave from there
+fn leaving_slot(port: Port) -> Option<usize> {
+    match (port.node, port.curved) {
+        (Node::Branch(_), Some(curved)) => Some(curved as usize),
+        (Node::Sensor(_) | Node::Merge(_) | Node::Enter(_), None) => Some(0),
+        _ => None,
+    }
+}
+
+/// Whether a piece can arrive at `port`
+fn can_arrive(port: Port) -> bool {
+    matches!(
+        (port.node, port.curved),
+        (Node::Merge(_), Some(_)) | (Node::Sensor(_) | Node::Branch(_) | Node::Exit(_), None)
+    )
+}
+
+pub struct Edge {
+    pub dest: Node,
+    pub dist: u32,
+}
+
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<[Option<Edge>; 2]>,
+}
+
+impl Graph {
+    pub fn index(&self, node: Node) -> usize {
+        self.nodes.iter().position(|n| *n == node).unwrap()
+    }
+}
+
+/// Read the description in `text`. Mistakes in it panic, naming `path` and the line.
+pub fn parse_track(path: &str, text: &str) -> Graph {
+    let mut pieces = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fail = |why: &str| panic!("{path}:{}: {why}: {line}", i + 1);
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let [from, to, dist] = words[..] else {
+            fail("expected `<from> <to> <mm>`")
+        };
+        let from = parse_port(from).unwrap_or_else(|| fail("bad node"));
+        let to = parse_port(to).unwrap_or_else(|| fail("bad node"));
+        let dist: u32 = dist.parse().unwrap_or_else(|_| fail("bad distance"));
+        if leaving_slot(from).is_none() || !can_arrive(to) {
+            fail("the track can't run between those ends");
+        }
+        pieces.push((i + 1, from, to, dist));
+    }
+
+    // sensors first so a sensor's node is its index, then switches and dead ends by number
+    let mut named = BTreeSet::new();
+    for &(_, from, to, _) in &pieces {
+        for node in [from.node, to.node] {
+            named.insert(node);
+            named.insert(node.reverse());
+        }
+    }
+    let mut nodes: Vec<Node> = (0..SENSOR_COUNT).map(Node::Sensor).collect();
+    let mut others: Vec<Node> = named
+        .into_iter()
+        .filter(|node| !matches!(node, Node::Sensor(_)))
+        .collect();
+    others.sort_by_key(|node| match *node {
+        Node::Branch(n) => (0, n, 0),
+        Node::Merge(n) => (0, n, 1),
+        Node::Enter(n) => (1, n, 0),
+        Node::Exit(n) => (1, n, 1),
+        Node::Sensor(_) => unreachable!(),
+    });
+    nodes.extend(others);
+
+    let mut graph = Graph {
+        edges: nodes.iter().map(|_| [None, None]).collect(),
+        nodes,
+    };
+    for (line, from, to, dist) in pieces {
+        for (from, to) in [(from, to), (to.reverse(), from.reverse())] {
+            let index = graph.index(from.node);
+            let slot = &mut graph.edges[index][leaving_slot(from).unwrap()];
+            if slot.is_some() {
+                panic!(
+                    "{path}:{line}: {} already leads somewhere",
+                    from.node.name()
+                );
+            }
+            *slot = Some(Edge {
+                dest: to.node,
+                dist,
+            });
+        }
+    }
+
+    for (node, edges) in graph.nodes.iter().zip(&graph.edges) {
+        let wanted = match node {
+            Node::Branch(_) => 2,
+            Node::Exit(_) => 0,
+            _ => 1,
+        };
+        let found = edges.iter().flatten().count();
+        if found != wanted {
+            panic!(
+                "{path}: {} should lead to {wanted} places, not {found}",
+                node.name()
+            );
+        }
+    }
+    graph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::Write as _;
+
+    /// Round through switches 1 and 2, with the curved side of switch 1 a shortcut
+    const LOOP: &str = "
+        # a loop with a shortcut
+        A1 BR1 100
+        BR1:S A3 500
+        BR1:C MR2:C 200 # the shortcut
+        A3 MR2:S 100
+        MR2 A1 100
+    ";
+
+    /// `text` with every sensor it leaves out run into dead ends numbered from 100, since a
+    /// track has to account for all of them
+    fn pad(text: &str) -> String {
+        let mut text = format!("{text}\n");
+        for index in (0..SENSOR_COUNT).step_by(2) {
+            let names = [Node::Sensor(index).name(), Node::Sensor(index + 1).name()];
+            if !text
+                .split_whitespace()
+                .any(|word| names.iter().any(|name| name == word))
+            {
+                let name = &names[0];
+                writeln!(text, "EN{} {name} 1", 100 + index).unwrap();
+                writeln!(text, "{name} EX{} 1", 101 + index).unwrap();
+            }
+        }
+        text
+    }
+
+    fn edge(graph: &Graph, from: Node, dir: usize) -> Option<(Node, u32)> {
+        graph.edges[graph.index(from)][dir]
+            .as_ref()
+            .map(|edge| (edge.dest, edge.dist))
+    }
+
+    fn node(name: &str) -> Node {
+        parse_port(name).unwrap().node
+    }
+
+    #[test]
+    fn sensors_come_first_then_switches_and_dead_ends_by_number() {
+        let graph = parse_track("loop", &pad(&format!("{LOOP}\nEN7 A5 10\nA5 EX3 10")));
+        assert!((0..SENSOR_COUNT).all(|index| graph.nodes[index] == Node::Sensor(index)));
+        assert_eq!(
+            graph.nodes[SENSOR_COUNT..SENSOR_COUNT + 8],
+            [
+                Node::Branch(1),
+                Node::Merge(1),
+                Node::Branch(2),
+                Node::Merge(2),
+                Node::Enter(3),
+                Node::Exit(3),
+                Node::Enter(7),
+                Node::Exit(7),
+            ]
+        );
+    }
+
+    #[test]
+    fn each_piece_is_filled_in_both_ways() {
+        let graph = parse_track("loop", &pad(LOOP));
+        assert_eq!(edge(&graph, node("A1"), 0), Some((node("BR1"), 100)));
+        assert_eq!(edge(&graph, node("BR1"), 0), Some((node("A3"), 500)));
+        assert_eq!(edge(&graph, node("BR1"), 1), Some((node("MR2"), 200)));
+        assert_eq!(edge(&graph, node("A3"), 0), Some((node("MR2"), 100)));
+        assert_eq!(edge(&graph, node("MR2"), 0), Some((node("A1"), 100)));
+
+        // the same pieces travelled the other way
+        assert_eq!(edge(&graph, node("A2"), 0), Some((node("BR2"), 100)));
+        assert_eq!(edge(&graph, node("BR2"), 0), Some((node("A4"), 100)));
+        assert_eq!(edge(&graph, node("BR2"), 1), Some((node("MR1"), 200)));
+        assert_eq!(edge(&graph, node("A4"), 0), Some((node("MR1"), 500)));
+        assert_eq!(edge(&graph, node("MR1"), 0), Some((node("A2"), 100)));
+    }
+
+    #[test]
+    fn dead_ends_only_lead_into_the_track() {
+        let graph = parse_track("spur", &pad("EN1 A1 50\nA1 EX2 70"));
+        assert_eq!(edge(&graph, node("EN1"), 0), Some((node("A1"), 50)));
+        assert_eq!(edge(&graph, node("A1"), 0), Some((node("EX2"), 70)));
+        assert_eq!(edge(&graph, node("EN2"), 0), Some((node("A2"), 70)));
+        assert_eq!(edge(&graph, node("A2"), 0), Some((node("EX1"), 50)));
+        assert_eq!(edge(&graph, node("EX1"), 0), None);
+        assert_eq!(edge(&graph, node("EX2"), 0), None);
+    }
+
+    #[test]
+    fn names_read_back_as_written() {
+        for name in ["A1", "E16", "BR153", "MR4", "EN2", "EX10"] {
+            assert_eq!(node(name).name(), name);
+        }
+        for name in ["F1", "A0", "A17", "BR", "XY3", "BR1:X"] {
+            assert!(parse_port(name).is_none(), "{name}");
+        }
+    }
+
+    #[test]
+    fn the_track_files_parse() {
+        parse_track("a.txt", include_str!("../tracks/a.txt"));
+        parse_track("b.txt", include_str!("../tracks/b.txt"));
+    }
+
+    #[test]
+    #[should_panic(expected = "bad:1: expected `<from> <to> <mm>`: A1 BR1")]
+    fn missing_distance() {
+        parse_track("bad", "A1 BR1");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad:2: bad node: A1 F3 10")]
+    fn unknown_node() {
+        parse_track("bad", "EN1 A1 10\nA1 F3 10");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad:1: the track can't run between those ends: BR1 A1 10")]
+    fn branch_left_without_a_side() {
+        parse_track("bad", "BR1 A1 10");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad:2: A1 already leads somewhere")]
+    fn sensor_leading_two_ways() {
+        parse_track("bad", "A1 A3 10\nA1 A5 10");
+    }
+
+    #[test]
+    #[should_panic(expected = "bad: BR1 should lead to 2 places, not 1")]
+    fn branch_missing_a_side() {
+        parse_track("bad", &pad("EN1 BR1 10\nBR1:S EX2 10"));
+    }
+
+    #[test]
+    #[should_panic(expected = "bad: A1 should lead to 1 places, not 0")]
+    fn sensor_left_out() {
+        parse_track("bad", "EN1 A3 10\nA3 EX2 10");
+    }
+}
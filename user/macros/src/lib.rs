@@ -0,0 +1,49 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, spanned::Spanned, Error, ItemFn, ReturnType, Type};
+
+/// The symbol the kernel looks up to start the first user task
+const ENTRY_SYMBOL: &str = "__lunaris_user_entry";
+
+/// Export a `fn() -> !` as the first user task, so the kernel can start it without knowing which
+/// crate it lives in. There can only be one per image.
+#[proc_macro_attribute]
+pub fn entry(args: TokenStream, input: TokenStream) -> TokenStream {
+    if !args.is_empty() {
+        return Error::new(
+            proc_macro2::TokenStream::from(args).span(),
+            "#[entry] takes no arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let func = parse_macro_input!(input as ItemFn);
+    let sig = &func.sig;
+    let never_returns =
+        matches!(&sig.output, ReturnType::Type(_, ty) if matches!(**ty, Type::Never(_)));
+    if !sig.inputs.is_empty()
+        || !never_returns
+        || !sig.generics.params.is_empty()
+        || sig.asyncness.is_some()
+        || sig.unsafety.is_some()
+        || sig.abi.is_some()
+    {
+        return Error::new(sig.span(), "#[entry] must be a plain `fn() -> !`")
+            .to_compile_error()
+            .into();
+    }
+
+    let name = &sig.ident;
+    quote! {
+        #func
+
+        #[doc(hidden)]
+        #[allow(unsafe_code)]
+        #[export_name = #ENTRY_SYMBOL]
+        pub fn __lunaris_user_entry() -> ! {
+            #name()
+        }
+    }
+    .into()
+}
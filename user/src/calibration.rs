@@ -0,0 +1,1076 @@
+use crate::clock::{DelayUntil, Time, CLOCK_SERVER_NAME};
+use crate::marklin::{
+    Reverse, SetSpeed, SetSwitch, SwitchDirection, TrainError, MAX_SPEED, MAX_TRAIN,
+    REVERSE_STOP_TICKS, TRAIN_SERVER_NAME,
+};
+use crate::name_server::{RegisterAs, WhoIs};
+use crate::syscall::{
+    reply, reply_status, request, CreateEx, Exit, MyParentTid, Receive, Send, ServerError,
+    SyscallError, TaskOptions, Tid,
+};
+use crate::track::{NodeId, NodeKind, Position, Track, TRACKS};
+use crate::tracking::{AwaitLocation, TrackTrain, TRACKING_SERVER_NAME};
+use heapless::Vec;
+
+pub const CALIBRATION_SERVER_NAME: &str = "calibration";
+/// Below the train server, which it sends to
+pub const CALIBRATION_SERVER_PRIORITY: usize = 26;
+/// The train models live on the stack
+pub const CALIBRATION_SERVER_STACK_SIZE: usize = 0x4000;
+const STOPPER_PRIORITY: usize = 27;
+/// Above the tracking server, so it changes speed as soon as it hears of a sensor
+const CALIBRATOR_PRIORITY: usize = 27;
+/// Trains the server can model at once
+pub const MAX_MODELLED_TRAINS: usize = 8;
+const SPEEDS: usize = MAX_SPEED as usize + 1;
+
+// Velocities are in µm per 10ms tick, which keeps slow trains out of rounding error.
+/// What each speed step is worth before a train has been timed, about 40 mm/s
+const DEFAULT_VELOCITY_STEP: u32 = 400;
+/// µm per tick gained or lost each tick before a change of speed has been timed
+const DEFAULT_ACCEL: u32 = 30;
+/// Each sensor to sensor sample counts for 1/8 of the smoothed velocity
+const SMOOTHING_SHIFT: u32 = 3;
+/// StopAt gives up on stops further off than this, and trains further off than this from a sensor
+/// aren't expected there
+const MAX_PREDICT_TICKS: i64 = 6000;
+
+/// The calibrator runs its train at each of these speeds in turn. Every speed is run once before
+/// the changes that end at it, so those can be timed against a velocity already measured.
+const CALIBRATION_SPEEDS: [u8; 8] = [8, 10, 12, 14, 8, 14, 10, 12];
+/// and for at least this long at each, enough for a few laps
+const CALIBRATION_TICKS_PER_SPEED: i64 = 3000;
+
+const REQUEST_DRIVE: u8 = 0;
+const REQUEST_TURN_AROUND: u8 = 1;
+const REQUEST_THROW: u8 = 2;
+const REQUEST_PASSED: u8 = 3;
+const REQUEST_PREDICT: u8 = 4;
+const REQUEST_STOP_AT: u8 = 5;
+const REQUEST_STOPPING_DISTANCE: u8 = 6;
+const REQUEST_CALIBRATE: u8 = 7;
+/// From a stopper, once its stop is due
+const REQUEST_STOP_DUE: u8 = 8;
+const REQUEST_EXPECT: u8 = 9;
+
+/// Request kind, train or switch, then a speed or direction or a little endian node, then a little
+/// endian time, offset or distance from byte 4
+const REQUEST_SIZE: usize = 12;
+/// One byte of status, then a little endian node and offset, a distance or an `Expectation`
+const REPLY_SIZE: usize = 1 + EXPECTATION_SIZE;
+/// Three sensors and times, and the switch that could divert the train
+const EXPECTATION_SIZE: usize = 3 * 10 + 2;
+/// Sent in place of a sensor that isn't there
+const NO_SENSOR: u16 = u16::MAX;
+
+/// The time a train is expected at a sensor it won't reach soon
+pub const NEVER: i64 = i64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationError {
+    /// The train server turned the command down
+    Train(TrainError),
+    /// There is no such node on the track
+    InvalidNode,
+    /// No sensor has placed the train on the track yet
+    NotLocated,
+    /// The stop isn't ahead of the train with the switches as they are, or the train won't get
+    /// there at the speed it is going
+    Unreachable,
+    /// Every model is taken, or the server couldn't start a stopper or calibrator
+    Busy,
+    /// The calibration server couldn't be reached
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for CalibrationError {
+    fn from(err: SyscallError) -> Self {
+        Self::Syscall(err)
+    }
+}
+
+/// The train server's codes for the commands it turns down are passed on as they are
+impl ServerError for CalibrationError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::Train(
+                err @ (TrainError::InvalidTrain
+                | TrainError::InvalidSpeed
+                | TrainError::InvalidSwitch),
+            ) => err.status(),
+            Self::InvalidNode => 4,
+            Self::NotLocated => 5,
+            Self::Unreachable => 6,
+            Self::Train(_) | Self::Busy | Self::Syscall(_) => 7,
+        }
+    }
+
+    fn from_status(status: u8) -> Self {
+        match status {
+            1..=3 => Self::Train(TrainError::from_status(status)),
+            4 => Self::InvalidNode,
+            5 => Self::NotLocated,
+            6 => Self::Unreachable,
+            _ => Self::Busy,
+        }
+    }
+}
+
+/// Send `msg` to the calibration server, returning what follows the status in its reply
+fn call(server: Tid, msg: &[u8]) -> Result<[u8; REPLY_SIZE - 1], CalibrationError> {
+    let mut reply = [0u8; REPLY_SIZE];
+    request::<CalibrationError>(server, msg, &mut reply)?;
+    Ok(reply[1..].try_into().unwrap())
+}
+
+fn encode(kind: u8, first: u8, node: NodeId, arg: &[u8]) -> [u8; REQUEST_SIZE] {
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[0] = kind;
+    msg[1] = first;
+    msg[2..4].copy_from_slice(&(node as u16).to_le_bytes());
+    msg[4..4 + arg.len()].copy_from_slice(arg);
+    msg
+}
+
+/// Run `train` at `speed` through the train server, so the model knows it is speeding up or
+/// slowing down.
+#[allow(non_snake_case)]
+pub fn Drive(server: Tid, train: u8, speed: u8) -> Result<(), CalibrationError> {
+    call(server, &[REQUEST_DRIVE, train, speed]).map(|_| ())
+}
+
+/// Reverse `train` through the train server. The model has it stop, flip and set off again.
+#[allow(non_snake_case)]
+pub fn TurnAround(server: Tid, train: u8) -> Result<(), CalibrationError> {
+    call(server, &[REQUEST_TURN_AROUND, train]).map(|_| ())
+}
+
+/// Throw `switch` through the train server, so predictions follow it.
+#[allow(non_snake_case)]
+pub fn Throw(server: Tid, switch: u8, direction: SwitchDirection) -> Result<(), CalibrationError> {
+    call(server, &[REQUEST_THROW, switch, direction as u8]).map(|_| ())
+}
+
+/// Tell the model `train` passed the sensor at `node` at tick `time`. The first report places the
+/// train, and later ones at a steady speed are timed into its velocities.
+///
+/// Returns how many mm the model had the train past the sensor by then, negative if short of it.
+#[allow(non_snake_case)]
+pub fn Passed(server: Tid, train: u8, node: NodeId, time: i64) -> Result<i32, CalibrationError> {
+    let msg = encode(REQUEST_PASSED, train, node, &time.to_le_bytes());
+    let reply = call(server, &msg)?;
+    Ok(i32::from_le_bytes(reply[..4].try_into().unwrap()))
+}
+
+/// Where the front of `train` will be at tick `time`, if nothing else is asked of it before then.
+#[allow(non_snake_case)]
+pub fn PredictPosition(server: Tid, train: u8, time: i64) -> Result<Position, CalibrationError> {
+    let msg = encode(REQUEST_PREDICT, train, 0, &time.to_le_bytes());
+    let reply = call(server, &msg)?;
+    Ok(Position {
+        node: u16::from_le_bytes(reply[..2].try_into().unwrap()) as NodeId,
+        offset: u32::from_le_bytes(reply[2..6].try_into().unwrap()),
+    })
+}
+
+/// Bring `train` to a stop `offset_mm` past `node`, cutting its speed once it is its stopping
+/// distance away. Returns as soon as the stop is planned.
+#[allow(non_snake_case)]
+pub fn StopAt(
+    server: Tid,
+    train: u8,
+    node: NodeId,
+    offset_mm: u32,
+) -> Result<(), CalibrationError> {
+    let msg = encode(REQUEST_STOP_AT, train, node, &offset_mm.to_le_bytes());
+    call(server, &msg).map(|_| ())
+}
+
+/// How many mm `train` would take to stop if its speed were cut now, from how quickly it has
+/// been timed slowing down.
+#[allow(non_snake_case)]
+pub fn StoppingDistance(server: Tid, train: u8) -> Result<u32, CalibrationError> {
+    let reply = call(server, &[REQUEST_STOPPING_DISTANCE, train])?;
+    Ok(u32::from_le_bytes(reply[..4].try_into().unwrap()))
+}
+
+/// Run `train` round the track on its own to time it, at a few speeds and the changes between
+/// them. It has to be the only train running, and needs the tracking server up. Returns as soon
+/// as the train sets off.
+#[allow(non_snake_case)]
+pub fn Calibrate(server: Tid, train: u8) -> Result<(), CalibrationError> {
+    call(server, &[REQUEST_CALIBRATE, train]).map(|_| ())
+}
+
+/// A sensor a train should pass, and when
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expected {
+    pub sensor: NodeId,
+    /// The tick it gets there, or `NEVER`
+    pub time: i64,
+}
+
+/// The sensors a train could trip next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expectation {
+    /// The first sensor ahead with the switches as they are
+    pub next: Expected,
+    /// The one after it, where the train turns up if `next` fails to trigger
+    pub after: Option<Expected>,
+    /// Where the train turns up if the first switch before `next` didn't throw, and that switch
+    /// set the way it would have to be
+    pub diverted: Option<(Expected, u8, SwitchDirection)>,
+}
+
+impl Expectation {
+    fn encode(&self) -> [u8; EXPECTATION_SIZE] {
+        let mut bytes = [0u8; EXPECTATION_SIZE];
+        let diverted = self.diverted.map(|(expected, _, _)| expected);
+        for (i, expected) in [Some(self.next), self.after, diverted].iter().enumerate() {
+            let (sensor, time) = match expected {
+                Some(expected) => (expected.sensor as u16, expected.time),
+                None => (NO_SENSOR, NEVER),
+            };
+            bytes[i * 10..i * 10 + 2].copy_from_slice(&sensor.to_le_bytes());
+            bytes[i * 10 + 2..i * 10 + 10].copy_from_slice(&time.to_le_bytes());
+        }
+        if let Some((_, switch, direction)) = self.diverted {
+            bytes[30] = switch;
+            bytes[31] = direction as u8;
+        }
+        bytes
+    }
+
+    fn decode(bytes: &[u8; EXPECTATION_SIZE]) -> Self {
+        let expected = |i: usize| {
+            let sensor = u16::from_le_bytes(bytes[i * 10..i * 10 + 2].try_into().unwrap());
+            let time = i64::from_le_bytes(bytes[i * 10 + 2..i * 10 + 10].try_into().unwrap());
+            (sensor != NO_SENSOR).then_some(Expected {
+                sensor: sensor as NodeId,
+                time,
+            })
+        };
+        let direction = if bytes[31] == SwitchDirection::Curved as u8 {
+            SwitchDirection::Curved
+        } else {
+            SwitchDirection::Straight
+        };
+        Self {
+            next: expected(0).unwrap(),
+            after: expected(1),
+            diverted: expected(2).map(|expected| (expected, bytes[30], direction)),
+        }
+    }
+}
+
+/// The sensors `train` could trip next and when, counting from the last time it was seen or
+/// told anything.
+#[allow(non_snake_case)]
+pub fn Expect(server: Tid, train: u8) -> Result<Expectation, CalibrationError> {
+    let reply = call(server, &[REQUEST_EXPECT, train])?;
+    Ok(Expectation::decode(&reply))
+}
+
+/// How a train is moving since it was last seen or told anything. Speeding up and slowing down
+/// are taken to happen at a constant rate.
+#[derive(Debug, Clone, Copy)]
+struct Motion {
+    /// Where the front of the train was at `time`
+    anchor: Position,
+    time: i64,
+    /// Velocity at `time`
+    from: u32,
+    /// Velocity it is heading for
+    to: u32,
+    /// Velocity gained or lost each tick until it gets there
+    rate: u32,
+}
+
+impl Motion {
+    fn ramp_ticks(&self) -> i64 {
+        (self.to.abs_diff(self.from) / self.rate) as i64
+    }
+
+    fn velocity(&self, time: i64) -> u32 {
+        let dt = (time - self.time).max(0);
+        if dt >= self.ramp_ticks() {
+            self.to
+        } else if self.to > self.from {
+            self.from + self.rate * dt as u32
+        } else {
+            self.from - self.rate * dt as u32
+        }
+    }
+
+    /// µm covered from `time` on to `until`
+    fn travelled(&self, until: i64) -> u64 {
+        let dt = (until - self.time).max(0);
+        let ramp = dt.min(self.ramp_ticks());
+        let ramp_end = self.velocity(self.time + ramp) as u64;
+        (self.from as u64 + ramp_end) * ramp as u64 / 2 + self.to as u64 * (dt - ramp) as u64
+    }
+
+    /// The tick the train will have covered `dist` mm since `time`, or `NEVER`
+    fn arrival(&self, dist: u32) -> i64 {
+        let dist_um = dist as u64 * 1000;
+        if self.travelled(self.time + MAX_PREDICT_TICKS) < dist_um {
+            return NEVER;
+        }
+        let (mut lo, mut hi) = (0, MAX_PREDICT_TICKS);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.travelled(self.time + mid) < dist_um {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        self.time + hi
+    }
+
+    fn position(&self, track: &Track, switches: &Switches, time: i64) -> Position {
+        let mm = (self.travelled(time) / 1000) as u32;
+        track.advance(self.anchor, mm, |switch| switches[switch as usize])
+    }
+}
+
+/// The rate a train must have gone from velocity `from` to `to` at to cover `dist_um` in `dt`
+/// ticks, or `None` if no constant rate fits, as when something held it up.
+fn ramp_rate(from: u32, to: u32, dist_um: u64, dt: i64) -> Option<u32> {
+    if dt <= 0 || from == to {
+        return None;
+    }
+    let (from, to, dist) = (from as i64, to as i64, dist_um as i64);
+    let change = (to - from).abs();
+    let speeding_up = to > from;
+
+    // still changing speed at the sensor: dist = from * dt ± rate * dt² / 2
+    let gained = if speeding_up {
+        dist - from * dt
+    } else {
+        from * dt - dist
+    };
+    if gained > 0 && 2 * gained <= change * dt {
+        return Some((2 * gained / (dt * dt)).max(1) as u32);
+    }
+    // done by then: dist = to * dt ∓ change² / (2 * rate)
+    let short = if speeding_up {
+        to * dt - dist
+    } else {
+        dist - to * dt
+    };
+    if short <= 0 {
+        return None;
+    }
+    let rate = (change * change / (2 * short)).max(1);
+    (change <= rate * dt).then_some(rate as u32)
+}
+
+/// Fold `sample` into the exponentially smoothed `value`. The first sample replaces the guess
+/// outright.
+fn smooth(value: &mut u32, samples: &mut u32, sample: u32) {
+    *value = if *samples == 0 {
+        sample
+    } else {
+        let diff = sample as i64 - *value as i64;
+        (*value as i64 + (diff >> SMOOTHING_SHIFT)) as u32
+    };
+    *samples += 1;
+}
+
+type Switches = [SwitchDirection; 256];
+
+/// What the server knows of one train
+struct TrainModel {
+    train: u8,
+    /// Exponentially smoothed, indexed by speed
+    velocity: [u32; SPEEDS],
+    /// Samples taken at each speed
+    samples: [u32; SPEEDS],
+    /// µm per tick gained each tick speeding up, exponentially smoothed
+    accel: u32,
+    accel_samples: u32,
+    /// and lost each tick slowing down, which stopping distances follow from
+    decel: u32,
+    decel_samples: u32,
+    speed: u8,
+    /// `None` until a sensor places the train
+    motion: Option<Motion>,
+    /// The last sensor passed and when, as long as the train has kept a steady speed since
+    last_pass: Option<(NodeId, i64)>,
+    /// A change between two measured speeds, made while the train was going steadily. The next
+    /// sensor times how quickly it changed.
+    ramp: Option<Motion>,
+    /// When the planned StopAt cuts the speed
+    stop_due: Option<i64>,
+}
+
+impl TrainModel {
+    fn new(train: u8) -> Self {
+        Self {
+            train,
+            velocity: core::array::from_fn(|speed| speed as u32 * DEFAULT_VELOCITY_STEP),
+            samples: [0; SPEEDS],
+            accel: DEFAULT_ACCEL,
+            accel_samples: 0,
+            decel: DEFAULT_ACCEL,
+            decel_samples: 0,
+            speed: 0,
+            motion: None,
+            last_pass: None,
+            ramp: None,
+            stop_due: None,
+        }
+    }
+
+    /// Velocity gained or lost each tick going from `from` to `to`
+    fn rate(&self, from: u32, to: u32) -> u32 {
+        if to > from {
+            self.accel
+        } else {
+            self.decel
+        }
+    }
+
+    /// µm it takes to stop from velocity `v`
+    fn stopping_um(&self, v: u32) -> u64 {
+        let v = v as u64;
+        v * v / (2 * self.decel as u64)
+    }
+
+    /// Start a new motion from wherever the train is at `time`
+    fn reanchor(&mut self, track: &Track, switches: &Switches, time: i64, to: u32, rate: u32) {
+        if let Some(motion) = self.motion {
+            self.motion = Some(Motion {
+                anchor: motion.position(track, switches, time),
+                time,
+                from: motion.velocity(time),
+                to,
+                rate,
+            });
+        }
+    }
+
+    fn drive(&mut self, track: &Track, switches: &Switches, now: i64, speed: u8) {
+        let to = self.velocity[speed as usize];
+        let from = self.motion.map_or(to, |motion| motion.velocity(now));
+        self.reanchor(track, switches, now, to, self.rate(from, to));
+
+        // a ramp is only worth timing between velocities the model is sure of
+        let known = |speed: u8| speed == 0 || self.samples[speed as usize] > 0;
+        let steady = self.last_pass.is_some() && known(self.speed);
+        self.ramp = self.motion.filter(|_| steady && from != to && known(speed));
+        self.speed = speed;
+        self.last_pass = None;
+        self.stop_due = None;
+    }
+
+    fn turn_around(&mut self, track: &Track, switches: &Switches, now: i64) {
+        self.last_pass = None;
+        self.ramp = None;
+        self.stop_due = None;
+
+        if let Some(motion) = self.motion {
+            let stopped = track.advance(
+                motion.position(track, switches, now),
+                (self.stopping_um(motion.velocity(now)) / 1000) as u32,
+                |switch| switches[switch as usize],
+            );
+            // the train server waits this long before setting off the other way
+            let to = self.velocity[self.speed as usize];
+            self.motion = Some(Motion {
+                anchor: track.flip(stopped, |switch| switches[switch as usize]),
+                time: now + REVERSE_STOP_TICKS,
+                from: 0,
+                to,
+                rate: self.rate(0, to),
+            });
+        }
+    }
+
+    /// Returns how many mm the model had the train past the sensor, negative if short of it
+    fn passed(&mut self, track: &Track, switches: &Switches, node: NodeId, time: i64) -> i32 {
+        let switch = |switch: u8| switches[switch as usize];
+        let sensor = Position { node, offset: 0 };
+
+        // whichever way round is shorter, as the track loops
+        let error = self.motion.map_or(0, |motion| {
+            let predicted = motion.position(track, switches, time);
+            let past = track.distance(sensor, predicted, switch);
+            let short = track.distance(predicted, sensor, switch);
+            match (past, short) {
+                (Some(past), Some(short)) if short < past => -(short as i32),
+                (Some(past), _) => past as i32,
+                (None, Some(short)) => -(short as i32),
+                (None, None) => 0,
+            }
+        });
+
+        if let Some((last, last_time)) = self.last_pass.filter(|&(_, t)| t < time) {
+            let last = Position {
+                node: last,
+                offset: 0,
+            };
+            if let Some(dist) = track.distance(last, sensor, switch) {
+                let speed = self.speed as usize;
+                let velocity = (dist as i64 * 1000 / (time - last_time)) as u32;
+                smooth(
+                    &mut self.velocity[speed],
+                    &mut self.samples[speed],
+                    velocity,
+                );
+            }
+        }
+
+        if let Some(ramp) = self.ramp.take() {
+            let rate = track
+                .distance(ramp.anchor, sensor, switch)
+                .and_then(|dist| {
+                    ramp_rate(ramp.from, ramp.to, dist as u64 * 1000, time - ramp.time)
+                });
+            match rate {
+                Some(rate) if ramp.to > ramp.from => {
+                    smooth(&mut self.accel, &mut self.accel_samples, rate)
+                }
+                Some(rate) => smooth(&mut self.decel, &mut self.decel_samples, rate),
+                None => {}
+            }
+        }
+
+        let to = self.velocity[self.speed as usize];
+        let from = match self.motion {
+            Some(motion) if time < motion.time + motion.ramp_ticks() => motion.velocity(time),
+            _ => to,
+        };
+        self.motion = Some(Motion {
+            anchor: sensor,
+            time,
+            from,
+            to,
+            rate: self.rate(from, to),
+        });
+        self.last_pass = (from == to).then_some((node, time));
+        error
+    }
+
+    fn predict(
+        &self,
+        track: &Track,
+        switches: &Switches,
+        time: i64,
+    ) -> Result<Position, CalibrationError> {
+        let motion = self.motion.ok_or(CalibrationError::NotLocated)?;
+        Ok(motion.position(track, switches, time))
+    }
+
+    /// The last tick the speed can be cut and still have the train stop short of `target`, or
+    /// `None` if it should be cut now
+    fn stop_tick(
+        &self,
+        track: &Track,
+        switches: &Switches,
+        now: i64,
+        target: Position,
+    ) -> Result<Option<i64>, CalibrationError> {
+        let motion = self.motion.ok_or(CalibrationError::NotLocated)?;
+        let here = motion.position(track, switches, now);
+        let dist = track
+            .distance(here, target, |switch| switches[switch as usize])
+            .ok_or(CalibrationError::Unreachable)?;
+        let dist_um = dist as u64 * 1000;
+
+        // how far the train gets if its speed is cut `dt` ticks from now
+        let base = motion.travelled(now);
+        let reach = |dt: i64| {
+            motion.travelled(now + dt) - base + self.stopping_um(motion.velocity(now + dt))
+        };
+        if reach(0) >= dist_um {
+            return Ok(None);
+        }
+        if reach(MAX_PREDICT_TICKS) < dist_um {
+            return Err(CalibrationError::Unreachable);
+        }
+        let (mut lo, mut hi) = (0, MAX_PREDICT_TICKS);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if reach(mid) < dist_um {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        Ok(Some(now + lo))
+    }
+
+    fn expect(&self, track: &Track, switches: &Switches) -> Result<Expectation, CalibrationError> {
+        let motion = self.motion.ok_or(CalibrationError::NotLocated)?;
+        let switch = |switch: u8| switches[switch as usize];
+        let from = motion.anchor;
+
+        let (next, dist) = track
+            .next_sensor(from, switch)
+            .ok_or(CalibrationError::Unreachable)?;
+        let expected = |(sensor, dist): (NodeId, u32)| Expected {
+            sensor,
+            time: motion.arrival(dist),
+        };
+        let after = track
+            .next_sensor(
+                Position {
+                    node: next,
+                    offset: 0,
+                },
+                switch,
+            )
+            .map(|(sensor, more)| expected((sensor, dist + more)));
+
+        // the points of a branch the train is already past have done their work
+        let mut node = from.node;
+        if from.offset > 0 {
+            node = track
+                .ahead(node, switch)
+                .ok_or(CalibrationError::Unreachable)?
+                .dest;
+        }
+        let mut diverted = None;
+        while node != next {
+            if let NodeKind::Branch(number) = track.nodes[node].kind {
+                let other = match switch(number) {
+                    SwitchDirection::Straight => SwitchDirection::Curved,
+                    SwitchDirection::Curved => SwitchDirection::Straight,
+                };
+                let thrown = |n: u8| if n == number { other } else { switch(n) };
+                diverted = track
+                    .next_sensor(from, thrown)
+                    .map(|found| (expected(found), number, other));
+                break;
+            }
+            node = track
+                .ahead(node, switch)
+                .ok_or(CalibrationError::Unreachable)?
+                .dest;
+        }
+
+        Ok(Expectation {
+            next: expected((next, dist)),
+            after,
+            diverted,
+        })
+    }
+
+    /// mm the train takes to stop if its speed is cut at `now`
+    fn stopping_distance(&self, now: i64) -> u32 {
+        let v = match self.motion {
+            Some(motion) => motion.velocity(now),
+            None => self.velocity[self.speed as usize],
+        };
+        (self.stopping_um(v) / 1000) as u32
+    }
+}
+
+/// Cuts a train's speed when its stop is due, without holding up the server. Started with the
+/// due tick shifted up past the train number.
+fn stopper(arg: usize) -> ! {
+    let server = MyParentTid().unwrap();
+    let clock = WhoIs(CLOCK_SERVER_NAME).unwrap();
+    let due = (arg >> 8) as i64;
+    DelayUntil(clock, due).unwrap();
+    let msg = encode(REQUEST_STOP_DUE, arg as u8, 0, &due.to_le_bytes());
+    Send(server, &msg, &mut []).unwrap();
+    Exit();
+}
+
+/// Runs a lone train at each of `CALIBRATION_SPEEDS` in turn, changing speed as it passes a
+/// sensor. The tracking server reports its sensor hits, which times its velocities and how
+/// quickly it changes between them. Started by `Calibrate`, with the train number as its argument.
+fn calibrator(train: usize) -> ! {
+    let server = MyParentTid().unwrap();
+    let tracking = WhoIs(TRACKING_SERVER_NAME).unwrap();
+    let clock = WhoIs(CLOCK_SERVER_NAME).unwrap();
+    let train = train as u8;
+    TrackTrain(tracking, train).unwrap();
+
+    for speed in CALIBRATION_SPEEDS {
+        Drive(server, train, speed).unwrap();
+        let until = Time(clock).unwrap() + CALIBRATION_TICKS_PER_SPEED;
+        loop {
+            let location = AwaitLocation(tracking).unwrap();
+            if location.train == train && Time(clock).unwrap() >= until {
+                break;
+            }
+        }
+    }
+    Drive(server, train, 0).unwrap();
+    Exit();
+}
+
+struct CalibrationServer {
+    track: &'static Track,
+    train_server: Tid,
+    clock: Tid,
+    /// As last thrown through this server, all straight before that
+    switches: Switches,
+    models: Vec<TrainModel, MAX_MODELLED_TRAINS>,
+}
+
+impl CalibrationServer {
+    fn model(&mut self, train: u8) -> Option<&mut TrainModel> {
+        if !self.models.iter().any(|model| model.train == train) {
+            self.models.push(TrainModel::new(train)).ok()?;
+        }
+        self.models.iter_mut().find(|model| model.train == train)
+    }
+
+    fn now(&self) -> i64 {
+        Time(self.clock).unwrap()
+    }
+
+    fn drive(&mut self, train: u8, speed: u8) -> Result<(), CalibrationError> {
+        SetSpeed(self.train_server, train, speed, false).map_err(CalibrationError::Train)?;
+        let now = self.now();
+        let (track, switches) = (self.track, self.switches);
+        // trains past the last model still run, they just aren't predicted
+        if let Some(model) = self.model(train) {
+            model.drive(track, &switches, now, speed);
+        }
+        Ok(())
+    }
+
+    fn turn_around(&mut self, train: u8) -> Result<(), CalibrationError> {
+        Reverse(self.train_server, train).map_err(CalibrationError::Train)?;
+        let now = self.now();
+        let (track, switches) = (self.track, self.switches);
+        if let Some(model) = self.model(train) {
+            model.turn_around(track, &switches, now);
+        }
+        Ok(())
+    }
+
+    fn throw(&mut self, switch: u8, direction: SwitchDirection) -> Result<(), CalibrationError> {
+        SetSwitch(self.train_server, switch, direction).map_err(CalibrationError::Train)?;
+        // trains already past the switch mustn't be moved onto its other side
+        let now = self.now();
+        for model in &mut self.models {
+            if let Some(motion) = model.motion {
+                model.reanchor(self.track, &self.switches, now, motion.to, motion.rate);
+            }
+            // the distance it was timed over may have changed under it
+            model.ramp = None;
+        }
+        self.switches[switch as usize] = direction;
+        Ok(())
+    }
+
+    fn passed(&mut self, train: u8, node: NodeId, time: i64) -> Result<i32, CalibrationError> {
+        let (track, switches) = (self.track, self.switches);
+        let model = self.model(train).ok_or(CalibrationError::Busy)?;
+        Ok(model.passed(track, &switches, node, time))
+    }
+
+    fn predict(&mut self, train: u8, time: i64) -> Result<Position, CalibrationError> {
+        let (track, switches) = (self.track, self.switches);
+        let model = self.model(train).ok_or(CalibrationError::Busy)?;
+        model.predict(track, &switches, time)
+    }
+
+    fn stop_at(&mut self, train: u8, target: Position) -> Result<(), CalibrationError> {
+        let now = self.now();
+        let (track, switches) = (self.track, self.switches);
+        let model = self.model(train).ok_or(CalibrationError::Busy)?;
+        let Some(due) = model.stop_tick(track, &switches, now, target)? else {
+            return self.drive(train, 0);
+        };
+
+        let created = CreateEx(&TaskOptions {
+            priority: STOPPER_PRIORITY,
+            func: stopper,
+            stack_size: 0x1000,
+            arg: ((due as usize) << 8) | train as usize,
+            name: "stopper",
+        });
+        if created.is_err() {
+            return Err(CalibrationError::Busy);
+        }
+        model.stop_due = Some(due);
+        Ok(())
+    }
+
+    fn stop_due(&mut self, train: u8, due: i64) {
+        // a later command has taken over from the stop
+        if self
+            .model(train)
+            .is_some_and(|model| model.stop_due == Some(due))
+        {
+            let _ = self.drive(train, 0);
+        }
+    }
+
+    fn expect(&mut self, train: u8) -> Result<Expectation, CalibrationError> {
+        let (track, switches) = (self.track, self.switches);
+        let model = self.model(train).ok_or(CalibrationError::Busy)?;
+        model.expect(track, &switches)
+    }
+
+    fn stopping_distance(&mut self, train: u8) -> Result<u32, CalibrationError> {
+        let now = self.now();
+        let model = self.model(train).ok_or(CalibrationError::Busy)?;
+        Ok(model.stopping_distance(now))
+    }
+
+    fn calibrate(&mut self, train: u8) -> Result<(), CalibrationError> {
+        CreateEx(&TaskOptions {
+            priority: CALIBRATOR_PRIORITY,
+            func: calibrator,
+            stack_size: 0x1000,
+            arg: train as usize,
+            name: "calibrator",
+        })
+        .map(|_| ())
+        .map_err(|_| CalibrationError::Busy)
+    }
+}
+
+/// Models how trains move from the sensors they pass and the commands sent through it, so they
+/// can be predicted and stopped on the spot. Started with the index of the track in `TRACKS` as
+/// its argument, after the clock and train servers.
+pub fn calibration_server(track: usize) -> ! {
+    RegisterAs(CALIBRATION_SERVER_NAME).unwrap();
+    let mut server = CalibrationServer {
+        track: TRACKS[track],
+        train_server: WhoIs(TRAIN_SERVER_NAME).unwrap(),
+        clock: WhoIs(CLOCK_SERVER_NAME).unwrap(),
+        switches: [SwitchDirection::Straight; 256],
+        models: Vec::new(),
+    };
+
+    loop {
+        let mut msg = [0u8; REQUEST_SIZE];
+        let (tid, _) = Receive(&mut msg);
+        let [kind, first, second, ..] = msg;
+        let node = u16::from_le_bytes(msg[2..4].try_into().unwrap()) as NodeId;
+        let time = i64::from_le_bytes(msg[4..12].try_into().unwrap());
+        let arg = u32::from_le_bytes(msg[4..8].try_into().unwrap());
+        let valid_train = (1..=MAX_TRAIN).contains(&first);
+        let valid_node = node < server.track.nodes.len();
+
+        match kind {
+            REQUEST_STOP_DUE => {
+                reply(tid, &[]);
+                server.stop_due(first, time);
+            }
+            REQUEST_THROW if first == 0 => {
+                reply_status(tid, Err(CalibrationError::Train(TrainError::InvalidSwitch)))
+            }
+            REQUEST_THROW => {
+                let direction = if second == SwitchDirection::Curved as u8 {
+                    SwitchDirection::Curved
+                } else {
+                    SwitchDirection::Straight
+                };
+                reply_status(tid, server.throw(first, direction));
+            }
+            _ if !valid_train => {
+                reply_status(tid, Err(CalibrationError::Train(TrainError::InvalidTrain)))
+            }
+            REQUEST_DRIVE => reply_status(tid, server.drive(first, second)),
+            REQUEST_TURN_AROUND => reply_status(tid, server.turn_around(first)),
+            REQUEST_PASSED | REQUEST_STOP_AT if !valid_node => {
+                reply_status(tid, Err(CalibrationError::InvalidNode))
+            }
+            REQUEST_PASSED => match server.passed(first, node, time) {
+                Ok(error) => {
+                    let mut msg = [0u8; REPLY_SIZE];
+                    msg[1..5].copy_from_slice(&error.to_le_bytes());
+                    reply(tid, &msg);
+                }
+                Err(err) => reply_status(tid, Err(err)),
+            },
+            REQUEST_PREDICT => match server.predict(first, time) {
+                Ok(pos) => {
+                    let mut msg = [0u8; REPLY_SIZE];
+                    msg[1..3].copy_from_slice(&(pos.node as u16).to_le_bytes());
+                    msg[3..7].copy_from_slice(&pos.offset.to_le_bytes());
+                    reply(tid, &msg);
+                }
+                Err(err) => reply_status(tid, Err(err)),
+            },
+            REQUEST_STOP_AT => {
+                let target = Position { node, offset: arg };
+                reply_status(tid, server.stop_at(first, target));
+            }
+            REQUEST_EXPECT => match server.expect(first) {
+                Ok(expectation) => {
+                    let mut msg = [0u8; REPLY_SIZE];
+                    msg[1..].copy_from_slice(&expectation.encode());
+                    reply(tid, &msg);
+                }
+                Err(err) => reply_status(tid, Err(err)),
+            },
+            REQUEST_STOPPING_DISTANCE => match server.stopping_distance(first) {
+                Ok(mm) => {
+                    let mut msg = [0u8; REPLY_SIZE];
+                    msg[1..5].copy_from_slice(&mm.to_le_bytes());
+                    reply(tid, &msg);
+                }
+                Err(err) => reply_status(tid, Err(err)),
+            },
+            REQUEST_CALIBRATE => reply_status(tid, server.calibrate(first)),
+            _ => reply_status(tid, Err(CalibrationError::Busy)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::tests::{A1, A3, BR1, LOOP};
+
+    const STRAIGHT: Switches = [SwitchDirection::Straight; 256];
+
+    fn at(node: NodeId, offset: u32) -> Position {
+        Position { node, offset }
+    }
+
+    /// A model of a train already timed at speed 10, seen at `sensor` at `time` going steadily
+    fn timed(velocity: u32, sensor: NodeId, time: i64) -> TrainModel {
+        let mut model = TrainModel::new(24);
+        model.speed = 10;
+        model.velocity[10] = velocity;
+        model.samples[10] = 1;
+        model.passed(&LOOP, &STRAIGHT, sensor, time);
+        model
+    }
+
+    #[test]
+    fn ramp_rate_finds_the_rate_either_side_of_the_ramp() {
+        // 0 to 40 at 4 takes 10 ticks, and covers 50µm in the first 5
+        assert_eq!(ramp_rate(0, 40, 50, 5), Some(4));
+        // 100 to 20 at 4 takes 20 ticks and 1200µm, then 10 more at 20
+        assert_eq!(ramp_rate(100, 20, 1400, 30), Some(4));
+        // too short or long a way for any rate, as when the train was held up
+        assert_eq!(ramp_rate(0, 40, 0, 10), None);
+        assert_eq!(ramp_rate(100, 20, 5000, 30), None);
+        assert_eq!(ramp_rate(40, 40, 400, 10), None);
+    }
+
+    #[test]
+    fn motion_covers_the_ramp_then_runs_steadily() {
+        let motion = Motion {
+            anchor: at(A1, 0),
+            time: 100,
+            from: 0,
+            to: 40,
+            rate: 4,
+        };
+        assert_eq!(motion.velocity(105), 20);
+        assert_eq!(motion.velocity(200), 40);
+        assert_eq!(motion.travelled(90), 0);
+        assert_eq!(motion.travelled(105), 50);
+        assert_eq!(motion.travelled(120), 600);
+        assert_eq!(motion.arrival(1), 130);
+        assert_eq!(motion.arrival(1_000_000), NEVER);
+    }
+
+    #[test]
+    fn smoothing_takes_the_first_sample_whole() {
+        let (mut value, mut samples) = (400, 0);
+        smooth(&mut value, &mut samples, 1000);
+        assert_eq!((value, samples), (1000, 1));
+        smooth(&mut value, &mut samples, 1800);
+        assert_eq!((value, samples), (1100, 2));
+    }
+
+    #[test]
+    fn passing_two_sensors_times_the_velocity() {
+        let mut model = TrainModel::new(24);
+        model.drive(&LOOP, &STRAIGHT, 0, 10);
+        assert_eq!(model.passed(&LOOP, &STRAIGHT, A1, 1000), 0);
+
+        // 600mm in 120 ticks, where the guess had it 480mm along
+        assert_eq!(model.passed(&LOOP, &STRAIGHT, A3, 1120), -120);
+        assert_eq!(model.velocity[10], 5000);
+        assert_eq!(model.samples[10], 1);
+    }
+
+    #[test]
+    fn a_stop_timed_at_a_sensor_sets_the_stopping_distance() {
+        let mut model = timed(5000, A3, 0);
+        model.drive(&LOOP, &STRAIGHT, 0, 0);
+
+        // slowing by 40 a tick covers the 200mm round to A1 in 50 ticks
+        model.passed(&LOOP, &STRAIGHT, A1, 50);
+        assert_eq!(model.decel, 40);
+        assert_eq!(model.accel, DEFAULT_ACCEL);
+        assert_eq!(model.stopping_um(5000) / 1000, 312);
+    }
+
+    #[test]
+    fn a_change_from_an_unsteady_speed_is_not_timed() {
+        let mut model = timed(5000, A3, 0);
+        model.drive(&LOOP, &STRAIGHT, 0, 12);
+        assert!(model.ramp.is_none());
+
+        let mut model = timed(5000, A3, 0);
+        model.drive(&LOOP, &STRAIGHT, 0, 0);
+        model.drive(&LOOP, &STRAIGHT, 10, 10);
+        assert!(model.ramp.is_none());
+    }
+
+    #[test]
+    fn predict_follows_the_switches() {
+        let mut model = TrainModel::new(24);
+        assert_eq!(
+            model.predict(&LOOP, &STRAIGHT, 0),
+            Err(CalibrationError::NotLocated)
+        );
+
+        let model = timed(4000, A1, 0);
+        assert_eq!(model.predict(&LOOP, &STRAIGHT, 50), Ok(at(BR1, 100)));
+        let mut curved = STRAIGHT;
+        curved[1] = SwitchDirection::Curved;
+        assert_eq!(model.predict(&LOOP, &curved, 100), Ok(at(A1, 0)));
+    }
+
+    #[test]
+    fn stop_tick_stops_the_train_just_short() {
+        let mut model = timed(4000, A1, 0);
+        let due = model.stop_tick(&LOOP, &STRAIGHT, 0, at(A3, 0)).unwrap();
+        assert_eq!(due, Some(83));
+
+        model.drive(&LOOP, &STRAIGHT, 83, 0);
+        let stopped = model.predict(&LOOP, &STRAIGHT, 1000).unwrap();
+        let short = LOOP.distance(stopped, at(A3, 0), |_| SwitchDirection::Straight);
+        assert!(short.is_some_and(|short| short < 5), "{:?}", stopped);
+    }
+
+    #[test]
+    fn stop_tick_cuts_now_or_gives_up() {
+        let model = timed(4000, A1, 0);
+        assert_eq!(model.stop_tick(&LOOP, &STRAIGHT, 0, at(BR1, 0)), Ok(None));
+
+        let model = timed(0, A1, 0);
+        assert_eq!(
+            model.stop_tick(&LOOP, &STRAIGHT, 0, at(A3, 0)),
+            Err(CalibrationError::Unreachable)
+        );
+        assert_eq!(
+            TrainModel::new(24).stop_tick(&LOOP, &STRAIGHT, 0, at(A3, 0)),
+            Err(CalibrationError::NotLocated)
+        );
+    }
+
+    #[test]
+    fn expect_looks_past_the_next_sensor_and_the_switch_before_it() {
+        let model = timed(4000, A1, 0);
+        let expectation = model.expect(&LOOP, &STRAIGHT).unwrap();
+        let expected = |sensor, time| Expected { sensor, time };
+
+        assert_eq!(expectation.next, expected(A3, 150));
+        assert_eq!(expectation.after, Some(expected(A1, 200)));
+        // switch 1 failing to stay straight sends it round the shortcut
+        assert_eq!(
+            expectation.diverted,
+            Some((expected(A1, 100), 1, SwitchDirection::Curved))
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::name_server::RegisterAs;
+use crate::syscall::{
+    reply, AwaitEvent, Create, MyParentTid, Receive, Send, SyscallError, Tid, EVENT_TIMER,
+};
+use heapless::{binary_heap::Min, BinaryHeap};
+
+pub const CLOCK_SERVER_NAME: &str = "clock";
+pub const CLOCK_SERVER_PRIORITY: usize = 30;
+const CLOCK_NOTIFIER_PRIORITY: usize = 31;
+/// Every other task could be sleeping at once
+const MAX_SLEEPERS: usize = 64;
+
+/// Sent back in place of the time when Delay was asked to wait a negative number of ticks
+const NEGATIVE_DELAY: i64 = -1;
+
+const REQUEST_TICK: u8 = 0;
+const REQUEST_TIME: u8 = 1;
+const REQUEST_DELAY: u8 = 2;
+const REQUEST_DELAY_UNTIL: u8 = 3;
+
+/// One byte of request kind followed by a little endian argument
+const REQUEST_SIZE: usize = 9;
+const REPLY_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    /// Delay was asked to wait a negative number of ticks
+    NegativeDelay,
+    /// The clock server couldn't be reached
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for ClockError {
+    fn from(err: SyscallError) -> Self {
+        Self::Syscall(err)
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct Sleeper {
+    until: i64,
+    tid: Tid,
+}
+
+fn request(clock_server: Tid, kind: u8, arg: i64) -> Result<i64, ClockError> {
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[0] = kind;
+    msg[1..].copy_from_slice(&arg.to_le_bytes());
+
+    let mut reply = [0u8; REPLY_SIZE];
+    Send(clock_server, &msg, &mut reply)?;
+    match i64::from_le_bytes(reply) {
+        NEGATIVE_DELAY => Err(ClockError::NegativeDelay),
+        time => Ok(time),
+    }
+}
+
+/// Ticks since the clock server started.
+#[allow(non_snake_case)]
+pub fn Time(clock_server: Tid) -> Result<i64, ClockError> {
+    request(clock_server, REQUEST_TIME, 0)
+}
+
+/// Sleep for `ticks` 10ms ticks. Returns the time on waking up.
+#[allow(non_snake_case)]
+pub fn Delay(clock_server: Tid, ticks: i64) -> Result<i64, ClockError> {
+    request(clock_server, REQUEST_DELAY, ticks)
+}
+
+/// Sleep until the clock reaches `tick`. Returns the time on waking up.
+///
+/// Periodic tasks should use this with a running deadline so they don't drift.
+#[allow(non_snake_case)]
+pub fn DelayUntil(clock_server: Tid, tick: i64) -> Result<i64, ClockError> {
+    request(clock_server, REQUEST_DELAY_UNTIL, tick)
+}
+
+fn clock_notifier() -> ! {
+    let clock_server = MyParentTid().unwrap();
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[0] = REQUEST_TICK;
+
+    loop {
+        AwaitEvent(EVENT_TIMER).unwrap();
+        Send(clock_server, &msg, &mut []).unwrap();
+    }
+}
+
+/// Counts timer ticks and wakes sleeping tasks in deadline order.
+pub fn clock_server() -> ! {
+    RegisterAs(CLOCK_SERVER_NAME).unwrap();
+    Create(CLOCK_NOTIFIER_PRIORITY, clock_notifier).unwrap();
+
+    let mut ticks: i64 = 0;
+    let mut sleepers: BinaryHeap<Sleeper, Min, MAX_SLEEPERS> = BinaryHeap::new();
+
+    loop {
+        let mut msg = [0u8; REQUEST_SIZE];
+        let (tid, _) = Receive(&mut msg);
+        let arg = i64::from_le_bytes(msg[1..].try_into().unwrap());
+
+        let until = match msg[0] {
+            REQUEST_TICK => {
+                reply(tid, &[]);
+                ticks += 1;
+                while sleepers
+                    .peek()
+                    .is_some_and(|sleeper| sleeper.until <= ticks)
+                {
+                    let sleeper = sleepers.pop().unwrap();
+                    reply(sleeper.tid, &ticks.to_le_bytes());
+                }
+                continue;
+            }
+            REQUEST_TIME => {
+                reply(tid, &ticks.to_le_bytes());
+                continue;
+            }
+            REQUEST_DELAY if arg < 0 => {
+                reply(tid, &NEGATIVE_DELAY.to_le_bytes());
+                continue;
+            }
+            REQUEST_DELAY => ticks + arg,
+            REQUEST_DELAY_UNTIL => arg,
+            _ => {
+                reply(tid, &[]);
+                continue;
+            }
+        };
+
+        if until <= ticks {
+            reply(tid, &ticks.to_le_bytes());
+        } else {
+            sleepers.push(Sleeper { until, tid }).unwrap();
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use crate::name_server::RegisterAs;
+use crate::syscall::{
+    reply, request, rx_event, tx_event, AwaitEvent, CreateEx, MyParentTid, Receive, Send,
+    ServerError, SyscallError, TaskOptions, Tid, Write, LINE_COUNT, LINE_MARKLIN, STATUS_OK,
+};
+use heapless::{Deque, Vec};
+
+pub const CONSOLE_SERVER_NAME: &str = "console";
+pub const MARKLIN_SERVER_NAME: &str = "marklin";
+pub const IO_SERVER_PRIORITY: usize = 32;
+/// The server keeps its rings on the stack
+pub const IO_SERVER_STACK_SIZE: usize = 0x4000;
+const IO_NOTIFIER_PRIORITY: usize = 33;
+
+/// Output waiting for room in the transmit FIFO
+const OUTPUT_SIZE: usize = 2048;
+/// Input nobody has asked for yet
+const INPUT_SIZE: usize = 256;
+const MAX_READERS: usize = 16;
+/// Writers held back until the output ring has room for them
+const MAX_WRITERS: usize = 16;
+/// Puts sends longer strings in pieces of this many bytes
+pub const MAX_PUTS: usize = 64;
+
+const REQUEST_GETC: u8 = 0;
+const REQUEST_PUTS: u8 = 1;
+const NOTIFY_RX: u8 = 2;
+const NOTIFY_TX: u8 = 3;
+
+/// One byte of request kind and one of line, followed by the bytes to put
+const REQUEST_SIZE: usize = 2 + MAX_PUTS;
+/// One byte of status followed by the byte read
+const REPLY_SIZE: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoError {
+    /// The server drives a different line
+    WrongLine,
+    /// Too many tasks are already waiting on the server
+    Busy,
+    /// The server couldn't be reached
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for IoError {
+    fn from(err: SyscallError) -> Self {
+        Self::Syscall(err)
+    }
+}
+
+impl ServerError for IoError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::WrongLine => 1,
+            Self::Busy | Self::Syscall(_) => 2,
+        }
+    }
+
+    fn from_status(status: u8) -> Self {
+        match status {
+            1 => Self::WrongLine,
+            _ => Self::Busy,
+        }
+    }
+}
+
+/// The name the server for `line` registers as
+pub fn server_name(line: u64) -> &'static str {
+    if line == LINE_MARKLIN {
+        MARKLIN_SERVER_NAME
+    } else {
+        CONSOLE_SERVER_NAME
+    }
+}
+
+fn line_byte(line: u64) -> Result<u8, IoError> {
+    u8::try_from(line).map_err(|_| IoError::WrongLine)
+}
+
+/// Wait for the next byte to arrive on `line`.
+#[allow(non_snake_case)]
+pub fn Getc(server: Tid, line: u64) -> Result<u8, IoError> {
+    let mut reply = [0u8; REPLY_SIZE];
+    request::<IoError>(server, &[REQUEST_GETC, line_byte(line)?], &mut reply)?;
+    Ok(reply[1])
+}
+
+/// Queue `ch` to go out on `line`. Only waits while the server's output ring is full.
+#[allow(non_snake_case)]
+pub fn Putc(server: Tid, line: u64, ch: u8) -> Result<(), IoError> {
+    Puts(server, line, &[ch])
+}
+
+/// Queue `bytes` to go out on `line`, without anything from other tasks landing in between as long
+/// as it is at most 64 bytes.
+#[allow(non_snake_case)]
+pub fn Puts(server: Tid, line: u64, bytes: &[u8]) -> Result<(), IoError> {
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[0] = REQUEST_PUTS;
+    msg[1] = line_byte(line)?;
+    for chunk in bytes.chunks(MAX_PUTS) {
+        msg[2..2 + chunk.len()].copy_from_slice(chunk);
+        request::<IoError>(server, &msg[..2 + chunk.len()], &mut [0u8; REPLY_SIZE])?;
+    }
+    Ok(())
+}
+
+fn rx_notifier(line: usize) -> ! {
+    let server = MyParentTid().unwrap();
+    loop {
+        let ch = AwaitEvent(rx_event(line as u64)).unwrap();
+        Send(server, &[NOTIFY_RX, line as u8, ch as u8], &mut []).unwrap();
+    }
+}
+
+fn tx_notifier(line: usize) -> ! {
+    let server = MyParentTid().unwrap();
+    loop {
+        AwaitEvent(tx_event(line as u64)).unwrap();
+        Send(server, &[NOTIFY_TX, line as u8], &mut []).unwrap();
+    }
+}
+
+struct Output {
+    line: u64,
+    ring: Deque<u8, OUTPUT_SIZE>,
+    writers: Deque<(Tid, Vec<u8, MAX_PUTS>), MAX_WRITERS>,
+    /// The TX notifier, parked here while the FIFO had room but there was nothing to send
+    tx_ready: Option<Tid>,
+}
+
+impl Output {
+    fn has_room(&self, len: usize) -> bool {
+        self.ring.capacity() - self.ring.len() >= len
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &ch in bytes {
+            let _ = self.ring.push_back(ch);
+        }
+    }
+
+    /// Take `bytes` from `tid`, holding the writer back if the ring is full
+    fn put(&mut self, tid: Tid, bytes: &[u8]) {
+        if self.writers.is_empty() && self.has_room(bytes.len()) {
+            self.push(bytes);
+            reply(tid, &[STATUS_OK, 0]);
+        } else if self
+            .writers
+            .push_back((tid, Vec::from_slice(bytes).unwrap()))
+            .is_err()
+        {
+            reply(tid, &[IoError::Busy.status(), 0]);
+        }
+    }
+
+    /// Hand the FIFO as much as it takes, then let held back writers in
+    fn transmit(&mut self) {
+        if !self.ring.is_empty() {
+            if let Some(notifier) = self.tx_ready.take() {
+                let written = Write(self.line, self.ring.as_slices().0).unwrap();
+                for _ in 0..written {
+                    self.ring.pop_front();
+                }
+                // it comes back once the FIFO has room again
+                reply(notifier, &[]);
+            }
+        }
+
+        while let Some((_, bytes)) = self.writers.front() {
+            if !self.has_room(bytes.len()) {
+                break;
+            }
+            let (tid, bytes) = self.writers.pop_front().unwrap();
+            self.push(&bytes);
+            reply(tid, &[STATUS_OK, 0]);
+        }
+    }
+}
+
+/// Buffers input and output for one UART line, started with the line number as its argument.
+///
+/// Notifiers wait on the line's interrupts, so nobody ever spins on the UART.
+pub fn io_server(line: usize) -> ! {
+    assert!(line < LINE_COUNT, "no UART line {}", line);
+    RegisterAs(server_name(line as u64)).unwrap();
+    for (func, name) in [
+        (rx_notifier as fn(usize) -> !, "io rx notifier"),
+        (tx_notifier, "io tx notifier"),
+    ] {
+        CreateEx(&TaskOptions {
+            priority: IO_NOTIFIER_PRIORITY,
+            func,
+            stack_size: 0x1000,
+            arg: line,
+            name,
+        })
+        .unwrap();
+    }
+
+    let mut output = Output {
+        line: line as u64,
+        ring: Deque::new(),
+        writers: Deque::new(),
+        tx_ready: None,
+    };
+    let mut input: Deque<u8, INPUT_SIZE> = Deque::new();
+    let mut readers: Deque<Tid, MAX_READERS> = Deque::new();
+
+    loop {
+        let mut msg = [0u8; REQUEST_SIZE];
+        let (tid, len) = Receive(&mut msg);
+        let len = len.unwrap_or(REQUEST_SIZE);
+        if len < 2 {
+            reply(tid, &[IoError::Busy.status(), 0]);
+            continue;
+        }
+
+        match msg[0] {
+            NOTIFY_RX => {
+                reply(tid, &[]);
+                if let Some(reader) = readers.pop_front() {
+                    reply(reader, &[STATUS_OK, msg[2]]);
+                } else {
+                    // typed faster than anyone reads, so it is lost
+                    let _ = input.push_back(msg[2]);
+                }
+            }
+            NOTIFY_TX => {
+                output.tx_ready = Some(tid);
+                output.transmit();
+            }
+            _ if msg[1] as usize != line => reply(tid, &[IoError::WrongLine.status(), 0]),
+            REQUEST_GETC => {
+                if let Some(ch) = input.pop_front() {
+                    reply(tid, &[STATUS_OK, ch]);
+                } else if readers.push_back(tid).is_err() {
+                    reply(tid, &[IoError::Busy.status(), 0]);
+                }
+            }
+            REQUEST_PUTS => {
+                output.put(tid, &msg[2..len]);
+                output.transmit();
+            }
+            _ => reply(tid, &[IoError::Busy.status(), 0]),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod calibration;
+pub mod clock;
+pub mod io;
+pub mod marklin;
+pub mod name_server;
+#[cfg(not(test))]
+mod panic;
+pub mod print;
+pub mod reservation;
+pub mod screen;
+pub mod syscall;
+pub mod track;
+pub mod tracking;
+
+/// What `build.rs` reads `tracks/` with, included here so its tests run with the crate's
+#[cfg(test)]
+#[path = "../build/track_file.rs"]
+mod track_file;
+
+/// Marks the function the kernel starts as the first user task. It must be `fn() -> !`.
+pub use lunaris_user_macros::entry;
@@ -0,0 +1,475 @@
+use crate::clock::{Delay, CLOCK_SERVER_NAME};
+use crate::io::{Getc, Puts, MARKLIN_SERVER_NAME};
+use crate::name_server::{RegisterAs, WhoIs};
+use crate::syscall::{
+    reply, reply_status, request, Create, CreateEx, Exit, MyParentTid, Receive, Send, ServerError,
+    SyscallError, TaskOptions, Tid, LINE_MARKLIN, STATUS_OK,
+};
+use core::fmt;
+use heapless::Deque;
+
+pub const TRAIN_SERVER_NAME: &str = "train";
+pub const TRAIN_SERVER_PRIORITY: usize = 28;
+const TRAIN_COURIER_PRIORITY: usize = 29;
+
+pub const MAX_TRAIN: u8 = 80;
+pub const MAX_SPEED: u8 = 14;
+pub const SENSOR_BANKS: usize = 5;
+pub const SENSORS_PER_BANK: usize = 16;
+pub const SENSOR_COUNT: usize = SENSOR_BANKS * SENSORS_PER_BANK;
+/// Two bytes per bank, sensor 1 in the top bit of the first
+pub const SENSOR_REPORT_SIZE: usize = SENSOR_BANKS * 2;
+
+// Märklin Digital 6051 interface command bytes. Speeds and switches are followed by the train or
+// switch number.
+/// Added to a speed to turn the headlights on
+pub const LIGHTS: u8 = 16;
+/// Sent as a speed, flips the direction of a train that has stopped
+pub const SPEED_REVERSE: u8 = 15;
+pub const SOLENOID_OFF: u8 = 0x20;
+pub const SWITCH_STRAIGHT: u8 = 0x21;
+pub const SWITCH_CURVED: u8 = 0x22;
+pub const GO: u8 = 0x60;
+pub const STOP: u8 = 0x61;
+/// Plus a number of banks, dumps banks A onwards. On its own, turns reset mode off.
+pub const DUMP_BANKS: u8 = 0x80;
+/// Plus a bank number from 1, dumps just that bank. On its own, turns reset mode on.
+pub const DUMP_BANK: u8 = 0xc0;
+const DUMP_SENSORS: u8 = DUMP_BANKS + SENSOR_BANKS as u8;
+/// Clear the sensor banks after every dump, so each report only holds new triggers
+const RESET_MODE_ON: u8 = DUMP_BANK;
+
+// The box has no way to tell us it is busy without CTS, so we keep to its timing ourselves. All of
+// these are 10ms ticks.
+/// Between any two commands
+const COMMAND_GAP_TICKS: i64 = 6;
+/// Between throwing a switch and turning its solenoid off, long enough for the blade to move
+const SWITCH_GAP_TICKS: i64 = 15;
+/// Between stopping a train and reversing it, long enough to stop from full speed
+pub const REVERSE_STOP_TICKS: i64 = 350;
+
+const REQUEST_SPEED: u8 = 0;
+const REQUEST_REVERSE: u8 = 1;
+const REQUEST_SWITCH: u8 = 2;
+const REQUEST_GO: u8 = 3;
+const REQUEST_STOP: u8 = 4;
+const REQUEST_SENSORS: u8 = 5;
+/// From the courier, with whatever the last command read back
+const REQUEST_COURIER: u8 = 6;
+/// From a reverser, once its train has had time to stop
+const REQUEST_REVERSED: u8 = 7;
+
+/// One byte of request kind and two of arguments, or a length and a sensor report from the courier
+const REQUEST_SIZE: usize = 2 + SENSOR_REPORT_SIZE;
+/// One byte of status followed by the sensor report
+const REPLY_SIZE: usize = 1 + SENSOR_REPORT_SIZE;
+/// Length, two command bytes, ticks to wait afterwards and how many bytes to read back
+const COMMAND_SIZE: usize = 5;
+
+/// Commands waiting for the courier
+const MAX_COMMANDS: usize = 64;
+const MAX_SENSOR_WAITERS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrainError {
+    /// Trains are numbered 1 to 80
+    InvalidTrain,
+    /// Speeds go from 0 to 14
+    InvalidSpeed,
+    /// Switches are numbered 1 to 255
+    InvalidSwitch,
+    /// The command queue is full, or the train is already reversing
+    Busy,
+    /// The train server couldn't be reached
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for TrainError {
+    fn from(err: SyscallError) -> Self {
+        Self::Syscall(err)
+    }
+}
+
+impl ServerError for TrainError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::InvalidTrain => 1,
+            Self::InvalidSpeed => 2,
+            Self::InvalidSwitch => 3,
+            Self::Busy | Self::Syscall(_) => 4,
+        }
+    }
+
+    fn from_status(status: u8) -> Self {
+        match status {
+            1 => Self::InvalidTrain,
+            2 => Self::InvalidSpeed,
+            3 => Self::InvalidSwitch,
+            _ => Self::Busy,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchDirection {
+    Straight,
+    Curved,
+}
+
+/// A sensor named by bank letter and number, like `C13`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Sensor {
+    /// 0 for bank A
+    pub bank: u8,
+    /// 1 to 16
+    pub number: u8,
+}
+
+impl Sensor {
+    /// Where the sensor falls among all 80, A1 first
+    pub fn index(&self) -> usize {
+        self.bank as usize * SENSORS_PER_BANK + self.number as usize - 1
+    }
+
+    pub fn from_index(index: usize) -> Option<Self> {
+        (index < SENSOR_COUNT).then(|| Self {
+            bank: (index / SENSORS_PER_BANK) as u8,
+            number: (index % SENSORS_PER_BANK) as u8 + 1,
+        })
+    }
+
+    /// Read a name like `C13`
+    pub fn parse(name: &str) -> Option<Self> {
+        let (bank, number) = name.split_at_checked(1)?;
+        let bank = bank.as_bytes()[0].checked_sub(b'A')?;
+        let number: u8 = number.parse().ok()?;
+        let valid =
+            (bank as usize) < SENSOR_BANKS && (1..=SENSORS_PER_BANK as u8).contains(&number);
+        valid.then_some(Self { bank, number })
+    }
+}
+
+impl fmt::Display for Sensor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", (b'A' + self.bank) as char, self.number)
+    }
+}
+
+/// The sensors that triggered since the last dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SensorReport([u8; SENSOR_REPORT_SIZE]);
+
+impl SensorReport {
+    pub fn from_bytes(bytes: [u8; SENSOR_REPORT_SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    /// As the box sends it
+    pub fn as_bytes(&self) -> &[u8; SENSOR_REPORT_SIZE] {
+        &self.0
+    }
+
+    pub fn is_triggered(&self, sensor: Sensor) -> bool {
+        let index = sensor.index();
+        self.0[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn triggered(&self) -> impl Iterator<Item = Sensor> + '_ {
+        (0..SENSOR_COUNT)
+            .filter_map(Sensor::from_index)
+            .filter(|sensor| self.is_triggered(*sensor))
+    }
+}
+
+/// Send `msg` to the train server, for requests that only get a status back
+fn command(server: Tid, msg: &[u8]) -> Result<(), TrainError> {
+    request(server, msg, &mut [0u8; 1])
+}
+
+/// Run `train` at `speed`, from 0 to 14.
+#[allow(non_snake_case)]
+pub fn SetSpeed(server: Tid, train: u8, speed: u8, lights: bool) -> Result<(), TrainError> {
+    if speed > MAX_SPEED {
+        return Err(TrainError::InvalidSpeed);
+    }
+    let byte = speed + if lights { LIGHTS } else { 0 };
+    command(server, &[REQUEST_SPEED, train, byte])
+}
+
+/// Stop `train`, flip its direction once it has had time to stop, then bring it back to speed.
+/// Returns as soon as the stop is queued.
+#[allow(non_snake_case)]
+pub fn Reverse(server: Tid, train: u8) -> Result<(), TrainError> {
+    command(server, &[REQUEST_REVERSE, train])
+}
+
+/// Throw switch `switch`, turning the solenoid off again afterwards.
+#[allow(non_snake_case)]
+pub fn SetSwitch(server: Tid, switch: u8, direction: SwitchDirection) -> Result<(), TrainError> {
+    command(server, &[REQUEST_SWITCH, switch, direction as u8])
+}
+
+/// Turn power to the track on.
+#[allow(non_snake_case)]
+pub fn Go(server: Tid) -> Result<(), TrainError> {
+    command(server, &[REQUEST_GO])
+}
+
+/// Cut power to the track, stopping everything at once.
+#[allow(non_snake_case)]
+pub fn Stop(server: Tid) -> Result<(), TrainError> {
+    command(server, &[REQUEST_STOP])
+}
+
+/// Wait for the next sensor dump. Tasks asking at the same time share one.
+#[allow(non_snake_case)]
+pub fn DumpSensors(server: Tid) -> Result<SensorReport, TrainError> {
+    let mut reply = [0u8; REPLY_SIZE];
+    request::<TrainError>(server, &[REQUEST_SENSORS], &mut reply)?;
+    Ok(SensorReport(reply[1..].try_into().unwrap()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// `byte` holds the speed and lights
+    Speed {
+        train: u8,
+        byte: u8,
+    },
+    Switch {
+        switch: u8,
+        direction: SwitchDirection,
+    },
+    SolenoidOff,
+    Go,
+    Stop,
+    ResetModeOn,
+    DumpSensors,
+}
+
+impl Command {
+    /// What the courier needs to carry it out, see `COMMAND_SIZE`
+    fn encode(self) -> [u8; COMMAND_SIZE] {
+        let gap = COMMAND_GAP_TICKS as u8;
+        match self {
+            Command::Speed { train, byte } => [2, byte, train, gap, 0],
+            Command::Switch { switch, direction } => {
+                let byte = match direction {
+                    SwitchDirection::Straight => SWITCH_STRAIGHT,
+                    SwitchDirection::Curved => SWITCH_CURVED,
+                };
+                [2, byte, switch, SWITCH_GAP_TICKS as u8, 0]
+            }
+            Command::SolenoidOff => [1, SOLENOID_OFF, 0, gap, 0],
+            Command::Go => [1, GO, 0, gap, 0],
+            Command::Stop => [1, STOP, 0, gap, 0],
+            Command::ResetModeOn => [1, RESET_MODE_ON, 0, gap, 0],
+            Command::DumpSensors => [1, DUMP_SENSORS, 0, gap, SENSOR_REPORT_SIZE as u8],
+        }
+    }
+}
+
+/// Carries commands to the box one at a time, waiting out the gap the box needs after each
+fn train_courier() -> ! {
+    let server = MyParentTid().unwrap();
+    let clock = WhoIs(CLOCK_SERVER_NAME).unwrap();
+    let marklin = WhoIs(MARKLIN_SERVER_NAME).unwrap();
+
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[0] = REQUEST_COURIER;
+    let mut len = 2;
+    loop {
+        let mut command = [0u8; COMMAND_SIZE];
+        Send(server, &msg[..len], &mut command).unwrap();
+        let [bytes, first, second, gap, read] = command;
+
+        Puts(marklin, LINE_MARKLIN, &[first, second][..bytes as usize]).unwrap();
+        for byte in &mut msg[2..2 + read as usize] {
+            *byte = Getc(marklin, LINE_MARKLIN).unwrap();
+        }
+        msg[1] = read;
+        len = 2 + read as usize;
+
+        Delay(clock, gap as i64).unwrap();
+    }
+}
+
+/// Waits for a train to stop before it is reversed, without holding up the courier
+fn reverser(train: usize) -> ! {
+    let server = MyParentTid().unwrap();
+    let clock = WhoIs(CLOCK_SERVER_NAME).unwrap();
+    Delay(clock, REVERSE_STOP_TICKS).unwrap();
+    Send(server, &[REQUEST_REVERSED, train as u8], &mut []).unwrap();
+    Exit();
+}
+
+struct TrainServer {
+    commands: Deque<Command, MAX_COMMANDS>,
+    /// The courier, parked here while there was nothing to send
+    courier: Option<Tid>,
+    sensor_waiters: Deque<Tid, MAX_SENSOR_WAITERS>,
+    /// The last speed byte asked for, indexed by train
+    speeds: [u8; MAX_TRAIN as usize + 1],
+    reversing: [bool; MAX_TRAIN as usize + 1],
+}
+
+impl TrainServer {
+    fn has_room(&self, commands: usize) -> bool {
+        self.commands.capacity() - self.commands.len() >= commands
+    }
+
+    fn queue(&mut self, command: Command) -> Result<(), TrainError> {
+        self.commands
+            .push_back(command)
+            .map_err(|_| TrainError::Busy)
+    }
+
+    fn queue_switch(&mut self, switch: u8, direction: SwitchDirection) -> Result<(), TrainError> {
+        if !self.has_room(2) {
+            return Err(TrainError::Busy);
+        }
+        // switches thrown back to back share one solenoid off
+        if self.commands.back() == Some(&Command::SolenoidOff) {
+            self.commands.pop_back();
+        }
+        let _ = self
+            .commands
+            .push_back(Command::Switch { switch, direction });
+        let _ = self.commands.push_back(Command::SolenoidOff);
+        Ok(())
+    }
+
+    fn set_speed(&mut self, train: u8, byte: u8) -> Result<(), TrainError> {
+        if byte & !LIGHTS > MAX_SPEED {
+            return Err(TrainError::InvalidSpeed);
+        }
+        self.speeds[train as usize] = byte;
+        if self.reversing[train as usize] {
+            // applied once the reverse is done, so the train doesn't set off the old way
+            return Ok(());
+        }
+        self.queue(Command::Speed { train, byte })
+    }
+
+    fn reverse(&mut self, train: u8) -> Result<(), TrainError> {
+        if self.reversing[train as usize] || !self.has_room(1) {
+            return Err(TrainError::Busy);
+        }
+        let created = CreateEx(&TaskOptions {
+            priority: TRAIN_COURIER_PRIORITY,
+            func: reverser,
+            stack_size: 0x1000,
+            arg: train as usize,
+            name: "reverser",
+        });
+        if created.is_err() {
+            return Err(TrainError::Busy);
+        }
+
+        self.reversing[train as usize] = true;
+        let byte = self.speeds[train as usize] & LIGHTS;
+        self.queue(Command::Speed { train, byte })
+    }
+
+    fn reversed(&mut self, train: u8) {
+        self.reversing[train as usize] = false;
+        let lights = self.speeds[train as usize] & LIGHTS;
+        let speed = self.speeds[train as usize];
+        // with no room the train is left stopped, which is the safe way to lose it
+        let _ = self.queue(Command::Speed {
+            train,
+            byte: SPEED_REVERSE + lights,
+        });
+        let _ = self.queue(Command::Speed { train, byte: speed });
+    }
+
+    fn dump_sensors(&mut self, tid: Tid) {
+        if self.sensor_waiters.push_back(tid).is_err() {
+            reply_status(tid, Err(TrainError::Busy));
+            return;
+        }
+        if !self
+            .commands
+            .iter()
+            .any(|command| *command == Command::DumpSensors)
+        {
+            let _ = self.queue(Command::DumpSensors);
+        }
+    }
+
+    fn report(&mut self, report: &[u8]) {
+        let mut msg = [0u8; REPLY_SIZE];
+        msg[0] = STATUS_OK;
+        msg[1..].copy_from_slice(report);
+        while let Some(tid) = self.sensor_waiters.pop_front() {
+            reply(tid, &msg);
+        }
+    }
+
+    /// Give the courier the next command, if both are there
+    fn dispatch(&mut self) {
+        if let Some(courier) = self.courier {
+            if let Some(command) = self.commands.pop_front() {
+                self.courier = None;
+                reply(courier, &command.encode());
+            }
+        }
+    }
+}
+
+/// Queues commands for the Märklin box and keeps to its timing. Start it after the clock server
+/// and the Märklin I/O server.
+pub fn train_server() -> ! {
+    RegisterAs(TRAIN_SERVER_NAME).unwrap();
+    Create(TRAIN_COURIER_PRIORITY, train_courier).unwrap();
+
+    let mut server = TrainServer {
+        commands: Deque::new(),
+        courier: None,
+        sensor_waiters: Deque::new(),
+        speeds: [0; MAX_TRAIN as usize + 1],
+        reversing: [false; MAX_TRAIN as usize + 1],
+    };
+    let _ = server.queue(Command::ResetModeOn);
+
+    loop {
+        let mut msg = [0u8; REQUEST_SIZE];
+        let (tid, _) = Receive(&mut msg);
+        let [kind, first, second, ..] = msg;
+        let valid_train = (1..=MAX_TRAIN).contains(&first);
+
+        match kind {
+            REQUEST_COURIER => {
+                if first as usize == SENSOR_REPORT_SIZE {
+                    server.report(&msg[2..]);
+                }
+                server.courier = Some(tid);
+            }
+            REQUEST_REVERSED => {
+                reply(tid, &[]);
+                if valid_train {
+                    server.reversed(first);
+                }
+            }
+            REQUEST_SENSORS => server.dump_sensors(tid),
+            REQUEST_SPEED | REQUEST_REVERSE if !valid_train => {
+                reply_status(tid, Err(TrainError::InvalidTrain))
+            }
+            REQUEST_SPEED => reply_status(tid, server.set_speed(first, second)),
+            REQUEST_REVERSE => reply_status(tid, server.reverse(first)),
+            REQUEST_SWITCH if first == 0 => reply_status(tid, Err(TrainError::InvalidSwitch)),
+            REQUEST_SWITCH => {
+                let direction = if second == SwitchDirection::Curved as u8 {
+                    SwitchDirection::Curved
+                } else {
+                    SwitchDirection::Straight
+                };
+                reply_status(tid, server.queue_switch(first, direction));
+            }
+            REQUEST_GO => reply_status(tid, server.queue(Command::Go)),
+            REQUEST_STOP => reply_status(tid, server.queue(Command::Stop)),
+            _ => reply_status(tid, Err(TrainError::Busy)),
+        }
+        server.dispatch();
+    }
+}
@@ -0,0 +1,120 @@
+use crate::syscall::{Receive, Reply, Send, SyscallError, Tid};
+use heapless::{index_map::FnvIndexMap, String};
+
+/// The name server is the first task `_kmain` creates, so it gets the first slot
+pub const NAME_SERVER_TID: Tid = 0;
+pub const NAME_SERVER_PRIORITY: usize = 40;
+/// The name table alone takes about 3KiB
+pub const NAME_SERVER_STACK_SIZE: usize = 0x2000;
+const MAX_NAME_LEN: usize = 32;
+/// Must be a power of two
+const MAX_NAMES: usize = 64;
+
+/// Sent back in place of a TID when the name was too long or the server was full
+const NAME_TOO_LONG: Tid = -2;
+const NAME_SERVER_FULL: Tid = -3;
+
+const REQUEST_REGISTER_AS: u8 = 0;
+const REQUEST_WHO_IS: u8 = 1;
+
+/// One byte of request kind followed by the name
+const REQUEST_SIZE: usize = 1 + MAX_NAME_LEN;
+/// Whether the name was found, followed by the little endian TID or error
+const REPLY_SIZE: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameServerError {
+    /// The name is longer than `MAX_NAME_LEN` bytes
+    NameTooLong,
+    /// The name server has no room for another name
+    Full,
+    /// The name server couldn't be reached
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for NameServerError {
+    fn from(err: SyscallError) -> Self {
+        Self::Syscall(err)
+    }
+}
+
+fn reply(tid: Tid, found: bool, value: Tid) {
+    let mut reply = [0u8; REPLY_SIZE];
+    reply[0] = found as u8;
+    reply[1..].copy_from_slice(&value.to_le_bytes());
+    // a sender that has gone away no longer needs an answer
+    let _ = Reply(tid, &reply);
+}
+
+fn request(kind: u8, name: &str, reply: &mut [u8; REPLY_SIZE]) -> Result<(), NameServerError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(NameServerError::NameTooLong);
+    }
+
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[0] = kind;
+    msg[1..=name.len()].copy_from_slice(name.as_bytes());
+    Send(NAME_SERVER_TID, &msg[..=name.len()], reply)?;
+    Ok(())
+}
+
+/// Make the calling task findable as `name`, replacing whoever had it before.
+#[allow(non_snake_case)]
+pub fn RegisterAs(name: &str) -> Result<(), NameServerError> {
+    let mut reply = [0u8; REPLY_SIZE];
+    request(REQUEST_REGISTER_AS, name, &mut reply)?;
+    match Tid::from_le_bytes(reply[1..].try_into().unwrap()) {
+        NAME_TOO_LONG => Err(NameServerError::NameTooLong),
+        NAME_SERVER_FULL => Err(NameServerError::Full),
+        _ => Ok(()),
+    }
+}
+
+/// The task most recently registered as `name`.
+#[allow(non_snake_case)]
+pub fn WhoIs(name: &str) -> Option<Tid> {
+    let mut reply = [0u8; REPLY_SIZE];
+    if request(REQUEST_WHO_IS, name, &mut reply).is_err() || reply[0] == 0 {
+        None
+    } else {
+        Some(Tid::from_le_bytes(reply[1..].try_into().unwrap()))
+    }
+}
+
+/// Maps task names to TIDs, so tasks don't have to pass TIDs around by hand.
+pub fn name_server() -> ! {
+    let mut names: FnvIndexMap<String<MAX_NAME_LEN>, Tid, MAX_NAMES> = FnvIndexMap::new();
+
+    loop {
+        let mut msg = [0u8; REQUEST_SIZE];
+        let (tid, len) = Receive(&mut msg);
+        let Some(len) = len.ok().filter(|&len| len >= 1) else {
+            reply(tid, false, NAME_TOO_LONG);
+            continue;
+        };
+
+        let name = core::str::from_utf8(&msg[1..len])
+            .ok()
+            .and_then(|name| String::try_from(name).ok());
+        let Some(name) = name else {
+            reply(tid, false, NAME_TOO_LONG);
+            continue;
+        };
+
+        match msg[0] {
+            REQUEST_REGISTER_AS => {
+                let ret = if names.insert(name, tid).is_ok() {
+                    0
+                } else {
+                    NAME_SERVER_FULL
+                };
+                reply(tid, true, ret);
+            }
+            REQUEST_WHO_IS => match names.get(&name) {
+                Some(found) => reply(tid, true, *found),
+                None => reply(tid, false, 0),
+            },
+            _ => reply(tid, false, 0),
+        }
+    }
+}
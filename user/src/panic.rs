@@ -0,0 +1,27 @@
+use crate::syscall::{Exit, Print, Yield};
+use core::{fmt::Write, panic::PanicInfo};
+use heapless::String;
+
+/// Room for the location and a line or two of message, anything past that is cut off
+const PANIC_MESSAGE_SIZE: usize = 256;
+
+/// Report the panic through the kernel's log and exit. The console server is no use here, since it
+/// may be what panicked or be waiting on the task that did.
+///
+/// The kernel is linked into the same image and panics through here too. Its `Print` is then taken
+/// from EL1, which the kernel treats as its own panic and never returns from.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut msg: String<PANIC_MESSAGE_SIZE> = String::new();
+    let _ = write!(msg, "{}\r\n", info);
+
+    let mut rest = msg.as_bytes();
+    while !rest.is_empty() {
+        match Print(rest) {
+            Ok(0) => Yield(),
+            Ok(printed) => rest = &rest[printed..],
+            Err(_) => break,
+        }
+    }
+    Exit();
+}
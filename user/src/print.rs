@@ -0,0 +1,84 @@
+use crate::io::{IoError, Puts, CONSOLE_SERVER_NAME, MAX_PUTS};
+use crate::name_server::WhoIs;
+use crate::syscall::{Print, Tid, Yield, LINE_CONSOLE};
+use core::fmt;
+use heapless::Vec;
+
+/// The console as seen from EL0, for `print!` and `println!`.
+///
+/// Output is gathered into pieces `Puts` keeps whole and handed to the console server, which holds
+/// the caller up while its ring is full. Until the server is up, or while it has too many writers
+/// waiting, it goes through the kernel's `Print` instead. The name and console servers must not
+/// print, as they would wait on themselves.
+pub struct Stdout {
+    console: Option<Tid>,
+    pending: Vec<u8, MAX_PUTS>,
+}
+
+impl Stdout {
+    /// Look up the console server, once for everything written through this
+    pub fn open() -> Self {
+        Self {
+            console: WhoIs(CONSOLE_SERVER_NAME),
+            pending: Vec::new(),
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut rest = &self.pending[..];
+        while !rest.is_empty() {
+            match self
+                .console
+                .map(|console| Puts(console, LINE_CONSOLE, rest))
+            {
+                Some(Ok(())) => break,
+                // too many writers are queued on the server already, try again once it has room
+                Some(Err(IoError::Busy)) | None => {}
+                Some(Err(_)) => self.console = None,
+            }
+            match Print(rest) {
+                // neither the server nor the kernel's log can take any more, let them drain
+                Ok(0) => Yield(),
+                Ok(printed) => rest = &rest[printed..],
+                Err(_) => break,
+            }
+        }
+        self.pending.clear();
+    }
+}
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &ch in s.as_bytes() {
+            if self.pending.is_full() {
+                self.flush();
+            }
+            let _ = self.pending.push(ch);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Stdout {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {{
+            use core::fmt::Write;
+            let _ = write!($crate::print::Stdout::open(), $($arg)*);
+    }};
+}
+
+#[macro_export]
+macro_rules! println {
+    ($($arg:tt)*) => {{
+            use core::fmt::Write;
+            let mut stdout = $crate::print::Stdout::open();
+            let _ = writeln!(stdout, $($arg)*);
+            let _ = write!(stdout, "\r");
+    }};
+}
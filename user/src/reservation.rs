@@ -0,0 +1,627 @@
+use crate::calibration::{
+    CalibrationError, Drive, StopAt, StoppingDistance, Throw, CALIBRATION_SERVER_NAME,
+    MAX_MODELLED_TRAINS,
+};
+use crate::clock::{Delay, CLOCK_SERVER_NAME};
+use crate::io::CONSOLE_SERVER_NAME;
+use crate::marklin::{SwitchDirection, TrainError, MAX_SPEED, MAX_TRAIN};
+use crate::name_server::{RegisterAs, WhoIs};
+use crate::screen::{Panel, ROW_SIZE};
+use crate::syscall::{
+    reply, reply_status, request, Create, MyParentTid, Receive, Send, ServerError, SyscallError,
+    Tid,
+};
+use crate::track::{NodeId, NodeKind, Position, Route, Step, Track, DIR_CURVED, MAX_NODES, TRACKS};
+use crate::tracking::{Locate, TrackTrain, TRACKING_SERVER_NAME};
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+pub const RESERVATION_SERVER_NAME: &str = "reservation";
+/// Below the tracking and calibration servers, which it sends to
+pub const RESERVATION_SERVER_PRIORITY: usize = 24;
+/// Claims and routes are worked out on the stack
+pub const RESERVATION_SERVER_STACK_SIZE: usize = 0x4000;
+const RESERVATION_TICKER_PRIORITY: usize = 27;
+/// How often claims are brought up to date
+const RESERVATION_PERIOD_TICKS: i64 = 10;
+
+/// Claimed past the stopping distance, for the model being off and the train moving on before
+/// the next update
+const MARGIN_MM: u32 = 250;
+/// Held behind the front of a train, for the rest of it
+const TRAIN_LENGTH_MM: u32 = 250;
+/// What a route is charged for each piece of held track it crosses, if it isn't kept off them
+pub const RESERVED_PENALTY: u32 = 5000;
+/// A title, then a row for each train in the order they were first run
+const PANEL_ROWS: usize = 1 + MAX_MODELLED_TRAINS;
+
+const REQUEST_RUN: u8 = 0;
+const REQUEST_RESERVED: u8 = 1;
+/// From the ticker, to update every claim
+const REQUEST_TICK: u8 = 2;
+const REQUEST_GOTO: u8 = 3;
+
+/// Request kind, train and speed, then a little endian node and offset to go to
+const REQUEST_SIZE: usize = 3 + 2 + 4;
+/// One byte of status followed by the owner of every node
+const REPLY_SIZE: usize = 1 + MAX_NODES;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationError {
+    /// Trains are numbered 1 to 80
+    InvalidTrain,
+    /// Speeds go from 0 to 14
+    InvalidSpeed,
+    /// The train hasn't been run and seen at a sensor yet
+    NotLocated,
+    /// There is no way from the train to where it was sent, or it won't stop there
+    Unreachable,
+    /// Every train slot is taken, or the train couldn't be driven
+    Busy,
+    /// The reservation server couldn't be reached
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for ReservationError {
+    fn from(err: SyscallError) -> Self {
+        Self::Syscall(err)
+    }
+}
+
+impl From<CalibrationError> for ReservationError {
+    fn from(err: CalibrationError) -> Self {
+        match err {
+            CalibrationError::Train(TrainError::InvalidTrain) => Self::InvalidTrain,
+            CalibrationError::Train(TrainError::InvalidSpeed) => Self::InvalidSpeed,
+            CalibrationError::NotLocated => Self::NotLocated,
+            CalibrationError::Unreachable => Self::Unreachable,
+            _ => Self::Busy,
+        }
+    }
+}
+
+impl ServerError for ReservationError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::InvalidTrain => 1,
+            Self::InvalidSpeed => 2,
+            Self::NotLocated => 3,
+            Self::Unreachable => 4,
+            Self::Busy | Self::Syscall(_) => 5,
+        }
+    }
+
+    fn from_status(status: u8) -> Self {
+        match status {
+            1 => Self::InvalidTrain,
+            2 => Self::InvalidSpeed,
+            3 => Self::NotLocated,
+            4 => Self::Unreachable,
+            _ => Self::Busy,
+        }
+    }
+}
+
+/// Which train holds the track just past each node, and so the same place facing the other way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservations([u8; MAX_NODES]);
+
+impl Reservations {
+    pub fn owner(&self, node: NodeId) -> Option<u8> {
+        (self.0[node] != 0).then_some(self.0[node])
+    }
+
+    /// Whether anyone other than `train` holds the track past `node`
+    pub fn is_held_against(&self, node: NodeId, train: u8) -> bool {
+        self.owner(node).is_some_and(|owner| owner != train)
+    }
+}
+
+/// Run `train` at `speed` as long as it can hold the track it needs to stop in. It is stopped
+/// while another train holds any of it, and set off again at `speed` once that train moves on.
+#[allow(non_snake_case)]
+pub fn Run(server: Tid, train: u8, speed: u8) -> Result<(), ReservationError> {
+    request(server, &[REQUEST_RUN, train, speed], &mut [0u8; 1])
+}
+
+/// Run `train` at `speed` to a stop `offset_mm` past `node`, the way `route_around` finds, with
+/// its switches thrown ahead of it. A route that backs up stops the train past the first node it
+/// reverses at, to be turned around and sent on from there. The train has to have been `Run`
+/// and seen at a sensor first.
+#[allow(non_snake_case)]
+pub fn Goto(
+    server: Tid,
+    train: u8,
+    speed: u8,
+    node: NodeId,
+    offset_mm: u32,
+) -> Result<(), ReservationError> {
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[..3].copy_from_slice(&[REQUEST_GOTO, train, speed]);
+    msg[3..5].copy_from_slice(&(node as u16).to_le_bytes());
+    msg[5..].copy_from_slice(&offset_mm.to_le_bytes());
+    request(server, &msg, &mut [0u8; 1])
+}
+
+/// Who holds what right now.
+#[allow(non_snake_case)]
+pub fn Reserved(server: Tid) -> Result<Reservations, ReservationError> {
+    let mut reply = [0u8; REPLY_SIZE];
+    request::<ReservationError>(server, &[REQUEST_RESERVED], &mut reply)?;
+    Ok(Reservations(reply[1..].try_into().unwrap()))
+}
+
+/// Like `Track::route`, but keeping `train` off track other trains hold. With a `penalty` the
+/// route may still cross it, at that many extra mm for each piece.
+pub fn route_around(
+    track: &Track,
+    reservations: &Reservations,
+    train: u8,
+    from: NodeId,
+    to: NodeId,
+    penalty: Option<u32>,
+) -> Option<Route> {
+    track.route_with(from, to, |node, edge| {
+        let held = reservations.is_held_against(node, train)
+            || reservations.is_held_against(edge.dest, train);
+        match (held, penalty) {
+            (false, _) => Some(edge.dist),
+            (true, Some(penalty)) => Some(edge.dist + penalty),
+            (true, None) => None,
+        }
+    })
+}
+
+fn reservation_ticker() -> ! {
+    let server = MyParentTid().unwrap();
+    let clock = WhoIs(CLOCK_SERVER_NAME).unwrap();
+    loop {
+        Delay(clock, RESERVATION_PERIOD_TICKS).unwrap();
+        Send(server, &[REQUEST_TICK], &mut []).unwrap();
+    }
+}
+
+struct Claimant {
+    train: u8,
+    /// What the train was last asked to run at
+    speed: u8,
+    /// Stopped for want of track
+    blocked: bool,
+    /// What the train needed when it was stopped, so it only sets off again once all of that
+    /// is free rather than creeping up on the train in the way
+    held_reach: u32,
+    /// Where `Goto` is bringing the train to a stop
+    stop: Option<Position>,
+}
+
+impl Claimant {
+    /// The speed to drive the train at if it has to stop or can set off again
+    fn change(&self, blocked: bool) -> Option<u8> {
+        match (self.blocked, blocked) {
+            (false, true) => Some(0),
+            (true, false) => Some(self.speed),
+            _ => None,
+        }
+    }
+}
+
+/// Who holds which track
+struct Claims {
+    track: &'static Track,
+    owners: [u8; MAX_NODES],
+}
+
+impl Claims {
+    fn reservations(&self) -> Reservations {
+        Reservations(self.owners)
+    }
+
+    /// Add the nodes within `reach` mm past `pos` to `claim`. Switches can change under a train,
+    /// so both ways out of a branch are claimed.
+    fn claim_ahead(&self, pos: Position, reach: u32, claim: &mut Vec<NodeId, MAX_NODES>) {
+        let mut stack: Vec<(NodeId, u32), MAX_NODES> = Vec::new();
+        if !claim.contains(&pos.node) {
+            let _ = claim.push(pos.node);
+        }
+        let _ = stack.push((pos.node, reach + pos.offset));
+        while let Some((node, left)) = stack.pop() {
+            for edge in self.track.nodes[node].edges.iter().flatten() {
+                if edge.dist < left && !claim.contains(&edge.dest) {
+                    let _ = claim.push(edge.dest);
+                    let _ = stack.push((edge.dest, left - edge.dist));
+                }
+            }
+        }
+    }
+
+    /// What a train with its front at `pos` needs to stop within `reach` mm, and what the rest
+    /// of it covers
+    fn claim(&self, pos: Position, reach: u32) -> Vec<NodeId, MAX_NODES> {
+        let mut claim = Vec::new();
+        self.claim_ahead(pos, reach, &mut claim);
+        let behind = Position {
+            node: self.track.nodes[pos.node].reverse,
+            offset: 0,
+        };
+        self.claim_ahead(
+            behind,
+            TRAIN_LENGTH_MM.saturating_sub(pos.offset),
+            &mut claim,
+        );
+        claim
+    }
+
+    /// Have `train` hold `claim` and nothing else, short of what other trains hold. Returns
+    /// whether any of it was held against it.
+    fn take(&mut self, train: u8, claim: &[NodeId]) -> bool {
+        let mut blocked = false;
+        for node in 0..self.track.nodes.len() {
+            let wanted = claim.contains(&node) || claim.contains(&self.track.nodes[node].reverse);
+            let owner = self.owners[node];
+            if wanted && owner != 0 && owner != train {
+                blocked = true;
+            } else if wanted != (owner == train) {
+                self.owners[node] = if wanted { train } else { 0 };
+            }
+        }
+        blocked
+    }
+}
+
+struct ReservationServer {
+    claims: Claims,
+    calibration: Tid,
+    tracking: Tid,
+    trains: Vec<Claimant, MAX_MODELLED_TRAINS>,
+    panel: Panel<PANEL_ROWS>,
+}
+
+impl ReservationServer {
+    /// Bring train `i`'s row of the panel up to date. Only rows that change are redrawn.
+    fn show(&mut self, i: usize, claim: &[NodeId]) {
+        let claimant = &self.trains[i];
+        let mut row: String<ROW_SIZE> = String::new();
+        let state = if claimant.blocked {
+            "stopped, wants"
+        } else {
+            "holds"
+        };
+        let _ = write!(row, "Train {} {}", claimant.train, state);
+        for &node in claim {
+            if write!(row, " {}", self.claims.track.nodes[node].name).is_err() {
+                break;
+            }
+        }
+        // the display is only a view of the claims, which stand either way
+        let _ = self.panel.set(1 + i, &row);
+    }
+
+    /// Claim what `train` needs to stop in and let go of the rest, stopping it if the claim
+    /// runs into another train's
+    fn update(&mut self, i: usize) {
+        let train = self.trains[i].train;
+        let Ok(location) = Locate(self.tracking, train) else {
+            // until it is seen it keeps whatever it held
+            return;
+        };
+        let stopping = StoppingDistance(self.calibration, train).unwrap_or(0);
+        let reach = (stopping + MARGIN_MM).max(self.trains[i].held_reach);
+
+        let pos = location.position;
+        let claim = self.claims.claim(pos, reach);
+        let blocked = self.claims.take(train, &claim);
+
+        let claimant = &mut self.trains[i];
+        // once at its stop the train stays there, rather than being sent on round again
+        let arrived = stopping == 0 && claimant.stop.is_some_and(|stop| claim.contains(&stop.node));
+        if arrived && !claimant.blocked {
+            claimant.speed = 0;
+            claimant.stop = None;
+        }
+
+        if let Some(speed) = claimant.change(blocked) {
+            if Drive(self.calibration, train, speed).is_ok() {
+                claimant.blocked = blocked;
+                claimant.held_reach = if blocked { reach } else { 0 };
+                // setting off again drops the stop the calibration server had planned
+                if let Some(stop) = claimant.stop.filter(|_| !blocked) {
+                    let _ = StopAt(self.calibration, train, stop.node, stop.offset);
+                }
+            }
+        }
+        self.show(i, &claim);
+    }
+
+    /// Where `train` is in `trains`, adding it if it is new
+    fn claimant(&mut self, train: u8, speed: u8) -> Result<usize, ReservationError> {
+        if let Some(i) = self
+            .trains
+            .iter()
+            .position(|claimant| claimant.train == train)
+        {
+            return Ok(i);
+        }
+        let claimant = Claimant {
+            train,
+            speed,
+            blocked: false,
+            held_reach: 0,
+            stop: None,
+        };
+        if self.trains.push(claimant).is_err() {
+            return Err(ReservationError::Busy);
+        }
+        // the tracker finds it at the next sensor no other train accounts for
+        let _ = TrackTrain(self.tracking, train);
+        Ok(self.trains.len() - 1)
+    }
+
+    fn run(&mut self, train: u8, speed: u8) -> Result<(), ReservationError> {
+        let i = self.claimant(train, speed)?;
+        self.trains[i].speed = speed;
+        self.trains[i].stop = None;
+        if self.trains[i].blocked {
+            // it sets off once the track ahead is free
+            return Ok(());
+        }
+        Ok(Drive(self.calibration, train, speed)?)
+    }
+
+    fn goto(&mut self, train: u8, speed: u8, dest: Position) -> Result<(), ReservationError> {
+        let track = self.claims.track;
+        if dest.node >= track.nodes.len() {
+            return Err(ReservationError::Unreachable);
+        }
+        let i = self.claimant(train, speed)?;
+        let from = Locate(self.tracking, train)
+            .map_err(|_| ReservationError::NotLocated)?
+            .position
+            .node;
+
+        // only cross held track if there is no other way
+        let reservations = self.claims.reservations();
+        let route = route_around(track, &reservations, train, from, dest.node, None)
+            .or_else(|| {
+                route_around(
+                    track,
+                    &reservations,
+                    train,
+                    from,
+                    dest.node,
+                    Some(RESERVED_PENALTY),
+                )
+            })
+            .ok_or(ReservationError::Unreachable)?;
+
+        let stop = first_leg(track, &route, |switch, direction| {
+            Throw(self.calibration, switch, direction)
+        })?
+        .unwrap_or(dest);
+
+        self.run(train, speed)?;
+        self.trains[i].stop = Some(stop);
+        if self.trains[i].blocked {
+            // planned once it sets off again
+            return Ok(());
+        }
+        Ok(StopAt(self.calibration, train, stop.node, stop.offset)?)
+    }
+}
+
+/// Throw the switches on `route` up to where it first reverses, returning where the train has to
+/// stop to back up from there, or `None` if it doesn't
+fn first_leg(
+    track: &Track,
+    route: &Route,
+    mut throw: impl FnMut(u8, SwitchDirection) -> Result<(), CalibrationError>,
+) -> Result<Option<Position>, CalibrationError> {
+    for pair in route.path.windows(2) {
+        match track.step(pair[0], pair[1]) {
+            Some(Step::Edge(dir)) => {
+                if let NodeKind::Branch(switch) = track.nodes[pair[0]].kind {
+                    let direction = if dir == DIR_CURVED {
+                        SwitchDirection::Curved
+                    } else {
+                        SwitchDirection::Straight
+                    };
+                    throw(switch, direction)?;
+                }
+            }
+            // far enough past for the whole train to clear it
+            Some(Step::Reverse) => {
+                return Ok(Some(Position {
+                    node: pair[0],
+                    offset: TRAIN_LENGTH_MM,
+                }))
+            }
+            None => break,
+        }
+    }
+    Ok(None)
+}
+
+/// Has each train it runs hold the track it needs to stop in, and stops trains that can't.
+/// Started with the index of the track in `TRACKS` as its argument, after the tracking server.
+/// Claims are shown in a panel at the top of the console, so it needs the console server up too.
+pub fn reservation_server(track: usize) -> ! {
+    RegisterAs(RESERVATION_SERVER_NAME).unwrap();
+    let mut panel = Panel::new(WhoIs(CONSOLE_SERVER_NAME).unwrap()).unwrap();
+    let _ = panel.set(0, "Track held by each train");
+    let mut server = ReservationServer {
+        claims: Claims {
+            track: TRACKS[track],
+            owners: [0; MAX_NODES],
+        },
+        calibration: WhoIs(CALIBRATION_SERVER_NAME).unwrap(),
+        tracking: WhoIs(TRACKING_SERVER_NAME).unwrap(),
+        trains: Vec::new(),
+        panel,
+    };
+    Create(RESERVATION_TICKER_PRIORITY, reservation_ticker).unwrap();
+
+    loop {
+        let mut msg = [0u8; REQUEST_SIZE];
+        let (tid, _) = Receive(&mut msg);
+        let [kind, train, speed, ..] = msg;
+        let node = u16::from_le_bytes(msg[3..5].try_into().unwrap()) as NodeId;
+        let offset = u32::from_le_bytes(msg[5..9].try_into().unwrap());
+
+        match kind {
+            REQUEST_TICK => {
+                reply(tid, &[]);
+                for i in 0..server.trains.len() {
+                    server.update(i);
+                }
+            }
+            REQUEST_RESERVED => {
+                let mut msg = [0u8; REPLY_SIZE];
+                msg[1..].copy_from_slice(&server.claims.owners);
+                reply(tid, &msg);
+            }
+            REQUEST_RUN | REQUEST_GOTO if !(1..=MAX_TRAIN).contains(&train) => {
+                reply_status(tid, Err(ReservationError::InvalidTrain))
+            }
+            REQUEST_RUN | REQUEST_GOTO if speed > MAX_SPEED => {
+                reply_status(tid, Err(ReservationError::InvalidSpeed))
+            }
+            REQUEST_RUN => reply_status(tid, server.run(train, speed)),
+            REQUEST_GOTO => {
+                let dest = Position { node, offset };
+                reply_status(tid, server.goto(train, speed, dest))
+            }
+            _ => reply_status(tid, Err(ReservationError::Busy)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::track::tests::{A1, A2, A3, A4, BR1, BR2, LOOP, MR1, MR2};
+
+    fn at(node: NodeId, offset: u32) -> Position {
+        Position { node, offset }
+    }
+
+    fn claims() -> Claims {
+        Claims {
+            track: &LOOP,
+            owners: [0; MAX_NODES],
+        }
+    }
+
+    fn sorted(claim: &[NodeId]) -> Vec<NodeId, MAX_NODES> {
+        let mut claim = Vec::from_slice(claim).unwrap();
+        claim.sort_unstable();
+        claim
+    }
+
+    fn held_by(claims: &Claims, train: u8) -> Vec<NodeId, MAX_NODES> {
+        (0..LOOP.nodes.len())
+            .filter(|&node| claims.owners[node] == train)
+            .collect()
+    }
+
+    #[test]
+    fn claim_ahead_takes_both_ways_out_of_a_branch() {
+        let claims = claims();
+        let mut claim = Vec::new();
+        claims.claim_ahead(at(BR1, 0), 250, &mut claim);
+        assert_eq!(sorted(&claim)[..], [BR1, MR2]);
+
+        let mut claim = Vec::new();
+        claims.claim_ahead(at(BR1, 0), 600, &mut claim);
+        assert_eq!(sorted(&claim)[..], [A1, A3, BR1, MR2]);
+    }
+
+    #[test]
+    fn claim_ahead_counts_from_the_front_of_the_train() {
+        let claims = claims();
+        let mut claim = Vec::new();
+        claims.claim_ahead(at(A1, 0), 60, &mut claim);
+        assert_eq!(claim[..], [A1]);
+
+        let mut claim = Vec::new();
+        claims.claim_ahead(at(A1, 50), 60, &mut claim);
+        assert_eq!(claim[..], [A1, BR1]);
+    }
+
+    #[test]
+    fn a_claim_covers_the_train_behind_its_front() {
+        let claim = claims().claim(at(A1, 0), 50);
+        assert_eq!(claim[..], [A1, A2, BR2, A4]);
+    }
+
+    #[test]
+    fn taking_a_claim_holds_it_both_ways_and_lets_go_of_the_rest() {
+        let mut claims = claims();
+        let claim = claims.claim(at(A1, 0), 50);
+        assert!(!claims.take(24, &claim));
+        assert_eq!(held_by(&claims, 24)[..], [A1, A2, A3, A4, BR2, MR2]);
+        assert!(claims.reservations().is_held_against(MR2, 58));
+        assert!(!claims.reservations().is_held_against(MR2, 24));
+
+        assert!(!claims.take(24, &[A1]));
+        assert_eq!(held_by(&claims, 24)[..], [A1, A2]);
+    }
+
+    #[test]
+    fn a_train_that_runs_into_a_claim_stops_until_it_is_let_go() {
+        let mut claims = claims();
+        let claim = claims.claim(at(A1, 0), 50);
+        claims.take(24, &claim);
+
+        let mut claimant = Claimant {
+            train: 58,
+            speed: 10,
+            blocked: false,
+            held_reach: 0,
+            stop: None,
+        };
+        let claim = claims.claim(at(A3, 0), 250);
+        assert!(claims.take(58, &claim));
+        // what train 24 holds stays its
+        assert_eq!(held_by(&claims, 58)[..], []);
+        assert_eq!(claimant.change(true), Some(0));
+        claimant.blocked = true;
+        assert_eq!(claimant.change(true), None);
+
+        claims.take(24, &[]);
+        assert!(!claims.take(58, &claim));
+        assert_eq!(claimant.change(false), Some(10));
+    }
+
+    #[test]
+    fn route_around_keeps_off_held_track_unless_told_it_may_cross() {
+        let mut claims = claims();
+        claims.take(58, &[A3]);
+        let reservations = claims.reservations();
+
+        assert!(route_around(&LOOP, &reservations, 24, A1, A3, None).is_none());
+        let route = route_around(&LOOP, &reservations, 24, A1, A3, Some(RESERVED_PENALTY)).unwrap();
+        assert_eq!(route.path[..], [A1, BR1, A3]);
+        // its own claims are no obstacle
+        assert!(route_around(&LOOP, &reservations, 58, A1, A3, None).is_some());
+    }
+
+    #[test]
+    fn first_leg_throws_the_switches_up_to_the_first_reverse() {
+        let mut thrown: Vec<(u8, SwitchDirection), 4> = Vec::new();
+        let route = LOOP.route(A1, MR2).unwrap();
+        let stop = first_leg(&LOOP, &route, |switch, direction| {
+            thrown.push((switch, direction)).unwrap();
+            Ok(())
+        });
+        assert_eq!(stop, Ok(None));
+        assert_eq!(thrown[..], [(1, SwitchDirection::Curved)]);
+
+        thrown.clear();
+        let route = LOOP.route(BR1, A2).unwrap();
+        let stop = first_leg(&LOOP, &route, |switch, direction| {
+            thrown.push((switch, direction)).unwrap();
+            Ok(())
+        });
+        assert_eq!(stop, Ok(Some(at(BR1, TRAIN_LENGTH_MM))));
+        assert!(thrown.is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+use crate::io::{IoError, Puts, MAX_PUTS};
+use crate::syscall::{Tid, LINE_CONSOLE};
+use core::fmt::{self, Write};
+use heapless::String;
+
+/// Room for a row's text in the one `Puts` that draws it, next to the escapes that put it in place
+pub const ROW_SIZE: usize = MAX_PUTS - 16;
+
+/// The top `ROWS` rows of the console, kept for text redrawn in place. Everything else printed
+/// scrolls by underneath them.
+pub struct Panel<const ROWS: usize> {
+    console: Tid,
+    /// What each row shows now
+    rows: [String<ROW_SIZE>; ROWS],
+}
+
+impl<const ROWS: usize> Panel<ROWS> {
+    /// Clear the panel's rows and leave the cursor below them, through the console server
+    /// `console`
+    pub fn new(console: Tid) -> Result<Self, IoError> {
+        for row in 1..=ROWS {
+            puts(console, format_args!("\x1b[{row};1H\x1b[K"))?;
+        }
+        // setting the scrolling region sends the cursor home, so it is moved down afterwards
+        let below = ROWS + 1;
+        puts(console, format_args!("\x1b[{below}r\x1b[{below};1H"))?;
+        Ok(Self {
+            console,
+            rows: core::array::from_fn(|_| String::new()),
+        })
+    }
+
+    /// Show `text` on `row`, counting from 0, unless it is there already. Whatever is past
+    /// `ROW_SIZE` bytes is cut off.
+    pub fn set(&mut self, row: usize, text: &str) -> Result<(), IoError> {
+        let mut fitted: String<ROW_SIZE> = String::new();
+        for ch in text.chars() {
+            if fitted.push(ch).is_err() {
+                break;
+            }
+        }
+        if self.rows[row] == fitted {
+            return Ok(());
+        }
+        // the cursor is put back afterwards, so whoever prints next carries on where they were
+        puts(
+            self.console,
+            format_args!("\x1b7\x1b[{};1H{fitted}\x1b[K\x1b8", row + 1),
+        )?;
+        self.rows[row] = fitted;
+        Ok(())
+    }
+}
+
+/// Send `args` in one piece, so nothing from other tasks lands between the escapes
+fn puts(console: Tid, args: fmt::Arguments) -> Result<(), IoError> {
+    let mut msg: String<MAX_PUTS> = String::new();
+    msg.write_fmt(args).unwrap();
+    Puts(console, LINE_CONSOLE, msg.as_bytes())
+}
@@ -0,0 +1,348 @@
+// Host builds only exist for the tests, and have no kernel to trap into
+#![cfg_attr(
+    not(target_arch = "aarch64"),
+    allow(unreachable_code, unused_unsafe, unused_variables)
+)]
+
+/// Trap into the kernel with exception code `$code`, passing the operands on to `asm!`
+#[cfg(target_arch = "aarch64")]
+macro_rules! svc {
+    ($code:expr $(, $($operands:tt)*)?) => {
+        core::arch::asm!("svc {}", const $code $(, $($operands)*)?)
+    };
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+macro_rules! svc {
+    ($($operands:tt)*) => {
+        unimplemented!("syscalls only run on the Pi")
+    };
+}
+
+/// A task slot tagged with the slot's generation
+pub type Tid = i32;
+
+pub const EXCEPTION_CODE_CREATE: u64 = 1;
+pub const EXCEPTION_CODE_MY_TID: u64 = 2;
+pub const EXCEPTION_CODE_MY_PARENT_TID: u64 = 3;
+pub const EXCEPTION_CODE_YIELD: u64 = 4;
+pub const EXCEPTION_CODE_EXIT: u64 = 5;
+pub const EXCEPTION_CODE_SEND: u64 = 6;
+pub const EXCEPTION_CODE_RECEIVE: u64 = 7;
+pub const EXCEPTION_CODE_REPLY: u64 = 8;
+pub const EXCEPTION_CODE_AWAIT_EVENT: u64 = 9;
+pub const EXCEPTION_CODE_PRINT: u64 = 10;
+pub const EXCEPTION_CODE_CREATE_EX: u64 = 11;
+pub const EXCEPTION_CODE_WRITE: u64 = 12;
+
+/// The UART wired to the terminal
+pub const LINE_CONSOLE: u64 = 0;
+/// The UART wired to the Märklin track controller
+pub const LINE_MARKLIN: u64 = 1;
+pub const LINE_COUNT: usize = 2;
+
+/// A byte arrived on the console UART, returned as the event data
+pub const EVENT_CONSOLE_RX: u64 = 0;
+/// The 10ms system timer tick
+pub const EVENT_TIMER: u64 = 1;
+/// The console UART has room to transmit
+pub const EVENT_CONSOLE_TX: u64 = 2;
+/// A byte arrived on the Märklin UART, returned as the event data
+pub const EVENT_MARKLIN_RX: u64 = 3;
+/// The Märklin UART has room to transmit
+pub const EVENT_MARKLIN_TX: u64 = 4;
+pub const EVENT_COUNT: usize = 5;
+
+/// The event for a byte arriving on `line`
+pub const fn rx_event(line: u64) -> u64 {
+    if line == LINE_MARKLIN {
+        EVENT_MARKLIN_RX
+    } else {
+        EVENT_CONSOLE_RX
+    }
+}
+
+/// The event for `line` having room to transmit
+pub const fn tx_event(line: u64) -> u64 {
+    if line == LINE_MARKLIN {
+        EVENT_MARKLIN_TX
+    } else {
+        EVENT_CONSOLE_TX
+    }
+}
+
+/// Why a syscall failed. The kernel hands these back negated in x0, so any non-negative x0 is the
+/// syscall's value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// The TID could never name a task
+    InvalidTid = 1,
+    /// The TID named a task that has since exited, or the caller has no parent
+    NoSuchTask = 2,
+    /// The message or reply did not fit and was cut short
+    Truncated = 3,
+    /// Reply was sent to a task that is not waiting on a reply from the caller
+    NotReplyBlocked = 4,
+    /// AwaitEvent was given an unknown event
+    InvalidEvent = 5,
+    /// The kernel heap had no room to queue the caller, or there were no frames left
+    OutOfMemory = 6,
+    /// Priorities run from 0 to 63
+    InvalidPriority = 7,
+    /// Every task descriptor is in use
+    OutOfDescriptors = 8,
+    /// The stack size was zero or bigger than the kernel allows
+    InvalidStackSize = 9,
+    /// A pointer reached outside the caller's memory
+    BadAddress = 10,
+    /// Write was given an unknown UART line
+    InvalidLine = 11,
+    /// The kernel sent back a code this side doesn't know, it never sends this one itself
+    Unknown = i64::MAX,
+}
+
+impl SyscallError {
+    /// What the kernel puts in x0
+    pub fn code(self) -> i64 {
+        -(self as i64)
+    }
+
+    fn from_code(code: i64) -> Self {
+        match code.wrapping_neg() {
+            1 => Self::InvalidTid,
+            2 => Self::NoSuchTask,
+            3 => Self::Truncated,
+            4 => Self::NotReplyBlocked,
+            5 => Self::InvalidEvent,
+            6 => Self::OutOfMemory,
+            7 => Self::InvalidPriority,
+            8 => Self::OutOfDescriptors,
+            9 => Self::InvalidStackSize,
+            10 => Self::BadAddress,
+            11 => Self::InvalidLine,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// Split what the kernel left in x0 into the value or the error
+fn result(x0: i64) -> Result<u64, SyscallError> {
+    if x0 < 0 {
+        Err(SyscallError::from_code(x0))
+    } else {
+        Ok(x0 as u64)
+    }
+}
+
+/// Task names longer than this are cut short
+pub const TASK_NAME_LEN: usize = 16;
+
+/// Everything `CreateEx` needs to start a task. The kernel reads the fields before `name` straight
+/// from the caller's memory, so they must stay first.
+#[repr(C)]
+pub struct TaskOptions<'a> {
+    pub priority: usize,
+    /// Called with `arg` as its only argument
+    pub func: fn(usize) -> !,
+    /// Rounded up to a whole number of pages
+    pub stack_size: usize,
+    pub arg: usize,
+    pub name: &'a str,
+}
+
+// Arguments go in x0 to x5. x0 comes back with the value or a negated `SyscallError`, and x1 with
+// a second value for the syscalls that have one.
+
+#[allow(non_snake_case)]
+pub fn Create(priority: usize, func: fn() -> !) -> Result<Tid, SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(EXCEPTION_CODE_CREATE, in("x0") priority, in("x1") func, lateout("x0") ret);
+    }
+    result(ret).map(|tid| tid as Tid)
+}
+
+/// Like `Create`, but with a stack size, an argument for the child and a name.
+#[allow(non_snake_case)]
+pub fn CreateEx(options: &TaskOptions) -> Result<Tid, SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(
+            EXCEPTION_CODE_CREATE_EX,
+            in("x0") options as *const TaskOptions,
+            in("x1") options.name.as_ptr(),
+            in("x2") options.name.len(),
+            lateout("x0") ret,
+        );
+    }
+    result(ret).map(|tid| tid as Tid)
+}
+
+#[allow(non_snake_case)]
+pub fn MyTid() -> Tid {
+    let ret: i64;
+    unsafe {
+        svc!(EXCEPTION_CODE_MY_TID, out("x0") ret);
+    }
+    ret as Tid
+}
+
+/// The task that created us, or `NoSuchTask` for the tasks the kernel starts.
+#[allow(non_snake_case)]
+pub fn MyParentTid() -> Result<Tid, SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(EXCEPTION_CODE_MY_PARENT_TID, out("x0") ret);
+    }
+    result(ret).map(|tid| tid as Tid)
+}
+
+#[allow(non_snake_case)]
+pub fn Yield() {
+    unsafe {
+        svc!(EXCEPTION_CODE_YIELD, lateout("x0") _);
+    }
+}
+
+#[allow(non_snake_case)]
+pub fn Exit() -> ! {
+    unsafe {
+        svc!(EXCEPTION_CODE_EXIT);
+    }
+
+    unreachable!("Exit returned")
+}
+
+/// Send `msg` to `tid` and block until it replies. Returns the reply length.
+#[allow(non_snake_case)]
+pub fn Send(tid: Tid, msg: &[u8], reply: &mut [u8]) -> Result<usize, SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(
+            EXCEPTION_CODE_SEND,
+            in("x0") tid,
+            in("x1") msg.as_ptr(),
+            in("x2") msg.len(),
+            in("x3") reply.as_mut_ptr(),
+            in("x4") reply.len(),
+            lateout("x0") ret,
+        );
+    }
+    result(ret).map(|len| len as usize)
+}
+
+/// Block until some task sends to us. Returns the sender along with the message length, so it can
+/// still be replied to when its message was `Truncated`.
+#[allow(non_snake_case)]
+pub fn Receive(msg: &mut [u8]) -> (Tid, Result<usize, SyscallError>) {
+    let ret: i64;
+    let tid: i64;
+    unsafe {
+        svc!(
+            EXCEPTION_CODE_RECEIVE,
+            in("x0") msg.as_mut_ptr(),
+            in("x1") msg.len(),
+            lateout("x0") ret,
+            lateout("x1") tid,
+        );
+    }
+    (tid as Tid, result(ret).map(|len| len as usize))
+}
+
+/// Unblock `tid`, which must be waiting on a reply from us.
+#[allow(non_snake_case)]
+pub fn Reply(tid: Tid, reply: &[u8]) -> Result<(), SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(
+            EXCEPTION_CODE_REPLY,
+            in("x0") tid,
+            in("x1") reply.as_ptr(),
+            in("x2") reply.len(),
+            lateout("x0") ret,
+        );
+    }
+    result(ret).map(|_| ())
+}
+
+/// Block until `event` happens. Returns the data attached to the event.
+#[allow(non_snake_case)]
+pub fn AwaitEvent(event: u64) -> Result<u64, SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(EXCEPTION_CODE_AWAIT_EVENT, in("x0") event, lateout("x0") ret);
+    }
+    result(ret)
+}
+
+/// Write `msg` to the console through the kernel's log. Returns how many bytes it had room for,
+/// which is fewer than all of them once the log is full. `print!` only falls back on this while the
+/// console server can't take its output.
+#[allow(non_snake_case)]
+pub fn Print(msg: &[u8]) -> Result<usize, SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(
+            EXCEPTION_CODE_PRINT,
+            in("x0") msg.as_ptr(),
+            in("x1") msg.len(),
+            lateout("x0") ret,
+        );
+    }
+    result(ret).map(|len| len as usize)
+}
+
+/// Put as much of `bytes` in `line`'s transmit FIFO as fits right now, without waiting. Returns how
+/// many bytes that was, which is 0 when the FIFO is full; wait on `tx_event(line)` for room.
+#[allow(non_snake_case)]
+pub fn Write(line: u64, bytes: &[u8]) -> Result<usize, SyscallError> {
+    let ret: i64;
+    unsafe {
+        svc!(
+            EXCEPTION_CODE_WRITE,
+            in("x0") line,
+            in("x1") bytes.as_ptr(),
+            in("x2") bytes.len(),
+            lateout("x0") ret,
+        );
+    }
+    result(ret).map(|written| written as usize)
+}
+
+/// The status byte a server's reply starts with when the request went through
+pub const STATUS_OK: u8 = 0;
+
+/// The errors a server reports as the status byte its replies start with
+pub trait ServerError: From<SyscallError> {
+    /// What the server replies with for this error, never `STATUS_OK`
+    fn status(&self) -> u8;
+
+    /// The error a status byte other than `STATUS_OK` stands for
+    fn from_status(status: u8) -> Self;
+}
+
+/// Send `msg` to `server`, turning any status but `STATUS_OK` into the error it stands for. The
+/// rest of the reply is left in `reply[1..]`.
+pub fn request<E: ServerError>(server: Tid, msg: &[u8], reply: &mut [u8]) -> Result<(), E> {
+    Send(server, msg, reply)?;
+    match reply[0] {
+        STATUS_OK => Ok(()),
+        status => Err(E::from_status(status)),
+    }
+}
+
+/// Answer `tid` from a server. Servers only answer tasks that are blocked waiting on them, so if
+/// it fails there is nobody left to tell.
+pub fn reply(tid: Tid, msg: &[u8]) {
+    let _ = Reply(tid, msg);
+}
+
+/// Answer `tid` with nothing but the status of `result`
+pub fn reply_status<E: ServerError>(tid: Tid, result: Result<(), E>) {
+    let status = match result {
+        Ok(()) => STATUS_OK,
+        Err(err) => err.status(),
+    };
+    reply(tid, &[status]);
+}
@@ -0,0 +1,536 @@
+use crate::marklin::{Sensor, SwitchDirection};
+use heapless::Vec;
+
+/// Where a node sits in its track's `nodes`. Sensors come first, so a sensor's node is its index.
+pub type NodeId = usize;
+
+/// The edge ahead of most nodes, and the straight edge out of a branch
+pub const DIR_AHEAD: usize = 0;
+pub const DIR_STRAIGHT: usize = 0;
+pub const DIR_CURVED: usize = 1;
+
+/// What stopping and backing up is worth in mm of travel, so routes only reverse to save a lot
+pub const REVERSE_COST: u32 = 1000;
+/// A route can't pass the same node twice
+pub const MAX_ROUTE: usize = MAX_NODES;
+/// A route passes each branch at most once, and no track has more switches than this
+pub const MAX_ROUTE_SWITCHES: usize = 32;
+/// Reversing takes a step past a node and back, so it can happen at most every other node
+pub const MAX_ROUTE_REVERSES: usize = MAX_ROUTE / 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Sensor(Sensor),
+    /// Points ahead of the train, numbered like the switch
+    Branch(u8),
+    /// The same switch met from its straight or curved side
+    Merge(u8),
+    /// A dead end, going into the track
+    Enter,
+    /// A dead end, coming out of the track
+    Exit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub dest: NodeId,
+    /// In mm
+    pub dist: u32,
+}
+
+/// A place on the track in one direction of travel
+#[derive(Debug)]
+pub struct Node {
+    pub name: &'static str,
+    pub kind: NodeKind,
+    /// The same place in the other direction
+    pub reverse: NodeId,
+    /// Indexed by `DIR_AHEAD`, or `DIR_STRAIGHT` and `DIR_CURVED` for a branch
+    pub edges: [Option<Edge>; 2],
+}
+
+#[derive(Debug)]
+pub struct Track {
+    pub name: &'static str,
+    pub nodes: &'static [Node],
+}
+
+/// A point on the track, `offset` mm past `node` towards whatever is ahead of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub node: NodeId,
+    pub offset: u32,
+}
+
+/// How a route moves on from one of its nodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Along one of the node's edges
+    Edge(usize),
+    /// Stop past the node and back up, continuing from its reverse
+    Reverse,
+}
+
+/// A way across the track. Routing and the route itself take a few KiB, so tasks that route
+/// need a bigger stack than `Create` gives.
+#[derive(Debug, Clone, Default)]
+pub struct Route {
+    /// Every node passed, the start first and the destination last
+    pub path: Vec<NodeId, MAX_ROUTE>,
+    /// Switches to set before the train reaches them, in the order it does
+    pub switches: Vec<(u8, SwitchDirection), MAX_ROUTE_SWITCHES>,
+    /// The nodes the train reverses at, in order
+    pub reverses: Vec<NodeId, MAX_ROUTE_REVERSES>,
+    /// mm of track covered, not counting reversing
+    pub dist: u32,
+}
+
+include!(concat!(env!("OUT_DIR"), "/track_data.rs"));
+
+pub static TRACKS: [&Track; 2] = [&TRACK_A, &TRACK_B];
+
+impl Track {
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+
+    pub fn sensor(&self, sensor: Sensor) -> NodeId {
+        sensor.index()
+    }
+
+    /// The node `step` leads to from `node`
+    pub fn next(&self, node: NodeId, step: Step) -> Option<NodeId> {
+        match step {
+            Step::Edge(dir) => self.nodes[node].edges[dir].map(|edge| edge.dest),
+            Step::Reverse => Some(self.nodes[node].reverse),
+        }
+    }
+
+    /// How to get from `node` to `next` in one step
+    pub fn step(&self, node: NodeId, next: NodeId) -> Option<Step> {
+        if self.nodes[node].reverse == next {
+            return Some(Step::Reverse);
+        }
+        (0..2)
+            .find(|&dir| self.next(node, Step::Edge(dir)) == Some(next))
+            .map(Step::Edge)
+    }
+
+    /// The edge a train leaving `node` takes, with switches set as `switch` says
+    pub fn ahead(&self, node: NodeId, switch: impl Fn(u8) -> SwitchDirection) -> Option<Edge> {
+        let dir = match self.nodes[node].kind {
+            NodeKind::Branch(number) if switch(number) == SwitchDirection::Curved => DIR_CURVED,
+            _ => DIR_AHEAD,
+        };
+        self.nodes[node].edges[dir]
+    }
+
+    /// Where a train at `pos` is after `dist` more mm, stopping short at a dead end. A switch
+    /// thrown under a train can leave `pos` further along than the edge now ahead of it is long,
+    /// in which case it is taken to be at the end of that edge.
+    pub fn advance(
+        &self,
+        pos: Position,
+        dist: u32,
+        switch: impl Fn(u8) -> SwitchDirection,
+    ) -> Position {
+        let mut pos = pos;
+        let mut left = dist;
+        while let Some(edge) = self.ahead(pos.node, &switch) {
+            let offset = pos.offset.min(edge.dist);
+            if offset + left < edge.dist {
+                return Position {
+                    node: pos.node,
+                    offset: offset + left,
+                };
+            }
+            left -= edge.dist - offset;
+            pos = Position {
+                node: edge.dest,
+                offset: 0,
+            };
+        }
+        pos
+    }
+
+    /// mm from `from` on to `to`, or `None` if the switches don't lead there before the track
+    /// loops back on itself
+    pub fn distance(
+        &self,
+        from: Position,
+        to: Position,
+        switch: impl Fn(u8) -> SwitchDirection,
+    ) -> Option<u32> {
+        if from.node == to.node && from.offset <= to.offset {
+            return Some(to.offset - from.offset);
+        }
+        let mut node = from.node;
+        let mut dist = 0;
+        for _ in 0..self.nodes.len() {
+            let edge = self.ahead(node, &switch)?;
+            dist += edge.dist;
+            node = edge.dest;
+            if node == to.node {
+                return (dist + to.offset).checked_sub(from.offset);
+            }
+        }
+        None
+    }
+
+    /// The first sensor past `pos` with the switches as they are, and how many mm on it is
+    pub fn next_sensor(
+        &self,
+        pos: Position,
+        switch: impl Fn(u8) -> SwitchDirection,
+    ) -> Option<(NodeId, u32)> {
+        let mut node = pos.node;
+        let mut dist = 0;
+        for _ in 0..self.nodes.len() {
+            let edge = self.ahead(node, &switch)?;
+            dist += edge.dist;
+            node = edge.dest;
+            if let NodeKind::Sensor(_) = self.nodes[node].kind {
+                return Some((node, dist.saturating_sub(pos.offset)));
+            }
+        }
+        None
+    }
+
+    /// The same point as `pos`, facing the other way
+    pub fn flip(&self, pos: Position, switch: impl Fn(u8) -> SwitchDirection) -> Position {
+        match self.ahead(pos.node, switch) {
+            Some(edge) => Position {
+                node: self.nodes[edge.dest].reverse,
+                offset: edge.dist.saturating_sub(pos.offset),
+            },
+            None => Position {
+                node: self.nodes[pos.node].reverse,
+                offset: 0,
+            },
+        }
+    }
+
+    /// The shortest way from `from` to `to` by distance, reversing where that saves enough.
+    pub fn route(&self, from: NodeId, to: NodeId) -> Option<Route> {
+        self.route_with(from, to, |_, edge| Some(edge.dist))
+    }
+
+    /// Like `route`, with `weigh` giving what it costs to take an edge out of a node, or `None`
+    /// if the edge can't be used.
+    pub fn route_with(
+        &self,
+        from: NodeId,
+        to: NodeId,
+        mut weigh: impl FnMut(NodeId, &Edge) -> Option<u32>,
+    ) -> Option<Route> {
+        let count = self.nodes.len();
+        let mut cost = [u32::MAX; MAX_NODES];
+        let mut prev = [None; MAX_NODES];
+        let mut done = [false; MAX_NODES];
+        cost[from] = 0;
+
+        // no heap, the tracks are small enough to scan for the closest node each time
+        loop {
+            let node = (0..count)
+                .filter(|&node| !done[node] && cost[node] != u32::MAX)
+                .min_by_key(|&node| cost[node])?;
+            if node == to {
+                break;
+            }
+            done[node] = true;
+
+            let mut relax = |next: NodeId, step_cost: u32| {
+                let next_cost = cost[node].saturating_add(step_cost);
+                if next_cost < cost[next] {
+                    cost[next] = next_cost;
+                    prev[next] = Some(node);
+                }
+            };
+            for edge in self.nodes[node].edges.iter().flatten() {
+                if let Some(edge_cost) = weigh(node, edge) {
+                    relax(edge.dest, edge_cost);
+                }
+            }
+            relax(self.nodes[node].reverse, REVERSE_COST);
+        }
+
+        let mut route = Route::default();
+        let mut node = to;
+        let _ = route.path.push(to);
+        while let Some(from) = prev[node] {
+            let _ = route.path.push(from);
+            node = from;
+        }
+        route.path.reverse();
+
+        for pair in route.path.windows(2) {
+            let node = pair[0];
+            match (self.nodes[node].kind, self.step(node, pair[1]).unwrap()) {
+                (_, Step::Reverse) => {
+                    let _ = route.reverses.push(node);
+                }
+                (kind, Step::Edge(dir)) => {
+                    route.dist += self.nodes[node].edges[dir].unwrap().dist;
+                    if let NodeKind::Branch(switch) = kind {
+                        let direction = if dir == DIR_CURVED {
+                            SwitchDirection::Curved
+                        } else {
+                            SwitchDirection::Straight
+                        };
+                        let _ = route.switches.push((switch, direction));
+                    }
+                }
+            }
+        }
+        Some(route)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::marklin::SENSOR_COUNT;
+
+    pub(crate) const A1: NodeId = 0;
+    pub(crate) const A2: NodeId = 1;
+    pub(crate) const A3: NodeId = 2;
+    pub(crate) const A4: NodeId = 3;
+    pub(crate) const BR1: NodeId = 4;
+    pub(crate) const MR1: NodeId = 5;
+    pub(crate) const BR2: NodeId = 6;
+    pub(crate) const MR2: NodeId = 7;
+
+    const fn sensor(number: u8) -> NodeKind {
+        NodeKind::Sensor(Sensor { bank: 0, number })
+    }
+
+    const fn to(dest: NodeId, dist: u32) -> Option<Edge> {
+        Some(Edge { dest, dist })
+    }
+
+    /// Round from A1 through switches 1 and 2, with the curved side of switch 1 a 200mm shortcut
+    /// past A3
+    pub(crate) static LOOP: Track = Track {
+        name: "loop",
+        nodes: &[
+            Node {
+                name: "A1",
+                kind: sensor(1),
+                reverse: A2,
+                edges: [to(BR1, 100), None],
+            },
+            Node {
+                name: "A2",
+                kind: sensor(2),
+                reverse: A1,
+                edges: [to(BR2, 100), None],
+            },
+            Node {
+                name: "A3",
+                kind: sensor(3),
+                reverse: A4,
+                edges: [to(MR2, 100), None],
+            },
+            Node {
+                name: "A4",
+                kind: sensor(4),
+                reverse: A3,
+                edges: [to(MR1, 500), None],
+            },
+            Node {
+                name: "BR1",
+                kind: NodeKind::Branch(1),
+                reverse: MR1,
+                edges: [to(A3, 500), to(MR2, 200)],
+            },
+            Node {
+                name: "MR1",
+                kind: NodeKind::Merge(1),
+                reverse: BR1,
+                edges: [to(A2, 100), None],
+            },
+            Node {
+                name: "BR2",
+                kind: NodeKind::Branch(2),
+                reverse: MR2,
+                edges: [to(A4, 100), to(MR1, 200)],
+            },
+            Node {
+                name: "MR2",
+                kind: NodeKind::Merge(2),
+                reverse: BR2,
+                edges: [to(A1, 100), None],
+            },
+        ],
+    };
+
+    fn straight(switch: u8) -> (u8, SwitchDirection) {
+        (switch, SwitchDirection::Straight)
+    }
+
+    fn curved(switch: u8) -> (u8, SwitchDirection) {
+        (switch, SwitchDirection::Curved)
+    }
+
+    /// Check `route` is a way from `from` to `to` that adds up
+    fn check(track: &Track, route: &Route, from: NodeId, to: NodeId) {
+        assert_eq!(route.path.first(), Some(&from));
+        assert_eq!(route.path.last(), Some(&to));
+
+        let mut dist = 0;
+        let mut switches: Vec<_, MAX_ROUTE_SWITCHES> = Vec::new();
+        let mut reverses: Vec<_, MAX_ROUTE_REVERSES> = Vec::new();
+        for pair in route.path.windows(2) {
+            match track.step(pair[0], pair[1]) {
+                Some(Step::Edge(dir)) => {
+                    dist += track.nodes[pair[0]].edges[dir].unwrap().dist;
+                    if let NodeKind::Branch(switch) = track.nodes[pair[0]].kind {
+                        let direction = if dir == DIR_CURVED { curved } else { straight };
+                        switches.push(direction(switch)).unwrap();
+                    }
+                }
+                Some(Step::Reverse) => reverses.push(pair[0]).unwrap(),
+                None => panic!(
+                    "{} doesn't lead to {}",
+                    track.nodes[pair[0]].name, track.nodes[pair[1]].name
+                ),
+            }
+        }
+        assert_eq!(route.dist, dist);
+        assert_eq!(route.switches[..], switches[..]);
+        assert_eq!(route.reverses[..], reverses[..]);
+    }
+
+    #[test]
+    fn route_takes_the_shortest_way() {
+        let route = LOOP.route(A1, A3).unwrap();
+        assert_eq!(route.path[..], [A1, BR1, A3]);
+        assert_eq!(route.switches[..], [straight(1)]);
+        assert!(route.reverses.is_empty());
+        assert_eq!(route.dist, 600);
+
+        let route = LOOP.route(A1, MR2).unwrap();
+        assert_eq!(route.path[..], [A1, BR1, MR2]);
+        assert_eq!(route.switches[..], [curved(1)]);
+        assert_eq!(route.dist, 300);
+    }
+
+    #[test]
+    fn route_to_where_it_starts_goes_nowhere() {
+        let route = LOOP.route(A3, A3).unwrap();
+        assert_eq!(route.path[..], [A3]);
+        assert!(route.switches.is_empty() && route.reverses.is_empty());
+        assert_eq!(route.dist, 0);
+    }
+
+    #[test]
+    fn route_reverses_where_going_round_costs_more() {
+        // round the shortcut and back up at A1 is 300mm, past `REVERSE_COST` less going round
+        let route = LOOP.route(BR1, A2).unwrap();
+        assert_eq!(route.path[..], [BR1, MR1, A2]);
+        assert_eq!(route.reverses[..], [BR1]);
+        assert!(route.switches.is_empty());
+        assert_eq!(route.dist, 100);
+    }
+
+    #[test]
+    fn route_with_keeps_off_edges_it_is_told_to() {
+        let route = LOOP
+            .route_with(A1, A3, |node, edge| {
+                (node != BR1 || edge.dest != A3).then_some(edge.dist)
+            })
+            .unwrap();
+        check(&LOOP, &route, A1, A3);
+        assert_eq!(route.path[..], [A1, A2, BR2, A4, A3]);
+        assert_eq!(route.reverses[..], [A1, A4]);
+        assert_eq!(route.switches[..], [straight(2)]);
+        assert_eq!(route.dist, 200);
+    }
+
+    #[test]
+    fn route_with_weighs_edges_as_told() {
+        let route = LOOP
+            .route_with(A1, MR2, |node, edge| {
+                let shortcut = node == BR1 && edge.dest == MR2;
+                Some(edge.dist + if shortcut { 1000 } else { 0 })
+            })
+            .unwrap();
+        assert_eq!(route.path[..], [A1, BR1, A3, MR2]);
+        assert_eq!(route.switches[..], [straight(1)]);
+        assert_eq!(route.dist, 700);
+    }
+
+    #[test]
+    fn route_with_no_usable_edges_can_only_reverse() {
+        assert!(LOOP.route_with(A1, A3, |_, _| None).is_none());
+        let route = LOOP.route_with(A1, A2, |_, _| None).unwrap();
+        assert_eq!(route.path[..], [A1, A2]);
+        assert_eq!(route.reverses[..], [A1]);
+    }
+
+    #[test]
+    fn advance_follows_the_switches() {
+        let at = |node, offset| Position { node, offset };
+        let straight = |_| SwitchDirection::Straight;
+        let curved = |_| SwitchDirection::Curved;
+
+        assert_eq!(LOOP.advance(at(A1, 50), 20, straight), at(A1, 70));
+        assert_eq!(LOOP.advance(at(A1, 50), 100, straight), at(BR1, 50));
+        assert_eq!(LOOP.advance(at(A1, 50), 100, curved), at(BR1, 50));
+        assert_eq!(LOOP.advance(at(BR1, 0), 600, straight), at(MR2, 0));
+        assert_eq!(LOOP.advance(at(BR1, 0), 300, curved), at(A1, 0));
+    }
+
+    #[test]
+    fn advance_past_a_switch_thrown_under_the_train() {
+        let at = |node, offset| Position { node, offset };
+
+        // 400mm along the straight side is further than the curved side goes
+        let pos = at(BR1, 400);
+        let curved = |_| SwitchDirection::Curved;
+        assert_eq!(LOOP.advance(pos, 0, curved), at(MR2, 0));
+        assert_eq!(LOOP.advance(pos, 50, curved), at(MR2, 50));
+        assert_eq!(LOOP.flip(pos, curved), at(BR2, 0));
+    }
+
+    #[test]
+    fn generated_tracks_run_both_ways() {
+        for track in TRACKS {
+            for (id, node) in track.nodes.iter().enumerate() {
+                assert_eq!(track.nodes[node.reverse].reverse, id, "{}", node.name);
+                for edge in node.edges.iter().flatten() {
+                    let back = track.nodes[edge.dest].reverse;
+                    let back_edge = track.nodes[back]
+                        .edges
+                        .iter()
+                        .flatten()
+                        .find(|back_edge| back_edge.dest == node.reverse);
+                    assert_eq!(
+                        back_edge.map(|edge| edge.dist),
+                        Some(edge.dist),
+                        "{} to {}",
+                        node.name,
+                        track.nodes[edge.dest].name
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn generated_tracks_route_between_any_sensors() {
+        for track in TRACKS {
+            for from in (0..SENSOR_COUNT).step_by(7) {
+                for to in 0..SENSOR_COUNT {
+                    let route = track.route(from, to).unwrap_or_else(|| {
+                        panic!(
+                            "{}: no way from {} to {}",
+                            track.name, track.nodes[from].name, track.nodes[to].name
+                        )
+                    });
+                    check(track, &route, from, to);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,506 @@
+use crate::calibration::{
+    Expect, Expectation, Expected, Passed, PredictPosition, Throw, CALIBRATION_SERVER_NAME,
+    MAX_MODELLED_TRAINS, NEVER,
+};
+use crate::clock::{Time, CLOCK_SERVER_NAME};
+use crate::marklin::{DumpSensors, SensorReport, MAX_TRAIN, SENSOR_REPORT_SIZE, TRAIN_SERVER_NAME};
+use crate::name_server::{RegisterAs, WhoIs};
+use crate::syscall::{
+    reply, reply_status, request, Create, MyParentTid, Receive, Send, ServerError, SyscallError,
+    Tid, STATUS_OK,
+};
+use crate::track::{NodeId, Position, Track, TRACKS};
+use heapless::{Deque, Vec};
+
+pub const TRACKING_SERVER_NAME: &str = "tracking";
+/// Below the calibration server, which it sends to
+pub const TRACKING_SERVER_PRIORITY: usize = 25;
+/// Expectations are a few hundred bytes, and the server keeps one per train
+pub const TRACKING_SERVER_STACK_SIZE: usize = 0x2000;
+const SENSOR_POLLER_PRIORITY: usize = 27;
+const MAX_WATCHERS: usize = 16;
+
+/// Hits this close to when a train was expected are put down to it whatever its speed
+const MIN_WINDOW_TICKS: i64 = 50;
+/// On top of that, a quarter of how long the train was expected to take to get there, since
+/// errors build up with distance
+const WINDOW_SHIFT: u32 = 2;
+
+const REQUEST_TRACK: u8 = 0;
+const REQUEST_LOCATE: u8 = 1;
+const REQUEST_AWAIT: u8 = 2;
+/// From the poller, with a sensor report and the tick it came back
+const REQUEST_REPORT: u8 = 3;
+
+/// Request kind and train, or a sensor report and little endian time from the poller
+const REQUEST_SIZE: usize = 2 + SENSOR_REPORT_SIZE + 8;
+/// One byte of status followed by a `TrainLocation`
+const REPLY_SIZE: usize = 1 + LOCATION_SIZE;
+/// Train, node, offset, sensor, time error and distance error
+const LOCATION_SIZE: usize = 1 + 2 + 4 + 2 + 8 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackingError {
+    /// Trains are numbered 1 to 80
+    InvalidTrain,
+    /// Nobody asked for the train to be tracked
+    NotTracked,
+    /// The train hasn't been seen at a sensor yet
+    NotLocated,
+    /// Every train slot or watcher slot is taken
+    Busy,
+    /// The tracking server couldn't be reached
+    Syscall(SyscallError),
+}
+
+impl From<SyscallError> for TrackingError {
+    fn from(err: SyscallError) -> Self {
+        Self::Syscall(err)
+    }
+}
+
+impl ServerError for TrackingError {
+    fn status(&self) -> u8 {
+        match self {
+            Self::InvalidTrain => 1,
+            Self::NotTracked => 2,
+            Self::NotLocated => 3,
+            Self::Busy | Self::Syscall(_) => 4,
+        }
+    }
+
+    fn from_status(status: u8) -> Self {
+        match status {
+            1 => Self::InvalidTrain,
+            2 => Self::NotTracked,
+            3 => Self::NotLocated,
+            _ => Self::Busy,
+        }
+    }
+}
+
+/// Where a train is, and how far off the model was when it last turned up at a sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrainLocation {
+    pub train: u8,
+    /// The front of the train, as the model has it now
+    pub position: Position,
+    /// The last sensor the train was seen at
+    pub sensor: NodeId,
+    /// Ticks the train got to `sensor` after it was expected, negative if early
+    pub time_error: i64,
+    /// mm the model had the train past `sensor` when it got there, negative if short of it
+    pub dist_error: i32,
+}
+
+impl TrainLocation {
+    fn encode(&self) -> [u8; REPLY_SIZE] {
+        let mut bytes = [0u8; REPLY_SIZE];
+        bytes[0] = STATUS_OK;
+        bytes[1] = self.train;
+        bytes[2..4].copy_from_slice(&(self.position.node as u16).to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.position.offset.to_le_bytes());
+        bytes[8..10].copy_from_slice(&(self.sensor as u16).to_le_bytes());
+        bytes[10..18].copy_from_slice(&self.time_error.to_le_bytes());
+        bytes[18..22].copy_from_slice(&self.dist_error.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; REPLY_SIZE]) -> Self {
+        Self {
+            train: bytes[1],
+            position: Position {
+                node: u16::from_le_bytes(bytes[2..4].try_into().unwrap()) as NodeId,
+                offset: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            },
+            sensor: u16::from_le_bytes(bytes[8..10].try_into().unwrap()) as NodeId,
+            time_error: i64::from_le_bytes(bytes[10..18].try_into().unwrap()),
+            dist_error: i32::from_le_bytes(bytes[18..22].try_into().unwrap()),
+        }
+    }
+}
+
+/// Send `msg` to the tracking server, for requests answered with a location
+fn call(server: Tid, msg: &[u8]) -> Result<TrainLocation, TrackingError> {
+    let mut reply = [0u8; REPLY_SIZE];
+    request::<TrackingError>(server, msg, &mut reply)?;
+    Ok(TrainLocation::decode(&reply))
+}
+
+/// Start tracking `train`. It is placed by the first sensor hit no tracked train accounts for, so
+/// bring trains onto the track one at a time.
+#[allow(non_snake_case)]
+pub fn TrackTrain(server: Tid, train: u8) -> Result<(), TrackingError> {
+    call(server, &[REQUEST_TRACK, train]).map(|_| ())
+}
+
+/// Where `train` is now.
+#[allow(non_snake_case)]
+pub fn Locate(server: Tid, train: u8) -> Result<TrainLocation, TrackingError> {
+    call(server, &[REQUEST_LOCATE, train])
+}
+
+/// Wait for the next train to turn up at a sensor.
+#[allow(non_snake_case)]
+pub fn AwaitLocation(server: Tid) -> Result<TrainLocation, TrackingError> {
+    call(server, &[REQUEST_AWAIT])
+}
+
+/// Keeps sensor dumps coming, so the server never waits on the train server itself
+fn sensor_poller() -> ! {
+    let server = MyParentTid().unwrap();
+    let train_server = WhoIs(TRAIN_SERVER_NAME).unwrap();
+    let clock = WhoIs(CLOCK_SERVER_NAME).unwrap();
+
+    let mut msg = [0u8; REQUEST_SIZE];
+    msg[0] = REQUEST_REPORT;
+    loop {
+        let report = DumpSensors(train_server).unwrap();
+        let time = Time(clock).unwrap();
+        msg[2..2 + SENSOR_REPORT_SIZE].copy_from_slice(report.as_bytes());
+        msg[2 + SENSOR_REPORT_SIZE..].copy_from_slice(&time.to_le_bytes());
+        Send(server, &msg, &mut []).unwrap();
+    }
+}
+
+struct TrackedTrain {
+    train: u8,
+    /// `None` until a sensor hit has been put down to the train
+    location: Option<TrainLocation>,
+    expectation: Option<Expectation>,
+    /// When the train was last seen, which `expectation` counts from
+    since: i64,
+}
+
+impl TrackedTrain {
+    /// How far either side of `expected` a hit can be and still be put down to the train
+    fn window(&self, expected: &Expected) -> i64 {
+        if expected.time == NEVER {
+            return NEVER;
+        }
+        MIN_WINDOW_TICKS + ((expected.time - self.since).max(0) >> WINDOW_SHIFT)
+    }
+}
+
+/// How well a hit fits a train: whether it is in the window, whether it is the very next sensor
+/// rather than one past a failed sensor or switch, and how far from the expected time. Lower is
+/// better.
+type Fit = (bool, bool, i64);
+
+struct TrackingServer {
+    track: &'static Track,
+    calibration: Tid,
+    clock: Tid,
+    trains: Vec<TrackedTrain, MAX_MODELLED_TRAINS>,
+    watchers: Deque<Tid, MAX_WATCHERS>,
+}
+
+impl TrackingServer {
+    fn refresh(&mut self, i: usize) {
+        let train = &mut self.trains[i];
+        train.expectation = train
+            .location
+            .and_then(|_| Expect(self.calibration, train.train).ok());
+    }
+
+    /// Which train most likely tripped `sensor`, and which of its expected sensors that was
+    fn attribute(&self, sensor: NodeId, time: i64) -> Option<(usize, u8, Option<Expected>)> {
+        let mut best: Option<(Fit, usize, u8, Option<Expected>)> = None;
+        for (i, train) in self.trains.iter().enumerate() {
+            let Some(expectation) = train.expectation else {
+                continue;
+            };
+            let diverted = expectation.diverted.map(|(expected, _, _)| expected);
+            let candidates = [Some(expectation.next), expectation.after, diverted];
+            for (rank, expected) in candidates.into_iter().enumerate() {
+                let Some(expected) = expected.filter(|expected| expected.sensor == sensor) else {
+                    continue;
+                };
+                let error = if expected.time == NEVER {
+                    NEVER
+                } else {
+                    (time - expected.time).abs()
+                };
+                // the sensor past a failed one and past a failed switch can be the same, so only
+                // the time tells those apart
+                let fit = (error > train.window(&expected), rank > 0, error);
+                if best.is_none_or(|(best, _, _, _)| fit < best) {
+                    best = Some((fit, i, rank as u8, Some(expected)));
+                }
+            }
+        }
+
+        match best {
+            // a train's very next sensor is its even if it is running well off the model
+            Some(((outside, alternative, _), i, rank, expected)) if !outside || !alternative => {
+                Some((i, rank, expected))
+            }
+            // a hit nobody accounts for places the first train still waiting to be seen
+            _ => self
+                .trains
+                .iter()
+                .position(|train| train.location.is_none())
+                .map(|i| (i, 0, None)),
+        }
+    }
+
+    fn hit(&mut self, sensor: NodeId, time: i64) {
+        let Some((i, rank, expected)) = self.attribute(sensor, time) else {
+            // nothing on the track should have tripped it
+            return;
+        };
+        let number = self.trains[i].train;
+
+        // the switch went the other way, so have the model and the track agree on which way
+        if rank == 2 {
+            if let Some((_, switch, direction)) =
+                self.trains[i].expectation.and_then(|e| e.diverted)
+            {
+                let _ = Throw(self.calibration, switch, direction);
+            }
+        }
+
+        let time_error = match expected {
+            Some(expected) if expected.time != NEVER => time - expected.time,
+            _ => 0,
+        };
+        let Ok(dist_error) = Passed(self.calibration, number, sensor, time) else {
+            return;
+        };
+
+        let train = &mut self.trains[i];
+        train.since = time;
+        train.location = Some(TrainLocation {
+            train: number,
+            position: Position {
+                node: sensor,
+                offset: 0,
+            },
+            sensor,
+            time_error,
+            dist_error,
+        });
+        self.refresh(i);
+
+        let location = self.trains[i].location.unwrap().encode();
+        while let Some(tid) = self.watchers.pop_front() {
+            reply(tid, &location);
+        }
+    }
+
+    fn report(&mut self, report: SensorReport, time: i64) {
+        // commands since the last report may have changed what each train is heading for
+        for i in 0..self.trains.len() {
+            self.refresh(i);
+        }
+        for sensor in report.triggered() {
+            self.hit(self.track.sensor(sensor), time);
+        }
+    }
+
+    fn track(&mut self, train: u8) -> Result<(), TrackingError> {
+        if self.trains.iter().any(|tracked| tracked.train == train) {
+            return Ok(());
+        }
+        let tracked = TrackedTrain {
+            train,
+            location: None,
+            expectation: None,
+            since: 0,
+        };
+        self.trains.push(tracked).map_err(|_| TrackingError::Busy)
+    }
+
+    fn locate(&self, train: u8) -> Result<TrainLocation, TrackingError> {
+        let tracked = self
+            .trains
+            .iter()
+            .find(|tracked| tracked.train == train)
+            .ok_or(TrackingError::NotTracked)?;
+        let mut location = tracked.location.ok_or(TrackingError::NotLocated)?;
+        let now = Time(self.clock).map_err(|_| TrackingError::Busy)?;
+        location.position =
+            PredictPosition(self.calibration, train, now).map_err(|_| TrackingError::NotLocated)?;
+        Ok(location)
+    }
+}
+
+/// Works out which train tripped each sensor from where the calibration server expects them,
+/// feeding the hits back to it. A single sensor that fails to trigger, or a switch that fails to
+/// throw, is put down to the train that should have been there. Started with the index of the
+/// track in `TRACKS` as its argument, after the calibration server.
+pub fn tracking_server(track: usize) -> ! {
+    RegisterAs(TRACKING_SERVER_NAME).unwrap();
+    let mut server = TrackingServer {
+        track: TRACKS[track],
+        calibration: WhoIs(CALIBRATION_SERVER_NAME).unwrap(),
+        clock: WhoIs(CLOCK_SERVER_NAME).unwrap(),
+        trains: Vec::new(),
+        watchers: Deque::new(),
+    };
+    Create(SENSOR_POLLER_PRIORITY, sensor_poller).unwrap();
+
+    loop {
+        let mut msg = [0u8; REQUEST_SIZE];
+        let (tid, _) = Receive(&mut msg);
+        let [kind, train, ..] = msg;
+        let valid_train = (1..=MAX_TRAIN).contains(&train);
+
+        match kind {
+            REQUEST_REPORT => {
+                reply(tid, &[]);
+                let bytes = msg[2..2 + SENSOR_REPORT_SIZE].try_into().unwrap();
+                let report = SensorReport::from_bytes(bytes);
+                let time = i64::from_le_bytes(msg[2 + SENSOR_REPORT_SIZE..].try_into().unwrap());
+                server.report(report, time);
+            }
+            REQUEST_AWAIT => {
+                if server.watchers.push_back(tid).is_err() {
+                    reply_status(tid, Err(TrackingError::Busy));
+                }
+            }
+            _ if !valid_train => reply_status(tid, Err(TrackingError::InvalidTrain)),
+            REQUEST_TRACK => reply_status(tid, server.track(train)),
+            REQUEST_LOCATE => match server.locate(train) {
+                Ok(location) => reply(tid, &location.encode()),
+                Err(err) => reply_status(tid, Err(err)),
+            },
+            _ => reply_status(tid, Err(TrackingError::Busy)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marklin::SwitchDirection;
+    use crate::track::tests::{A1, A3, A4, LOOP};
+
+    fn expected(sensor: NodeId, time: i64) -> Expected {
+        Expected { sensor, time }
+    }
+
+    /// A train seen at A1 at tick 0 doing 4mm a tick, as the calibration server would expect it
+    fn round_from_a1(train: u8) -> TrackedTrain {
+        located(
+            train,
+            Expectation {
+                next: expected(A3, 150),
+                after: Some(expected(A1, 200)),
+                // round the shortcut if switch 1 doesn't stay straight
+                diverted: Some((expected(A1, 100), 1, SwitchDirection::Curved)),
+            },
+        )
+    }
+
+    fn located(train: u8, expectation: Expectation) -> TrackedTrain {
+        TrackedTrain {
+            train,
+            location: Some(TrainLocation {
+                train,
+                position: Position {
+                    node: A1,
+                    offset: 0,
+                },
+                sensor: A1,
+                time_error: 0,
+                dist_error: 0,
+            }),
+            expectation: Some(expectation),
+            since: 0,
+        }
+    }
+
+    fn unlocated(train: u8) -> TrackedTrain {
+        TrackedTrain {
+            train,
+            location: None,
+            expectation: None,
+            since: 0,
+        }
+    }
+
+    fn tracking<const N: usize>(trains: [TrackedTrain; N]) -> TrackingServer {
+        TrackingServer {
+            track: &LOOP,
+            calibration: 0,
+            clock: 0,
+            trains: trains.into_iter().collect(),
+            watchers: Deque::new(),
+        }
+    }
+
+    #[test]
+    fn window_grows_with_how_far_off_the_sensor_is() {
+        let train = round_from_a1(24);
+        assert_eq!(train.window(&expected(A3, 0)), MIN_WINDOW_TICKS);
+        assert_eq!(train.window(&expected(A3, 200)), MIN_WINDOW_TICKS + 50);
+        assert_eq!(train.window(&expected(A3, NEVER)), NEVER);
+    }
+
+    #[test]
+    fn the_next_sensor_is_the_trains_however_late() {
+        let server = tracking([round_from_a1(24)]);
+        assert_eq!(
+            server.attribute(A3, 160),
+            Some((0, 0, Some(expected(A3, 150))))
+        );
+        assert_eq!(
+            server.attribute(A3, 1000),
+            Some((0, 0, Some(expected(A3, 150))))
+        );
+    }
+
+    #[test]
+    fn a_failed_sensor_is_passed_over() {
+        // A3 never triggered, so the train turns up at A1 when it should have after
+        let server = tracking([round_from_a1(24)]);
+        assert_eq!(
+            server.attribute(A1, 205),
+            Some((0, 1, Some(expected(A1, 200))))
+        );
+    }
+
+    #[test]
+    fn a_failed_switch_is_put_down_to_the_other_way() {
+        // back at A1 in half the time, so switch 1 sent it round the shortcut
+        let server = tracking([round_from_a1(24)]);
+        assert_eq!(
+            server.attribute(A1, 95),
+            Some((0, 2, Some(expected(A1, 100))))
+        );
+    }
+
+    #[test]
+    fn a_hit_nobody_accounts_for_places_a_waiting_train() {
+        let server = tracking([round_from_a1(24), unlocated(58)]);
+        assert_eq!(server.attribute(A4, 100), Some((1, 0, None)));
+        // well off any alternative's time
+        assert_eq!(server.attribute(A1, 400), Some((1, 0, None)));
+
+        let server = tracking([round_from_a1(24)]);
+        assert_eq!(server.attribute(A4, 100), None);
+    }
+
+    #[test]
+    fn the_train_that_fits_best_gets_the_hit() {
+        let other = located(
+            58,
+            Expectation {
+                next: expected(A1, 110),
+                after: None,
+                diverted: None,
+            },
+        );
+        let server = tracking([round_from_a1(24), other]);
+        // train 24 coming round the shortcut would fit too, but A1 is train 58's very next sensor
+        assert_eq!(
+            server.attribute(A1, 105),
+            Some((1, 0, Some(expected(A1, 110))))
+        );
+        assert_eq!(
+            server.attribute(A3, 150),
+            Some((0, 0, Some(expected(A3, 150))))
+        );
+    }
+}